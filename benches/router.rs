@@ -0,0 +1,95 @@
+//! Route lookups in a table the size of an upstream router's, by the trie of `Ipv4Router`
+//! against a scan of the same routes in lookup order.
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use unet::{
+    bench::{Ipv4Interface, Ipv4Router},
+    Ipv4Address, NetDevice,
+};
+
+const LOOKUPS: usize = 1024;
+
+/// Deterministic addresses, so that runs compare.
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// A router with a default route and `count` routes of /8 to /32, mostly /24 as in a full table.
+fn router(count: usize, random: &mut XorShift) -> Ipv4Router {
+    let interface = Arc::new(Ipv4Interface::new(
+        Ipv4Address::new(&[192, 0, 2, 2]),
+        Ipv4Address::new(&[255, 255, 255, 0]),
+        Arc::new(Mutex::new(NetDevice::null())),
+    ));
+    let gateway = Ipv4Address::new(&[192, 0, 2, 1]);
+    let mut router = Ipv4Router::new();
+    router.register(interface.unicast, interface.clone());
+    router.register_default(interface.clone(), gateway);
+    for _ in 0..count {
+        let length = match random.next() % 4 {
+            0 => 8 + random.next() % 25,
+            _ => 24,
+        };
+        let netmask = Ipv4Address(u32::MAX << (32 - length));
+        // Routes to the same network are told apart by their metric.
+        let metric = random.next();
+        router
+            .register_route(
+                Ipv4Address(random.next()),
+                netmask,
+                interface.clone(),
+                Some(gateway),
+                metric,
+            )
+            .unwrap();
+    }
+    router
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for count in [100, 10_000, 50_000] {
+        let mut random = XorShift(1);
+        let router = router(count, &mut random);
+        let routes = router.routes().cloned().collect::<Vec<_>>();
+        let dsts = (0..LOOKUPS)
+            .map(|_| Ipv4Address(random.next()))
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("trie", count), &dsts, |b, dsts| {
+            b.iter(|| {
+                for dst in dsts {
+                    black_box(router.lookup(*dst));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("scan", count), &dsts, |b, dsts| {
+            b.iter(|| {
+                for dst in dsts {
+                    black_box(
+                        routes
+                            .iter()
+                            .find(|route| *dst & route.netmask == route.network)
+                            .cloned(),
+                    );
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
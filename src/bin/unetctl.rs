@@ -0,0 +1,45 @@
+//! Client of the control socket served by unet, e.g. `unetctl route add default via 192.0.2.1`.
+
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    process::ExitCode,
+};
+
+use unet::CONTROL_SOCKET_PATH;
+
+fn main() -> ExitCode {
+    let mut path = CONTROL_SOCKET_PATH.to_string();
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "--socket") {
+        args.next();
+        path = args.next().unwrap_or_default();
+    }
+    let command = args.collect::<Vec<_>>().join(" ");
+    let command = if command.is_empty() { "help" } else { &command };
+
+    match request(&path, command) {
+        Ok(response) => {
+            print!("{}", response);
+            if response.starts_with("error: ") {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(err) => {
+            eprintln!("unetctl: {}: {}", path, err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn request(path: &str, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
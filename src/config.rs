@@ -0,0 +1,415 @@
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    devices::{
+        ethernet::{MacAddress, ETHERNET_PAYLOAD_MAX_SIZE, MAC_ADDRESS_LEN},
+        NetDevice, NetDevices,
+    },
+    protocols::ipv4::{Ipv4Address, Ipv4Interface, IPV4_MTU_MIN},
+};
+
+/// The topology `unet.conf` describes, used when no config file is given.
+pub const DEFAULT_CONFIG: &str = include_str!("../unet.conf");
+
+/// An address along with its netmask, written as `192.0.2.2/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Prefix {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+}
+
+impl TryFrom<&str> for Ipv4Prefix {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let Some((address, prefix)) = value.split_once('/') else {
+            anyhow::bail!("prefix length is missing: {}", value);
+        };
+        let prefix: u32 = prefix.parse()?;
+        anyhow::ensure!(prefix <= 32, "invalid prefix length: {}", prefix);
+        let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        Ok(Ipv4Prefix {
+            address: Ipv4Address::try_from(address)?,
+            netmask: Ipv4Address(netmask),
+        })
+    }
+}
+
+impl Ipv4Prefix {
+    /// Parses the network of a route, `default` standing for `0.0.0.0/0`.
+    pub fn network(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "default" => Ipv4Prefix::try_from("0.0.0.0/0"),
+            value => Ipv4Prefix::try_from(value),
+        }
+    }
+}
+
+impl std::fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.netmask.0.count_ones())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Loopback,
+    Tap,
+    Tun,
+    Packet,
+}
+
+impl TryFrom<&str> for DeviceKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "loopback" => Ok(DeviceKind::Loopback),
+            "tap" => Ok(DeviceKind::Tap),
+            "tun" => Ok(DeviceKind::Tun),
+            "packet" => Ok(DeviceKind::Packet),
+            _ => anyhow::bail!("unknown device type: {}", value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub kind: DeviceKind,
+    pub hw_addr: Option<MacAddress>,
+    pub mtu: Option<usize>,
+    pub promiscuous: bool,
+    pub addresses: Vec<Ipv4Prefix>,
+}
+
+impl DeviceConfig {
+    pub fn build(&self) -> NetDevice {
+        let mut device = match self.kind {
+            DeviceKind::Loopback => NetDevice {
+                name: self.name.clone(),
+                ..NetDevice::loopback()
+            },
+            DeviceKind::Tap => NetDevice::ethernet_tap(&self.name),
+            DeviceKind::Tun => NetDevice::tun(&self.name),
+            DeviceKind::Packet => NetDevice::packet(&self.name, self.promiscuous),
+        };
+        if let Some(hw_addr) = self.hw_addr {
+            device.hw_addr[..MAC_ADDRESS_LEN].copy_from_slice(&hw_addr.0);
+        }
+        if let Some(mtu) = self.mtu {
+            device.mtu = mtu;
+        }
+        device
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteConfig {
+    pub network: Ipv4Prefix,
+    pub gateway: Option<Ipv4Address>,
+    pub device: Option<String>,
+    pub metric: u32,
+}
+
+impl RouteConfig {
+    /// The interface the route goes out of: the one on the gateway's network, or the first of its device.
+    pub fn interface(&self, devices: &NetDevices) -> anyhow::Result<Arc<Ipv4Interface>> {
+        for device in devices.iter() {
+            let device = device.lock().unwrap();
+            if self
+                .device
+                .as_ref()
+                .is_some_and(|name| *name != device.name)
+            {
+                continue;
+            }
+            let found = device
+                .interfaces
+                .iter()
+                .find(|interface| match self.gateway {
+                    Some(gateway) => {
+                        gateway & interface.netmask == interface.unicast & interface.netmask
+                    }
+                    None => true,
+                });
+            if let Some(interface) = found {
+                return Ok(interface.clone());
+            }
+        }
+        anyhow::bail!(
+            "no interface for route, network: {}, gateway: {:?}, dev: {:?}",
+            self.network,
+            self.gateway,
+            self.device
+        )
+    }
+}
+
+/// Limits of the UDP sockets, the stack's defaults when omitted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UdpConfig {
+    pub max_sockets: Option<usize>,
+    pub queue_length: Option<usize>,
+}
+
+/// Behaviour of the IPv4 layer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub forwarding: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub devices: Vec<DeviceConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub ipv4: Ipv4Config,
+    pub udp: UdpConfig,
+}
+
+enum Section {
+    Device(DeviceConfig),
+    Route(RouteConfig),
+    Ipv4(Ipv4Config),
+    Udp(UdpConfig),
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("read config failed: {}, {}", path.display(), err))?;
+        Config::parse(&text)
+    }
+
+    /// Parses INI-style sections of `key = value` lines, `#` and `;` starting a comment line.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        let mut section = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let result = if let Some(header) = line.strip_prefix('[') {
+                config.push(section.take())?;
+                Config::parse_section(header).map(|new| section = Some(new))
+            } else {
+                Config::parse_entry(section.as_mut(), line)
+            };
+            result.map_err(|err| anyhow::anyhow!("invalid config, line: {}, {}", i + 1, err))?;
+        }
+        config.push(section)?;
+        Ok(config)
+    }
+
+    fn parse_section(header: &str) -> anyhow::Result<Section> {
+        let Some(header) = header.strip_suffix(']') else {
+            anyhow::bail!("unterminated section: [{}", header);
+        };
+        match header.split_whitespace().collect::<Vec<_>>()[..] {
+            ["device", name] => Ok(Section::Device(DeviceConfig {
+                name: name.to_string(),
+                kind: DeviceKind::Tap,
+                hw_addr: None,
+                mtu: None,
+                promiscuous: false,
+                addresses: vec![],
+            })),
+            ["route", network] => Ok(Section::Route(RouteConfig {
+                network: Ipv4Prefix::network(network)?,
+                gateway: None,
+                device: None,
+                metric: 0,
+            })),
+            ["ipv4"] => Ok(Section::Ipv4(Ipv4Config::default())),
+            ["udp"] => Ok(Section::Udp(UdpConfig::default())),
+            _ => anyhow::bail!("unknown section: [{}]", header),
+        }
+    }
+
+    fn parse_entry(section: Option<&mut Section>, line: &str) -> anyhow::Result<()> {
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("expected key = value: {}", line);
+        };
+        let (key, value) = (key.trim(), value.trim());
+        match (section, key) {
+            (Some(Section::Device(device)), "type") => device.kind = DeviceKind::try_from(value)?,
+            (Some(Section::Device(device)), "mac") => {
+                device.hw_addr = Some(MacAddress::try_from(value)?)
+            }
+            (Some(Section::Device(device)), "mtu") => {
+                let mtu = value.parse()?;
+                anyhow::ensure!(
+                    mtu >= IPV4_MTU_MIN,
+                    "mtu below {}, dev: {}, mtu: {}",
+                    IPV4_MTU_MIN,
+                    device.name,
+                    mtu
+                );
+                device.mtu = Some(mtu);
+            }
+            (Some(Section::Device(device)), "promiscuous") => device.promiscuous = value.parse()?,
+            (Some(Section::Device(device)), "address") => {
+                device.addresses.push(Ipv4Prefix::try_from(value)?)
+            }
+            (Some(Section::Route(route)), "gateway") => {
+                route.gateway = Some(Ipv4Address::try_from(value)?)
+            }
+            (Some(Section::Route(route)), "device") => route.device = Some(value.to_string()),
+            (Some(Section::Route(route)), "metric") => route.metric = value.parse()?,
+            (Some(Section::Ipv4(ipv4)), "forwarding") => ipv4.forwarding = value.parse()?,
+            (Some(Section::Udp(udp)), "max_sockets") => udp.max_sockets = Some(value.parse()?),
+            (Some(Section::Udp(udp)), "queue_length") => udp.queue_length = Some(value.parse()?),
+            (Some(_), _) => anyhow::bail!("unknown key: {}", key),
+            (None, _) => anyhow::bail!("key outside of a section: {}", key),
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, section: Option<Section>) -> anyhow::Result<()> {
+        match section {
+            Some(Section::Device(device)) => {
+                anyhow::ensure!(
+                    self.devices.iter().all(|d| d.name != device.name),
+                    "duplicate device: {}",
+                    device.name
+                );
+                anyhow::ensure!(
+                    !device.promiscuous || device.kind == DeviceKind::Packet,
+                    "promiscuous is for packet devices only, dev: {}",
+                    device.name
+                );
+                anyhow::ensure!(
+                    device.hw_addr.is_none()
+                        || matches!(device.kind, DeviceKind::Tap | DeviceKind::Packet),
+                    "mac is for ethernet devices only, dev: {}",
+                    device.name
+                );
+                if let (DeviceKind::Tap | DeviceKind::Packet, Some(mtu)) = (device.kind, device.mtu)
+                {
+                    // A frame is read into a buffer sized for the Ethernet maximum.
+                    anyhow::ensure!(
+                        mtu <= ETHERNET_PAYLOAD_MAX_SIZE,
+                        "mtu above {} on an ethernet device, dev: {}, mtu: {}",
+                        ETHERNET_PAYLOAD_MAX_SIZE,
+                        device.name,
+                        mtu
+                    );
+                }
+                self.devices.push(device);
+            }
+            Some(Section::Route(route)) => {
+                anyhow::ensure!(
+                    route.gateway.is_some() || route.device.is_some(),
+                    "route without gateway needs a device, network: {}",
+                    route.network.address
+                );
+                self.routes.push(route);
+            }
+            Some(Section::Ipv4(ipv4)) => self.ipv4 = ipv4,
+            Some(Section::Udp(udp)) => self.udp = udp,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_default_config() {
+        let config = Config::parse(DEFAULT_CONFIG).unwrap();
+        let names = config
+            .devices
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["lo", "tap0"]);
+        assert_eq!(config.devices[0].kind, DeviceKind::Loopback);
+        assert_eq!(config.udp, UdpConfig::default());
+        assert!(!config.ipv4.forwarding);
+        assert_eq!(
+            config.devices[1].addresses,
+            [Ipv4Prefix {
+                address: Ipv4Address::new(&[192, 0, 2, 2]),
+                netmask: Ipv4Address::new(&[255, 255, 255, 0]),
+            }]
+        );
+        assert_eq!(
+            config.routes,
+            [RouteConfig {
+                network: Ipv4Prefix {
+                    address: Ipv4Address::ANY,
+                    netmask: Ipv4Address::ANY,
+                },
+                gateway: Some(Ipv4Address::new(&[192, 0, 2, 1])),
+                device: None,
+                metric: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_device_overrides() {
+        let config = Config::parse(
+            "[device veth0]\n\
+             type = packet\n\
+             mac = 02:00:00:00:53:0a\n\
+             mtu = 1400\n\
+             promiscuous = true\n\
+             address = 198.51.100.2/24\n\
+             address = 203.0.113.2/25\n\
+             [route 10.0.0.0/8]\n\
+             gateway = 198.51.100.1\n\
+             metric = 20\n\
+             [ipv4]\n\
+             forwarding = true\n\
+             [udp]\n\
+             max_sockets = 4096\n",
+        )
+        .unwrap();
+        let device = config.devices[0].build();
+        assert_eq!(device.name, "veth0");
+        assert_eq!(device.mtu, 1400);
+        assert_eq!(device.hw_addr[..6], [0x02, 0x00, 0x00, 0x00, 0x53, 0x0a]);
+        assert_eq!(config.devices[0].addresses.len(), 2);
+        assert_eq!(
+            config.devices[0].addresses[1].netmask,
+            Ipv4Address::new(&[255, 255, 255, 128])
+        );
+        assert_eq!(config.routes[0].metric, 20);
+        assert!(config.ipv4.forwarding);
+        assert_eq!(config.udp.max_sockets, Some(4096));
+        assert_eq!(config.udp.queue_length, None);
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_line() {
+        let err = Config::parse("[device tap0]\ntype = tap\nspeed = 10\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config, line: 3, unknown key: speed"
+        );
+        assert!(Config::parse("type = tap\n").is_err());
+        assert!(Config::parse("[route 10.0.0.0/8]\n").is_err());
+        assert!(Config::parse("[device lo]\ntype = loopback\nmac = 02:00:00:00:00:01\n").is_err());
+
+        let err = Config::parse("[device tap0]\ntype = tap\nmtu = 67\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config, line: 3, mtu below 68, dev: tap0, mtu: 67"
+        );
+        assert!(Config::parse("[device tap0]\ntype = tap\nmtu = 68\n").is_ok());
+
+        let err = Config::parse("[device tap0]\ntype = tap\nmtu = 9000\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mtu above 1500 on an ethernet device, dev: tap0, mtu: 9000"
+        );
+        assert!(Config::parse("[device tap0]\ntype = tap\nmtu = 1500\n").is_ok());
+        assert!(Config::parse("[device tun0]\ntype = tun\nmtu = 9000\n").is_ok());
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{debug, error, info};
+
+use crate::{
+    config::{Ipv4Prefix, RouteConfig},
+    devices::{
+        ethernet::{MacAddress, MAC_ADDRESS_LEN},
+        NetDevices, NET_DEVICE_FLAG_BROADCAST, NET_DEVICE_FLAG_LOOPBACK, NET_DEVICE_FLAG_NEED_ARP,
+        NET_DEVICE_FLAG_P2P, NET_DEVICE_FLAG_PROMISC, NET_DEVICE_FLAG_UP,
+    },
+    driver::DriverType,
+    protocols::{
+        arp::ArpCacheState,
+        ipv4::{Ipv4Address, Ipv4Interface},
+        ProtocolStackContext,
+    },
+    transport::ContextBlocks,
+};
+
+pub const CONTROL_SOCKET_PATH: &str = "/tmp/unet.sock";
+// Connections are served one at a time, a client which stalls must not hold the others off.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DEVICE_FLAG_NAMES: [(u16, &str); 6] = [
+    (NET_DEVICE_FLAG_UP, "UP"),
+    (NET_DEVICE_FLAG_LOOPBACK, "LOOPBACK"),
+    (NET_DEVICE_FLAG_BROADCAST, "BROADCAST"),
+    (NET_DEVICE_FLAG_P2P, "POINTOPOINT"),
+    (NET_DEVICE_FLAG_NEED_ARP, "ARP"),
+    (NET_DEVICE_FLAG_PROMISC, "PROMISC"),
+];
+
+const USAGE: &str = "\
+link [show]
+addr [show]
+addr add|del <ipv4>/<prefix> dev <name>
+route [show]
+route add|replace <ipv4>/<prefix>|default [via <ipv4>] [dev <name>] [metric <number>]
+route del <ipv4>/<prefix>|default [metric <number>]
+neigh [show]
+neigh flush
+sockets
+";
+
+/// Serves `ip`-like commands over a unix socket, one command line per connection.
+/// State is locked in the order the stack does: context, pcbs, devices, then a device.
+pub struct Control {
+    devices: Arc<Mutex<NetDevices>>,
+    context: Arc<Mutex<ProtocolStackContext>>,
+    pcbs: Arc<Mutex<ContextBlocks>>,
+}
+
+impl Control {
+    pub fn new(
+        devices: Arc<Mutex<NetDevices>>,
+        context: Arc<Mutex<ProtocolStackContext>>,
+        pcbs: Arc<Mutex<ContextBlocks>>,
+    ) -> Self {
+        Control {
+            devices,
+            context,
+            pcbs,
+        }
+    }
+
+    /// Listens on `path` from a thread of its own, which lives as long as the process.
+    pub fn serve(self, path: &Path) -> anyhow::Result<JoinHandle<()>> {
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("control socket in use: {}", path.display());
+        }
+        // Left behind by a previous run which did not stop cleanly.
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        info!("control socket listening, path: {}", path.display());
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = self.handle(stream) {
+                            error!("control request failed: {:?}", err);
+                        }
+                    }
+                    Err(err) => error!("control accept failed: {:?}", err),
+                }
+            }
+        }))
+    }
+
+    fn handle(&self, stream: UnixStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        debug!("control command: {}", line.trim_end());
+        let response = match self.execute(&line) {
+            Ok(output) => output,
+            Err(err) => format!("error: {}\n", err),
+        };
+        (&stream).write_all(response.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn execute(&self, line: &str) -> anyhow::Result<String> {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        match args[..] {
+            ["link"] | ["link", "show"] => Ok(self.show_links()),
+            ["addr"] | ["addr", "show"] => Ok(self.show_addresses()),
+            ["addr", "add", address, "dev", name] => {
+                self.add_address(Ipv4Prefix::try_from(address)?, name)
+            }
+            ["addr", "del", address, "dev", name] => {
+                self.delete_address(Ipv4Prefix::try_from(address)?, name)
+            }
+            ["route"] | ["route", "show"] => Ok(self.show_routes()),
+            ["route", "add", network, ref options @ ..] => self.add_route(network, options, false),
+            ["route", "replace", network, ref options @ ..] => {
+                self.add_route(network, options, true)
+            }
+            ["route", "del", network] => self.delete_route(Ipv4Prefix::network(network)?, None),
+            ["route", "del", network, "metric", metric] => {
+                self.delete_route(Ipv4Prefix::network(network)?, Some(metric.parse()?))
+            }
+            ["neigh"] | ["neigh", "show"] => Ok(self.show_neighbours()),
+            ["neigh", "flush"] => {
+                self.context.lock().unwrap().arp_cache.flush();
+                Ok(String::new())
+            }
+            ["sockets"] => Ok(self.show_sockets()),
+            ["help"] => Ok(USAGE.to_string()),
+            _ => anyhow::bail!("unknown command: {}, try help", line.trim()),
+        }
+    }
+
+    fn show_links(&self) -> String {
+        let mut output = String::new();
+        for (i, device) in self.devices.lock().unwrap().iter().enumerate() {
+            let device = device.lock().unwrap();
+            let flags = DEVICE_FLAG_NAMES
+                .iter()
+                .filter(|(flag, _)| device.flags & flag != 0)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>();
+            let hw_addr = MacAddress::from(&device.hw_addr[..MAC_ADDRESS_LEN]);
+            let _ = write!(
+                output,
+                "{}: {}: <{}> mtu {} type {:?} link {}",
+                i + 1,
+                device.name,
+                flags.join(","),
+                device.mtu,
+                device.ty,
+                hw_addr
+            );
+            let dropped = device.driver.as_ref().map_or(0, DriverType::dropped);
+            if dropped != 0 {
+                let _ = write!(output, " dropped {}", dropped);
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    fn show_addresses(&self) -> String {
+        let mut output = String::new();
+        for device in self.devices.lock().unwrap().iter() {
+            let device = device.lock().unwrap();
+            for interface in device.interfaces.iter() {
+                let address = Ipv4Prefix {
+                    address: interface.unicast,
+                    netmask: interface.netmask,
+                };
+                let _ = writeln!(
+                    output,
+                    "{}: inet {} brd {}",
+                    device.name, address, interface.broadcast
+                );
+            }
+        }
+        output
+    }
+
+    fn add_address(&self, address: Ipv4Prefix, name: &str) -> anyhow::Result<String> {
+        let mut context = self.context.lock().unwrap();
+        let devices = self.devices.lock().unwrap();
+        let Some(device) = devices
+            .iter()
+            .find(|device| device.lock().unwrap().name == name)
+        else {
+            anyhow::bail!("device not found: {}", name);
+        };
+        let interface = Arc::new(Ipv4Interface::new(
+            address.address,
+            address.netmask,
+            device.clone(),
+        ));
+        let mut device = device.lock().unwrap();
+        anyhow::ensure!(
+            device
+                .interfaces
+                .iter()
+                .all(|interface| interface.unicast != address.address),
+            "address already assigned, dev: {}, address: {}",
+            name,
+            address.address
+        );
+        device.register_interface(&mut context, interface);
+        Ok(String::new())
+    }
+
+    fn delete_address(&self, address: Ipv4Prefix, name: &str) -> anyhow::Result<String> {
+        let mut context = self.context.lock().unwrap();
+        let devices = self.devices.lock().unwrap();
+        let Some(device) = devices
+            .iter()
+            .find(|device| device.lock().unwrap().name == name)
+        else {
+            anyhow::bail!("device not found: {}", name);
+        };
+        device.lock().unwrap().unregister_interface(
+            &mut context,
+            address.address,
+            address.netmask,
+        )?;
+        Ok(String::new())
+    }
+
+    fn show_routes(&self) -> String {
+        let mut output = String::new();
+        for route in self.context.lock().unwrap().router.routes() {
+            let network = Ipv4Prefix {
+                address: route.network,
+                netmask: route.netmask,
+            };
+            if network.netmask == Ipv4Address::ANY {
+                output.push_str("default");
+            } else {
+                let _ = write!(output, "{}", network);
+            }
+            if let Some(next_hop) = route.next_hop {
+                let _ = write!(output, " via {}", next_hop);
+            }
+            let device = route.interface.device.as_ref().and_then(|d| d.upgrade());
+            if let Some(device) = device {
+                let _ = write!(output, " dev {}", device.lock().unwrap().name);
+            }
+            let _ = write!(output, " src {}", route.interface.unicast);
+            if route.metric != 0 {
+                let _ = write!(output, " metric {}", route.metric);
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Adds a route, or with `replace` takes the place of the one with the same metric.
+    fn add_route(&self, network: &str, options: &[&str], replace: bool) -> anyhow::Result<String> {
+        let mut route = RouteConfig {
+            network: Ipv4Prefix::network(network)?,
+            gateway: None,
+            device: None,
+            metric: 0,
+        };
+        for option in options.chunks(2) {
+            match option {
+                ["via", gateway] => route.gateway = Some(Ipv4Address::try_from(*gateway)?),
+                ["dev", name] => route.device = Some(name.to_string()),
+                ["metric", metric] => route.metric = metric.parse()?,
+                _ => anyhow::bail!("invalid route option: {}", option.join(" ")),
+            }
+        }
+        anyhow::ensure!(
+            route.gateway.is_some() || route.device.is_some(),
+            "route without gateway needs a device"
+        );
+        let mut context = self.context.lock().unwrap();
+        let interface = route.interface(&self.devices.lock().unwrap())?;
+        let (address, netmask) = (route.network.address, route.network.netmask);
+        if replace {
+            context
+                .router
+                .replace_route(address, netmask, interface, route.gateway, route.metric);
+        } else {
+            context.router.register_route(
+                address,
+                netmask,
+                interface,
+                route.gateway,
+                route.metric,
+            )?;
+        }
+        Ok(String::new())
+    }
+
+    /// Deletes the route with `metric`, or the one in use when `None`.
+    fn delete_route(&self, network: Ipv4Prefix, metric: Option<u32>) -> anyhow::Result<String> {
+        let mut context = self.context.lock().unwrap();
+        anyhow::ensure!(
+            context
+                .router
+                .unregister_route(network.address, network.netmask, metric),
+            "route not found: {}",
+            network
+        );
+        Ok(String::new())
+    }
+
+    fn show_neighbours(&self) -> String {
+        let mut output = String::new();
+        let context = self.context.lock().unwrap();
+        let mut entries = context.arp_cache.entries().collect::<Vec<_>>();
+        entries.sort_by_key(|(ip_addr, _)| ip_addr.0);
+        for (ip_addr, state) in entries {
+            let _ = match state {
+                ArpCacheState::Resolved(hw_addr) => {
+                    writeln!(output, "{} lladdr {} REACHABLE", ip_addr, hw_addr)
+                }
+                ArpCacheState::Incomplete => writeln!(output, "{} INCOMPLETE", ip_addr),
+            };
+        }
+        output
+    }
+
+    fn show_sockets(&self) -> String {
+        let mut output = String::new();
+        let pcbs = self.pcbs.lock().unwrap();
+        for (id, local, foreign, queued, dropped) in pcbs.udp_pcb.list() {
+            let foreign = foreign.map_or("*:*".to_string(), |foreign| foreign.to_string());
+            let _ = writeln!(
+                output,
+                "udp {} {} {} queued {} dropped {}",
+                id, local, foreign, queued, dropped
+            );
+        }
+        for (id, state, local, foreign) in pcbs.tcp_pcb.list() {
+            let _ = writeln!(output, "tcp {} {} {} {:?}", id, local, foreign, state);
+        }
+        output
+    }
+}
+
+/// Removes the socket file of a control socket served from `path`.
+pub fn remove_socket(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        debug!("remove control socket failed: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::NetDevice,
+        transport::{udp, Endpoint},
+    };
+
+    fn control() -> Control {
+        let mut devices = NetDevices::new();
+        devices.push_back(Arc::new(Mutex::new(NetDevice::null())));
+        Control::new(
+            Arc::new(Mutex::new(devices)),
+            Arc::new(Mutex::new(ProtocolStackContext::new())),
+            Arc::new(Mutex::new(ContextBlocks::new())),
+        )
+    }
+
+    #[test]
+    fn test_addresses_and_routes() {
+        let control = control();
+        control.execute("addr add 192.0.2.2/24 dev null").unwrap();
+        control
+            .execute("route add 198.51.100.0/24 via 192.0.2.1")
+            .unwrap();
+        assert_eq!(
+            control.execute("addr").unwrap(),
+            "null: inet 192.0.2.2/24 brd 192.0.2.255\n"
+        );
+        assert_eq!(
+            control.execute("route show").unwrap(),
+            "192.0.2.0/24 dev null src 192.0.2.2\n\
+             198.51.100.0/24 via 192.0.2.1 dev null src 192.0.2.2\n"
+        );
+
+        control.execute("route del 198.51.100.0/24").unwrap();
+        assert!(control.execute("route del 198.51.100.0/24").is_err());
+        // Routes of the same network differ by their metric.
+        control
+            .execute("route add default via 192.0.2.1 metric 100")
+            .unwrap();
+        assert!(control
+            .execute("route add default via 192.0.2.3 metric 100")
+            .is_err());
+        control
+            .execute("route replace default via 192.0.2.3 metric 100")
+            .unwrap();
+        control
+            .execute("route add default via 192.0.2.4 metric 10")
+            .unwrap();
+        assert_eq!(
+            control.execute("route").unwrap(),
+            "192.0.2.0/24 dev null src 192.0.2.2\n\
+             default via 192.0.2.4 dev null src 192.0.2.2 metric 10\n\
+             default via 192.0.2.3 dev null src 192.0.2.2 metric 100\n"
+        );
+        control.execute("route del default metric 10").unwrap();
+        control.execute("route del default").unwrap();
+        // The routes of an interface go away along with it.
+        control.execute("route add default via 192.0.2.1").unwrap();
+        control.execute("addr del 192.0.2.2/24 dev null").unwrap();
+        assert_eq!(control.execute("route").unwrap(), "");
+        assert!(control.execute("route add default via 192.0.2.1").is_err());
+    }
+
+    #[test]
+    fn test_neighbours_and_sockets() {
+        let control = control();
+        {
+            let mut context = control.context.lock().unwrap();
+            context.arp_cache.insert(
+                Ipv4Address::new(&[192, 0, 2, 1]),
+                ArpCacheState::Resolved(MacAddress([0x02, 0, 0, 0, 0, 0x01])),
+            );
+            context
+                .arp_cache
+                .insert(Ipv4Address::new(&[192, 0, 2, 3]), ArpCacheState::Incomplete);
+        }
+        assert_eq!(
+            control.execute("neigh").unwrap(),
+            "192.0.2.1 lladdr 02:00:00:00:00:01 REACHABLE\n192.0.2.3 INCOMPLETE\n"
+        );
+        // Packets may wait on the incomplete entry, it is not flushed.
+        control.execute("neigh flush").unwrap();
+        assert_eq!(
+            control.execute("neigh show").unwrap(),
+            "192.0.2.3 INCOMPLETE\n"
+        );
+
+        let mut pcbs = control.pcbs.lock().unwrap();
+        let id = udp::open(&mut pcbs).unwrap();
+        udp::bind(&mut pcbs, id, &Endpoint::new(&[0, 0, 0, 0], 7)).unwrap();
+        drop(pcbs);
+        assert_eq!(
+            control.execute("sockets").unwrap(),
+            "udp 0 0.0.0.0:7 *:* queued 0 dropped 0\n"
+        );
+        assert_eq!(
+            control.execute("link").unwrap(),
+            "1: null: <> mtu 1500 type Null link 00:00:00:00:00:00\n"
+        );
+        assert!(control.execute("link set null up").is_err());
+    }
+
+    #[test]
+    fn test_stalled_client_times_out() {
+        let control = control();
+        // The client sends nothing, the request is given up on for the next connection.
+        let (server, _client) = UnixStream::pair().unwrap();
+        assert!(control.handle(server).is_err());
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        writeln!(client, "neigh").unwrap();
+        control.handle(server).unwrap();
+    }
+}
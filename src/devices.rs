@@ -1,4 +1,5 @@
 pub mod ethernet;
+pub mod link;
 pub mod loopback;
 pub mod null;
 
@@ -9,22 +10,22 @@ use std::{
 
 use ethernet::MacAddress;
 use log::{debug, info};
-use signal_hook::low_level::raise;
 
 use crate::{
-    driver::DriverType,
-    interrupt::{IrqEntry, INTR_IRQ_L3},
+    driver::{tun, DriverType},
+    interrupt::{raise_irq, IrqEntry, INTR_IRQ_L3},
     protocols::{
-        ipv4::Ipv4Interface, Ipv4QueueEntry, NetInterfaceFamily, NetProtocolType, NetProtocols,
-        ProtocolStackContext,
+        ipv4::{Ipv4Address, Ipv4Interface},
+        Ipv4QueueEntry, NetInterfaceFamily, NetProtocolType, NetProtocols, ProtocolStackContext,
     },
 };
 
-const NET_DEVICE_FLAG_UP: u16 = 0x0001;
+pub const NET_DEVICE_FLAG_UP: u16 = 0x0001;
 pub const NET_DEVICE_FLAG_LOOPBACK: u16 = 0x0010;
-const NET_DEVICE_FLAG_BROADCAST: u16 = 0x0020;
-const NET_DEVICE_FLAG_P2P: u16 = 0x0040;
+pub const NET_DEVICE_FLAG_BROADCAST: u16 = 0x0020;
+pub const NET_DEVICE_FLAG_P2P: u16 = 0x0040;
 pub const NET_DEVICE_FLAG_NEED_ARP: u16 = 0x0100;
+pub const NET_DEVICE_FLAG_PROMISC: u16 = 0x0200;
 
 pub const NET_DEVICE_ADDR_LEN: usize = 14;
 
@@ -69,6 +70,7 @@ pub enum NetDeviceType {
     Null,
     Loopback,
     Ethernet,
+    Tun,
 }
 
 #[derive(Debug)]
@@ -137,6 +139,33 @@ impl NetDevice {
         self.interfaces.push_back(interface);
     }
 
+    /// Removes the interface of `unicast`/`netmask` along with its routes.
+    pub fn unregister_interface(
+        &mut self,
+        context: &mut ProtocolStackContext,
+        unicast: Ipv4Address,
+        netmask: Ipv4Address,
+    ) -> anyhow::Result<()> {
+        let Some(interface) = self
+            .interfaces
+            .iter()
+            .find(|interface| interface.unicast == unicast && interface.netmask == netmask)
+            .cloned()
+        else {
+            anyhow::bail!(
+                "interface not found, dev: {}, unicast: {}",
+                self.name,
+                unicast
+            );
+        };
+        context.router.unregister_interface(&interface);
+        self.interfaces = std::mem::take(&mut self.interfaces)
+            .into_iter()
+            .filter(|i| !Arc::ptr_eq(i, &interface))
+            .collect();
+        Ok(())
+    }
+
     pub fn get_interface(&self, family: NetInterfaceFamily) -> Option<Arc<Ipv4Interface>> {
         for interface in self.interfaces.iter() {
             if interface.family == family {
@@ -171,37 +200,54 @@ impl NetDevice {
 
     #[tracing::instrument(skip_all)]
     pub fn handle_isr(&mut self, protocols: &mut NetProtocols) -> anyhow::Result<()> {
-        let (protocol, payload) = match self.ty {
+        if self.poll(protocols)? == 0 {
+            return Ok(());
+        }
+        // One IRQ for the whole batch, the protocols drain their queues.
+        raise_irq(INTR_IRQ_L3)?;
+        Ok(())
+    }
+
+    /// Moves pending frames to the protocol queues without raising any IRQ, returns how many.
+    pub fn poll(&mut self, protocols: &mut NetProtocols) -> anyhow::Result<usize> {
+        let frames = match self.ty {
             NetDeviceType::Null => {
-                return Ok(());
+                return Ok(0);
             }
-            NetDeviceType::Loopback => loopback::recv(self)?,
+            NetDeviceType::Loopback => vec![loopback::recv(self)?],
             NetDeviceType::Ethernet => ethernet::recv(self)?,
+            NetDeviceType::Tun => tun::recv(self)?,
         };
-        debug!(
-            "net device recv, protocol: {:?}, len: {}, {:?}",
-            protocol,
-            payload.len(),
-            payload,
-        );
-
-        for p in protocols.iter() {
-            if p.protocol_type == protocol {
-                let mut queue = p.queue.lock().unwrap();
-                let Some(interface) = self.get_interface(p.protocol_type.to_family()) else {
-                    anyhow::bail!("ipv4 interface not found, dev: {}", self.name);
-                };
-                queue.push_back(Ipv4QueueEntry {
-                    data: payload,
-                    interface,
-                });
-                debug!("net protocol queue pushed, len: {}", queue.len());
-                break;
+        let len = frames.len();
+        for (protocol, payload) in frames {
+            debug!(
+                "net device recv, protocol: {:?}, len: {}, {:?}",
+                protocol,
+                payload.len(),
+                payload,
+            );
+            for p in protocols.iter() {
+                if p.protocol_type == protocol {
+                    // The frame is dropped, the rest of the batch still goes to the protocols.
+                    let Some(interface) = self.get_interface(p.protocol_type.to_family()) else {
+                        debug!(
+                            "net device frame dropped, ipv4 interface not found, dev: {}",
+                            self.name
+                        );
+                        break;
+                    };
+                    let mut queue = p.queue.lock().unwrap();
+                    queue.push_back(Ipv4QueueEntry {
+                        data: payload,
+                        interface,
+                    });
+                    debug!("net protocol queue pushed, len: {}", queue.len());
+                    break;
+                }
             }
         }
 
-        raise(INTR_IRQ_L3)?;
-        Ok(())
+        Ok(len)
     }
 }
 
@@ -220,6 +266,7 @@ pub struct NetDeviceOps {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocols::NetProtocol;
 
     #[test]
     fn open_device() {
@@ -258,4 +305,25 @@ mod tests {
         assert_eq!(iter.next().unwrap().lock().unwrap().flags, 0x0000);
         assert_eq!(iter.next().unwrap().lock().unwrap().flags, 0x0000);
     }
+
+    #[test]
+    fn poll_skips_frames_without_interface() {
+        let mac_a = MacAddress([2, 0, 0, 0, 0, 1]);
+        let mac_b = MacAddress([2, 0, 0, 0, 0, 2]);
+        let (mut device_a, mut device_b) = NetDevice::link_pair(("a", mac_a), ("b", mac_b));
+        device_a.open().unwrap();
+        device_b.open().unwrap();
+        device_a
+            .send(&[0; 20], NetProtocolType::Ipv4, mac_b)
+            .unwrap();
+        device_a
+            .send(&[0; 28], NetProtocolType::Arp, mac_b)
+            .unwrap();
+
+        let mut protocols = NetProtocols::new();
+        protocols.push_back(NetProtocol::ipv4());
+        protocols.push_back(NetProtocol::arp());
+        assert_eq!(device_b.poll(&mut protocols).unwrap(), 2);
+        assert!(protocols.iter().all(|p| p.queue.lock().unwrap().is_empty()));
+    }
 }
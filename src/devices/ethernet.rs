@@ -1,7 +1,6 @@
-use crate::{
-    driver::{tap, DriverType},
-    protocols::NetProtocolType,
-};
+use log::debug;
+
+use crate::protocols::NetProtocolType;
 
 use super::NetDevice;
 
@@ -26,6 +25,29 @@ impl From<&[u8]> for MacAddress {
     }
 }
 
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let octets = self.0.map(|octet| format!("{:02x}", octet));
+        write!(f, "{}", octets.join(":"))
+    }
+}
+
+impl TryFrom<&str> for MacAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let octets: Vec<&str> = value.split(':').collect();
+        if octets.len() != MAC_ADDRESS_LEN {
+            anyhow::bail!("invalid mac address: {}", value);
+        }
+        let mut addr = [0; MAC_ADDRESS_LEN];
+        for (byte, octet) in addr.iter_mut().zip(octets) {
+            *byte = u8::from_str_radix(octet, 16)?;
+        }
+        Ok(MacAddress(addr))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct EthernetHeader {
     pub dst: MacAddress,
@@ -54,12 +76,67 @@ impl TryFrom<&[u8]> for EthernetHeader {
     }
 }
 
-#[tracing::instrument(skip_all)]
-pub fn recv(device: &mut NetDevice) -> anyhow::Result<(NetProtocolType, Vec<u8>)> {
-    let data = match device.driver.as_ref().expect("device driver not set") {
-        DriverType::Tap { .. } => tap::read(device)?,
+/// Sends an ethernet frame through the device's driver, the transmit op of ethernet devices.
+#[tracing::instrument(skip(device, data))]
+pub fn transmit(
+    device: &mut NetDevice,
+    data: &[u8],
+    ty: NetProtocolType,
+    dst: MacAddress,
+) -> anyhow::Result<()> {
+    let header = EthernetHeader {
+        dst,
+        src: MacAddress::from(device.hw_addr[..MAC_ADDRESS_LEN].as_ref()),
+        ty,
     };
-    let header = EthernetHeader::try_from(data.as_ref())?;
+
+    let mut frame = header.to_bytes();
+    frame.extend_from_slice(data);
+
+    let len_padding = if data.len() < ETHERNET_PAYLOAD_MIN_SIZE {
+        ETHERNET_PAYLOAD_MIN_SIZE - data.len()
+    } else {
+        0
+    };
+    frame.extend_from_slice(&vec![0; len_padding]);
+    if let Some(driver) = device.driver.as_mut() {
+        driver.write(&frame)?;
+    }
+
+    debug!(
+        "ethernet frame transmitted, dev: {}, type: {:#04x}, len: {}",
+        device.name,
+        ty as u16,
+        frame.len()
+    );
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub fn recv(device: &mut NetDevice) -> anyhow::Result<Vec<(NetProtocolType, Vec<u8>)>> {
+    let mut frames = vec![];
+    loop {
+        let driver = device.driver.as_mut().expect("device driver not set");
+        let Some(data) = driver.read(ETHERNET_FRAME_MAX_SIZE)? else {
+            break;
+        };
+        // A bad frame is dropped without stranding the rest of the batch.
+        match input(device, &data) {
+            Ok(frame) => frames.push(frame),
+            Err(err) => debug!("ethernet frame dropped, dev: {}, {:?}", device.name, err),
+        }
+    }
+    Ok(frames)
+}
+
+fn input(device: &NetDevice, data: &[u8]) -> anyhow::Result<(NetProtocolType, Vec<u8>)> {
+    anyhow::ensure!(
+        data.len() >= ETHERNET_HEADER_SIZE,
+        "too short ethernet frame, len: {}",
+        data.len()
+    );
+    let header = EthernetHeader::try_from(data)?;
     if header.dst != MacAddress::from(&device.hw_addr[..MAC_ADDRESS_LEN])
         && header.dst != MAC_ADDRESS_BROADCAST
     {
@@ -73,3 +150,45 @@ pub fn recv(device: &mut NetDevice) -> anyhow::Result<(NetProtocolType, Vec<u8>)
     let payload = data[ETHERNET_HEADER_SIZE..].to_vec();
     Ok((header.ty, payload))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::fd::OwnedFd, os::unix::net::UnixDatagram};
+
+    use super::*;
+    use crate::driver::DriverType;
+
+    #[test]
+    fn test_recv_drains_pending_frames() {
+        let (tap, peer) = UnixDatagram::pair().unwrap();
+        tap.set_nonblocking(true).unwrap();
+        let mut device = NetDevice::ethernet_tap("tap0");
+        device.hw_addr[..MAC_ADDRESS_LEN].copy_from_slice(&[0x00, 0x00, 0x5e, 0x00, 0x53, 0x01]);
+        device.driver = Some(DriverType::Tap {
+            file: File::from(OwnedFd::from(tap)),
+            dropped: 0,
+        });
+
+        let frame = |dst: MacAddress, payload: u8| {
+            let mut frame = EthernetHeader {
+                dst,
+                src: MacAddress([0x00, 0x00, 0x5e, 0x00, 0x53, 0x02]),
+                ty: NetProtocolType::Ipv4,
+            }
+            .to_bytes();
+            frame.push(payload);
+            frame
+        };
+        let hw_addr = MacAddress::from(&device.hw_addr[..MAC_ADDRESS_LEN]);
+        peer.send(&frame(hw_addr, 1)).unwrap();
+        peer.send(&frame(MacAddress([0x02; MAC_ADDRESS_LEN]), 2))
+            .unwrap();
+        peer.send(&frame(MAC_ADDRESS_BROADCAST, 3)).unwrap();
+
+        let frames = recv(&mut device).unwrap();
+        let payloads = frames.iter().map(|(_, p)| p[0]).collect::<Vec<_>>();
+        // The frame for another host is dropped without losing the one after it.
+        assert_eq!(payloads, [1, 3]);
+        assert!(recv(&mut device).unwrap().is_empty());
+    }
+}
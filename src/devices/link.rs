@@ -0,0 +1,300 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    driver::{DriverType, LinkQueue},
+    interrupt::{IrqEntry, INTR_IRQ_NULL, INTR_IRQ_SHARED},
+};
+
+use super::{
+    ethernet::{
+        self, MacAddress, ETHERNET_HEADER_SIZE, ETHERNET_PAYLOAD_MAX_SIZE, MAC_ADDRESS_LEN,
+    },
+    CastType, NetDevice, NetDeviceOps, NetDeviceQueueEntry, NetDeviceType, NET_DEVICE_ADDR_LEN,
+    NET_DEVICE_FLAG_BROADCAST, NET_DEVICE_FLAG_NEED_ARP,
+};
+
+fn open(_: &mut NetDevice) -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn close(_: &mut NetDevice) -> anyhow::Result<()> {
+    Ok(())
+}
+
+impl NetDevice {
+    /// Two ethernet devices connected back to back through in-process queues.
+    /// Frames raise no IRQ, the owner of each end takes them with `NetDevice::poll`.
+    pub fn link_pair(
+        (name_a, hw_addr_a): (&str, MacAddress),
+        (name_b, hw_addr_b): (&str, MacAddress),
+    ) -> (NetDevice, NetDevice) {
+        let a_to_b: LinkQueue = Arc::new(Mutex::new(VecDeque::new()));
+        let b_to_a: LinkQueue = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            NetDevice::link(name_a, hw_addr_a, b_to_a.clone(), a_to_b.clone()),
+            NetDevice::link(name_b, hw_addr_b, a_to_b, b_to_a),
+        )
+    }
+
+    fn link(name: &str, hw_addr: MacAddress, rx: LinkQueue, tx: LinkQueue) -> Self {
+        let irq_entry = IrqEntry {
+            irq: INTR_IRQ_NULL,
+            flags: INTR_IRQ_SHARED,
+        };
+        let mut addr = [0; NET_DEVICE_ADDR_LEN];
+        addr[..MAC_ADDRESS_LEN].copy_from_slice(&hw_addr.0);
+
+        Self {
+            index: 0,
+            name: name.to_string(),
+            ty: NetDeviceType::Ethernet,
+            mtu: ETHERNET_PAYLOAD_MAX_SIZE,
+            flags: NET_DEVICE_FLAG_BROADCAST | NET_DEVICE_FLAG_NEED_ARP,
+            header_len: ETHERNET_HEADER_SIZE as u16,
+            addr_len: MAC_ADDRESS_LEN as u16,
+            hw_addr: addr,
+            cast_type: CastType::Peer([0; NET_DEVICE_ADDR_LEN]),
+            ops: NetDeviceOps {
+                open,
+                close,
+                send: ethernet::transmit,
+            },
+            driver: Some(DriverType::Link { rx, tx }),
+            irq_entry,
+            queue: NetDeviceQueueEntry::Null,
+            interfaces: Default::default(),
+        }
+    }
+}
+
+/// Stack instances connected by links, driven from the test instead of IRQs.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use log::debug;
+
+    use crate::{
+        devices::{ethernet::MacAddress, NetDevice, NetDevices},
+        driver::DriverType,
+        interrupt::TIMER_INTERVAL,
+        protocols::{
+            arp::{self, ARP_TIMER_INTERVAL},
+            ipv4::{self, reassembly::REASSEMBLY_TIMER_INTERVAL, Ipv4Address, Ipv4Interface},
+            NetProtocol, NetProtocols, ProtocolStackContext,
+        },
+        transport::{
+            icmp::{self, IcmpType},
+            tcp, ContextBlocks,
+        },
+    };
+
+    // Bounds the rounds of `settle`, frames bouncing between hosts forever are a bug.
+    const SETTLE_ROUNDS_MAX: usize = 1000;
+
+    pub struct Host {
+        pub devices: NetDevices,
+        protocols: Mutex<NetProtocols>,
+        pub context: Arc<Mutex<ProtocolStackContext>>,
+        pub pcbs: Arc<Mutex<ContextBlocks>>,
+    }
+
+    impl Host {
+        pub fn new() -> Self {
+            let mut context = ProtocolStackContext::new();
+            context
+                .timers
+                .register("arp", ARP_TIMER_INTERVAL, arp::handle_timer);
+            context
+                .timers
+                .register("tcp", TIMER_INTERVAL, tcp::handle_timer);
+            context.timers.register(
+                "ipv4 reassembly",
+                REASSEMBLY_TIMER_INTERVAL,
+                ipv4::handle_reassembly_timer,
+            );
+            let mut protocols = NetProtocols::new();
+            protocols.push_back(NetProtocol::ipv4());
+            protocols.push_back(NetProtocol::arp());
+            Host {
+                devices: NetDevices::new(),
+                protocols: Mutex::new(protocols),
+                context: Arc::new(Mutex::new(context)),
+                pcbs: Arc::new(Mutex::new(ContextBlocks::new())),
+            }
+        }
+
+        /// Opens `device` with an interface of `unicast`/`prefix`.
+        pub fn attach(
+            &mut self,
+            device: NetDevice,
+            unicast: [u8; 4],
+            prefix: u32,
+        ) -> Arc<Mutex<NetDevice>> {
+            let device = Arc::new(Mutex::new(device));
+            let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let interface = Arc::new(Ipv4Interface::new(
+                Ipv4Address::new(&unicast),
+                Ipv4Address::new(&netmask.to_be_bytes()),
+                device.clone(),
+            ));
+            let mut guard = device.lock().unwrap();
+            guard.register_interface(&mut self.context.lock().unwrap(), interface);
+            guard.open().unwrap();
+            drop(guard);
+            self.devices.push_back(device.clone());
+            device
+        }
+
+        /// The device attached first.
+        pub fn device(&self) -> Arc<Mutex<NetDevice>> {
+            self.devices.front().expect("no device attached").clone()
+        }
+
+        /// Handles whatever arrived on the links and the expired timers, as the IRQ handlers do.
+        /// Returns false when there was nothing to do.
+        pub fn poll(&self) -> bool {
+            let mut frames = 0;
+            for device in self.devices.iter() {
+                let mut protocols = self.protocols.lock().unwrap();
+                frames += device.lock().unwrap().poll(&mut protocols).unwrap();
+            }
+            let mut context = self.context.lock().unwrap();
+            let mut pcbs = self.pcbs.lock().unwrap();
+            for protocol in self.protocols.lock().unwrap().iter() {
+                if let Err(err) = protocol.recv(&mut context, &mut pcbs) {
+                    debug!("handle packet failed: {:?}", err);
+                }
+            }
+            for (_, handler) in context.timers.expired(Instant::now()) {
+                handler(&mut context, &mut pcbs).unwrap();
+            }
+            frames > 0
+        }
+    }
+
+    /// Connects two hosts with a link, `a` and `b` being the addresses of each end.
+    pub fn connect(
+        host_a: &mut Host,
+        a: [u8; 4],
+        host_b: &mut Host,
+        b: [u8; 4],
+        prefix: u32,
+    ) -> (Arc<Mutex<NetDevice>>, Arc<Mutex<NetDevice>>) {
+        // Locally administered addresses derived from the IPv4 addresses.
+        let mac = |ip: [u8; 4]| MacAddress([0x02, 0x00, ip[0], ip[1], ip[2], ip[3]]);
+        let (device_a, device_b) = NetDevice::link_pair(
+            (&format!("link-{}", Ipv4Address::new(&a)), mac(a)),
+            (&format!("link-{}", Ipv4Address::new(&b)), mac(b)),
+        );
+        (
+            host_a.attach(device_a, a, prefix),
+            host_b.attach(device_b, b, prefix),
+        )
+    }
+
+    /// Sends an ICMP echo request, e.g. for the hosts to resolve each other before a test.
+    pub fn echo(host: &Host, src: [u8; 4], dst: [u8; 4]) {
+        let mut context = host.context.lock().unwrap();
+        icmp::send(
+            &mut context,
+            IcmpType::Echo,
+            0,
+            1,
+            b"ping",
+            Ipv4Address::new(&src),
+            Ipv4Address::new(&dst),
+        )
+        .unwrap();
+    }
+
+    /// Frames left on the link for `device`, that is until its host is polled.
+    pub fn pending(device: &Arc<Mutex<NetDevice>>) -> Vec<Vec<u8>> {
+        let device = device.lock().unwrap();
+        let Some(DriverType::Link { rx, .. }) = &device.driver else {
+            panic!("link driver not set");
+        };
+        let frames = rx.lock().unwrap().iter().cloned().collect();
+        frames
+    }
+
+    /// Polls the hosts until no frame is in flight.
+    pub fn settle(hosts: &[&Host]) {
+        for _ in 0..SETTLE_ROUNDS_MAX {
+            // Every host is polled, not only up to the first busy one.
+            let busy = hosts.iter().filter(|host| host.poll()).count();
+            if busy == 0 {
+                return;
+            }
+        }
+        panic!("hosts did not settle");
+    }
+
+    /// Two hosts linked on a /24, `a` and `b` having resolved each other already.
+    pub fn pair(a: [u8; 4], b: [u8; 4]) -> (Host, Host) {
+        let (mut host_a, mut host_b) = (Host::new(), Host::new());
+        connect(&mut host_a, a, &mut host_b, b, 24);
+        echo(&host_a, a, b);
+        settle(&[&host_a, &host_b]);
+        (host_a, host_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{connect, echo, pair, pending, settle, Host};
+    use super::*;
+    use crate::{
+        protocols::{arp::ArpCacheState, ipv4::Ipv4Address},
+        transport::icmp::IcmpType,
+    };
+
+    const A: [u8; 4] = [192, 0, 2, 1];
+    const B: [u8; 4] = [192, 0, 2, 2];
+
+    #[test]
+    fn test_arp_resolves_across_link() {
+        let (mut host_a, mut host_b) = (Host::new(), Host::new());
+        connect(&mut host_a, A, &mut host_b, B, 24);
+
+        echo(&host_a, A, B);
+        settle(&[&host_a, &host_b]);
+        let resolved = |host: &Host, ip: [u8; 4]| {
+            host.context
+                .lock()
+                .unwrap()
+                .arp_cache
+                .get(&Ipv4Address::new(&ip))
+        };
+        assert_eq!(
+            resolved(&host_a, B),
+            Some(ArpCacheState::Resolved(MacAddress([2, 0, 192, 0, 2, 2])))
+        );
+        assert_eq!(
+            resolved(&host_b, A),
+            Some(ArpCacheState::Resolved(MacAddress([2, 0, 192, 0, 2, 1])))
+        );
+    }
+
+    #[test]
+    fn test_echo_is_replied_across_link() {
+        let (host_a, host_b) = pair(A, B);
+        let device_a = host_a.device();
+
+        // Only host B is polled, so the reply is left on the link for host A.
+        echo(&host_a, A, B);
+        while host_b.poll() {}
+        let frames = pending(&device_a);
+        assert_eq!(frames.len(), 1);
+        // The IPv4 header carries no options.
+        let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+        assert_eq!(icmp[0], IcmpType::EchoReply as u8);
+        assert_eq!(&icmp[8..12], b"ping");
+    }
+}
@@ -4,10 +4,9 @@ use std::{
 };
 
 use log::debug;
-use signal_hook::low_level::raise;
 
 use crate::{
-    interrupt::{IrqEntry, INTR_IRQ_LOOPBACK},
+    interrupt::{raise_irq, IrqEntry, INTR_IRQ_LOOPBACK},
     protocols::NetProtocolType,
 };
 
@@ -49,7 +48,7 @@ fn send(
         queue.len()
     );
 
-    raise(INTR_IRQ_LOOPBACK)?;
+    raise_irq(INTR_IRQ_LOOPBACK)?;
     Ok(())
 }
 
@@ -1,10 +1,147 @@
-use std::fs::File;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+};
 
+use log::debug;
+use nix::{
+    errno::Errno,
+    libc::{c_int, fcntl, getpid, F_SETFL, F_SETOWN, O_ASYNC, O_NONBLOCK},
+};
+
+use crate::interrupt::{self, backend, EventBackend};
+
+pub mod packet;
 pub mod tap;
+pub mod tun;
+
+const F_SETSIG: c_int = 10;
+
+/// Frames in flight on one direction of an in-memory link.
+pub type LinkQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
 
+/// File-backed drivers count the frames dropped as the kernel would have blocked the write.
 #[derive(Debug)]
 pub enum DriverType {
-    Tap{
-        file: File,
-    },
+    Tap { file: File, dropped: usize },
+    Tun { file: File, dropped: usize },
+    Packet { file: File, dropped: usize },
+    Link { rx: LinkQueue, tx: LinkQueue },
+}
+
+impl DriverType {
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            DriverType::Tap { file, dropped }
+            | DriverType::Tun { file, dropped }
+            | DriverType::Packet { file, dropped } => match file.write(data) {
+                Ok(n) if n == data.len() => {}
+                Ok(n) => anyhow::bail!("short write, len: {}, written: {}", data.len(), n),
+                // The fd does not block, a full queue drops the frame as a NIC would.
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    *dropped += 1;
+                    debug!("frame dropped, len: {}, dropped: {}", data.len(), dropped);
+                }
+                Err(err) => return Err(err.into()),
+            },
+            DriverType::Link { tx, .. } => tx.lock().unwrap().push_back(data.to_vec()),
+        }
+        Ok(())
+    }
+
+    /// Frames dropped on transmit so far.
+    pub fn dropped(&self) -> usize {
+        match self {
+            DriverType::Tap { dropped, .. }
+            | DriverType::Tun { dropped, .. }
+            | DriverType::Packet { dropped, .. } => *dropped,
+            DriverType::Link { .. } => 0,
+        }
+    }
+
+    /// Reads up to `len` bytes, or returns `None` when nothing is pending.
+    pub fn read(&mut self, len: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let file = match self {
+            DriverType::Tap { file, .. }
+            | DriverType::Tun { file, .. }
+            | DriverType::Packet { file, .. } => file,
+            DriverType::Link { rx, .. } => {
+                let mut data = rx.lock().unwrap().pop_front();
+                if let Some(data) = data.as_mut() {
+                    data.truncate(len);
+                }
+                return Ok(data);
+            }
+        };
+        let mut buf = vec![0; len];
+        match file.read(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Some(buf))
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Raises `irq` whenever `file` becomes readable, through the selected event backend.
+/// The file must be non-blocking as the handler reads it until EAGAIN.
+pub fn request_irq(irq: i32, file: &File) -> anyhow::Result<()> {
+    if backend() == EventBackend::Epoll {
+        return interrupt::epoll::request_irq(irq, file);
+    }
+    let fd = file.as_raw_fd();
+    unsafe {
+        // Set asynchronous I/O destination
+        if fcntl(fd, F_SETOWN, getpid() as c_int) == -1 {
+            anyhow::bail!("fcntl F_SETOWN failed: {}", Errno::last_raw());
+        }
+        // Enable asynchronous I/O
+        if fcntl(fd, F_SETFL, O_ASYNC | O_NONBLOCK) == -1 {
+            anyhow::bail!("fcntl F_SETFL failed: {}", Errno::last_raw());
+        }
+        // Use other signal than SIGIO
+        if fcntl(fd, F_SETSIG, irq as c_int) == -1 {
+            anyhow::bail!("fcntl F_SETSIG failed: {}", Errno::last_raw());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::fd::OwnedFd, os::unix::net::UnixDatagram};
+
+    use super::*;
+
+    #[test]
+    fn test_write_drops_frames_when_full() {
+        let (tap, peer) = UnixDatagram::pair().unwrap();
+        tap.set_nonblocking(true).unwrap();
+        let mut driver = DriverType::Tap {
+            file: File::from(OwnedFd::from(tap)),
+            dropped: 0,
+        };
+
+        // The peer reads nothing, so its queue fills up.
+        let mut sent = 0;
+        while driver.dropped() == 0 {
+            driver.write(&[0; 64]).unwrap();
+            sent += 1;
+        }
+        driver.write(&[0; 64]).unwrap();
+        assert_eq!(driver.dropped(), 2);
+
+        let mut buf = [0; 64];
+        peer.set_nonblocking(true).unwrap();
+        let mut received = 0;
+        while peer.recv(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, sent - 1);
+    }
 }
@@ -0,0 +1,224 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use log::info;
+use nix::libc::{
+    self, c_int, c_void, packet_mreq, sockaddr, sockaddr_ll, socklen_t, AF_PACKET, ETH_P_ALL,
+    PACKET_ADD_MEMBERSHIP, PACKET_IGNORE_OUTGOING, PACKET_MR_PROMISC, SOCK_CLOEXEC, SOCK_NONBLOCK,
+    SOCK_RAW, SOL_PACKET,
+};
+
+use crate::{
+    devices::{
+        ethernet::{
+            self, ETHERNET_HEADER_SIZE, ETHERNET_PAYLOAD_MAX_SIZE, MAC_ADDRESS_ANY, MAC_ADDRESS_LEN,
+        },
+        CastType, NetDevice, NetDeviceOps, NetDeviceQueueEntry, NetDeviceType, NET_DEVICE_ADDR_LEN,
+        NET_DEVICE_FLAG_BROADCAST, NET_DEVICE_FLAG_NEED_ARP, NET_DEVICE_FLAG_PROMISC,
+    },
+    interrupt::{IrqEntry, INTR_IRQ_PACKET},
+};
+
+use super::{request_irq, tap::set_hw_address, DriverType};
+
+fn open(device: &mut NetDevice) -> anyhow::Result<()> {
+    let name = CString::new(device.name.as_str())?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        anyhow::bail!(
+            "interface not found, dev: {}, {}",
+            device.name,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    let protocol = (ETH_P_ALL as u16).to_be();
+    let fd = unsafe {
+        libc::socket(
+            AF_PACKET,
+            SOCK_RAW | SOCK_NONBLOCK | SOCK_CLOEXEC,
+            protocol as c_int,
+        )
+    };
+    if fd < 0 {
+        anyhow::bail!("socket failed: {}", std::io::Error::last_os_error());
+    }
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let mut addr: sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = index as c_int;
+    let len = mem::size_of::<sockaddr_ll>() as socklen_t;
+    if unsafe { libc::bind(fd, &addr as *const sockaddr_ll as *const sockaddr, len) } < 0 {
+        anyhow::bail!("bind failed: {}", std::io::Error::last_os_error());
+    }
+    // Frames transmitted by the stack itself are not read back.
+    set_option(file.as_raw_fd(), PACKET_IGNORE_OUTGOING, &(1 as c_int))?;
+    if device.flags & NET_DEVICE_FLAG_PROMISC != 0 {
+        let mut mreq: packet_mreq = unsafe { mem::zeroed() };
+        mreq.mr_ifindex = index as c_int;
+        mreq.mr_type = PACKET_MR_PROMISC as u16;
+        set_option(file.as_raw_fd(), PACKET_ADD_MEMBERSHIP, &mreq)?;
+    }
+
+    request_irq(device.irq_entry.irq, &file)?;
+    device.driver = Some(DriverType::Packet { file, dropped: 0 });
+
+    // Without an address of its own, the stack shares the one of the interface.
+    if device.hw_addr[..MAC_ADDRESS_LEN] == MAC_ADDRESS_ANY.0 {
+        set_hw_address(device)?;
+    }
+    info!(
+        "packet socket bound, dev: {}, index: {}, promiscuous: {}",
+        device.name,
+        index,
+        device.flags & NET_DEVICE_FLAG_PROMISC != 0
+    );
+    Ok(())
+}
+
+// The promiscuous membership is dropped by the kernel along with the socket.
+fn close(_device: &mut NetDevice) -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn set_option<T>(fd: RawFd, name: c_int, value: &T) -> anyhow::Result<()> {
+    let len = mem::size_of::<T>() as socklen_t;
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_PACKET,
+            name,
+            value as *const T as *const c_void,
+            len,
+        )
+    } < 0
+    {
+        anyhow::bail!(
+            "setsockopt failed, name: {}, {}",
+            name,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+impl NetDevice {
+    /// A device attached to an existing interface, e.g. one end of a veth pair.
+    /// A promiscuous device also receives frames for a hardware address other than the interface's.
+    /// Checksum offload must be disabled on a veth peer, or frames are read with partial checksums.
+    pub fn packet(name: &str, promiscuous: bool) -> Self {
+        let irq_entry = IrqEntry {
+            irq: INTR_IRQ_PACKET,
+            flags: 0x00,
+        };
+        let mut flags = NET_DEVICE_FLAG_BROADCAST | NET_DEVICE_FLAG_NEED_ARP;
+        if promiscuous {
+            flags |= NET_DEVICE_FLAG_PROMISC;
+        }
+
+        Self {
+            index: 0,
+            name: name.to_string(),
+            ty: NetDeviceType::Ethernet,
+            mtu: ETHERNET_PAYLOAD_MAX_SIZE,
+            flags,
+            header_len: ETHERNET_HEADER_SIZE as u16,
+            addr_len: MAC_ADDRESS_LEN as u16,
+            hw_addr: [0; NET_DEVICE_ADDR_LEN],
+            cast_type: CastType::Peer([0; NET_DEVICE_ADDR_LEN]),
+            ops: NetDeviceOps {
+                open,
+                close,
+                send: ethernet::transmit,
+            },
+            driver: None,
+            irq_entry,
+            queue: NetDeviceQueueEntry::Null,
+            interfaces: std::collections::LinkedList::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+    use crate::devices::{
+        ethernet::{EthernetHeader, MacAddress},
+        NetDeviceType, NET_DEVICE_FLAG_UP,
+    };
+    use crate::protocols::NetProtocolType;
+
+    #[test]
+    fn test_promiscuous_flag() {
+        let device = NetDevice::packet("veth0", false);
+        assert_eq!(device.mtu, ETHERNET_PAYLOAD_MAX_SIZE);
+        assert_eq!(device.flags & NET_DEVICE_FLAG_PROMISC, 0);
+        assert_eq!(device.hw_addr[..MAC_ADDRESS_LEN], MAC_ADDRESS_ANY.0);
+
+        let device = NetDevice::packet("veth0", true);
+        assert_ne!(device.flags & NET_DEVICE_FLAG_PROMISC, 0);
+        assert_ne!(device.flags & NET_DEVICE_FLAG_NEED_ARP, 0);
+    }
+
+    #[test]
+    fn test_open_unknown_interface_fails() {
+        let mut device = NetDevice::packet("unet-missing0", false);
+        assert!(device.open().is_err());
+        assert!(device.driver.is_none());
+    }
+
+    #[test]
+    fn test_frames_go_through_socket() {
+        let (socket, peer) = UnixDatagram::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut device = NetDevice::packet("veth0", false);
+        let hw_addr = MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        device.hw_addr[..MAC_ADDRESS_LEN].copy_from_slice(&hw_addr.0);
+        device.driver = Some(DriverType::Packet {
+            file: File::from(OwnedFd::from(socket)),
+            dropped: 0,
+        });
+
+        let peer_addr = MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+        let mut frame = EthernetHeader {
+            dst: hw_addr,
+            src: peer_addr,
+            ty: NetProtocolType::Arp,
+        }
+        .to_bytes();
+        frame.extend_from_slice(&[1, 2, 3]);
+        peer.send(&frame).unwrap();
+        let frames = ethernet::recv(&mut device).unwrap();
+        assert_eq!(frames, vec![(NetProtocolType::Arp, vec![1, 2, 3])]);
+
+        ethernet::transmit(&mut device, &[4, 5, 6], NetProtocolType::Ipv4, peer_addr).unwrap();
+        let mut buf = [0; 128];
+        let len = peer.recv(&mut buf).unwrap();
+        let header = EthernetHeader::try_from(&buf[..len]).unwrap();
+        assert_eq!(header.dst, peer_addr);
+        assert_eq!(header.src, hw_addr);
+        assert_eq!(header.ty, NetProtocolType::Ipv4);
+        assert_eq!(
+            &buf[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + 3],
+            &[4, 5, 6]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn test_open_binds_loopback() {
+        let mut device = NetDevice::packet("lo", false);
+        device.open().unwrap();
+        assert_eq!(device.flags & NET_DEVICE_FLAG_UP, NET_DEVICE_FLAG_UP);
+        assert_eq!(device.ty, NetDeviceType::Ethernet);
+        assert!(matches!(device.driver, Some(DriverType::Packet { .. })));
+    }
+}
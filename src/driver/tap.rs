@@ -1,39 +1,31 @@
 use core::slice;
 use std::{
     ffi::CString,
-    fs::OpenOptions,
-    io::{Read, Write},
-    os::fd::AsRawFd,
+    fs::{File, OpenOptions},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
 };
 
-use log::{debug, info};
+use log::info;
 use nix::{
-    errno::Errno,
     ioctl_read_bad, ioctl_write_int,
-    libc::{
-        c_int, c_short, fcntl, getpid, ifreq, F_SETFL, F_SETOWN, IFF_NO_PI, IFF_TAP, IFNAMSIZ,
-        O_ASYNC,
-    },
+    libc::{c_int, c_short, ifreq, IFF_NO_PI, IFF_TAP, IFNAMSIZ, O_NONBLOCK},
     sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType},
 };
 
 use crate::{
     devices::{
         ethernet::{
-            EthernetHeader, MacAddress, ETHERNET_FRAME_MAX_SIZE, ETHERNET_FRAME_MIN_SIZE,
-            ETHERNET_HEADER_SIZE, ETHERNET_PAYLOAD_MAX_SIZE, MAC_ADDRESS_ANY, MAC_ADDRESS_LEN,
+            self, ETHERNET_HEADER_SIZE, ETHERNET_PAYLOAD_MAX_SIZE, MAC_ADDRESS_ANY, MAC_ADDRESS_LEN,
         },
         CastType, NetDevice, NetDeviceOps, NetDeviceType, NET_DEVICE_ADDR_LEN,
-        NET_DEVICE_FLAG_LOOPBACK, NET_DEVICE_FLAG_NEED_ARP,
+        NET_DEVICE_FLAG_BROADCAST, NET_DEVICE_FLAG_NEED_ARP,
     },
     interrupt::{IrqEntry, INTR_IRQ_ETHERNET_TAP},
-    protocols::NetProtocolType,
 };
 
-use super::DriverType;
+use super::{request_irq, DriverType};
 
 const TUN_PATH: &str = "/dev/net/tun";
-const F_SETSIG: c_int = 10;
 
 // You can find the definition of magic number in <linux/tun.h>
 // See also: https://www.kernel.org/doc/Documentation/networking/tuntap.txt
@@ -45,14 +37,25 @@ fn close(_device: &mut NetDevice) -> anyhow::Result<()> {
 }
 
 fn open(device: &mut NetDevice) -> anyhow::Result<()> {
+    let file = open_tun(device, IFF_TAP)?;
+    device.driver = Some(DriverType::Tap { file, dropped: 0 });
+
+    if device.hw_addr[..MAC_ADDRESS_LEN] == MAC_ADDRESS_ANY.0 {
+        set_hw_address(device)?;
+    }
+    Ok(())
+}
+
+/// Attaches to the TUN/TAP interface named after the device, `mode` being `IFF_TAP` or `IFF_TUN`.
+pub(super) fn open_tun(device: &NetDevice, mode: c_int) -> anyhow::Result<File> {
+    // Frames are drained until EAGAIN on each IRQ, so the fd must not block.
     let file = OpenOptions::new()
         .read(true)
         .write(true)
-        .open(TUN_PATH)
-        .unwrap();
+        .custom_flags(O_NONBLOCK)
+        .open(TUN_PATH)?;
     let fd = file.as_raw_fd();
-    device.driver = Some(DriverType::Tap { file });
-    let ifru_flags = (IFF_TAP | IFF_NO_PI) as c_short;
+    let ifru_flags = (mode | IFF_NO_PI) as c_short;
     let ifreq = ifreq {
         ifr_name: to_ifreq_name(&device.name)?,
         ifr_ifru: nix::libc::__c_anonymous_ifr_ifru { ifru_flags },
@@ -63,25 +66,8 @@ fn open(device: &mut NetDevice) -> anyhow::Result<()> {
         }
     }
 
-    unsafe {
-        // Set asynchronous I/O destination
-        if fcntl(fd, F_SETOWN, getpid() as c_int) == -1 {
-            anyhow::bail!("fcntl F_SETOWN failed: {}", Errno::last_raw());
-        }
-        // Enable asynchronous I/O
-        if fcntl(fd, F_SETFL, O_ASYNC) == -1 {
-            anyhow::bail!("fcntl F_SETFL failed: {}", Errno::last_raw());
-        }
-        // Use other signal than SIGIO
-        if fcntl(fd, F_SETSIG, device.irq_entry.irq as c_int) == -1 {
-            anyhow::bail!("fcntl F_SETSIG failed: {}", Errno::last_raw());
-        }
-
-        if device.hw_addr[..MAC_ADDRESS_LEN] == MAC_ADDRESS_ANY.0 {
-            set_tap_address(device)?;
-        }
-    }
-    Ok(())
+    request_irq(device.irq_entry.irq, &file)?;
+    Ok(file)
 }
 
 fn to_ifreq_name(name: &str) -> anyhow::Result<[i8; IFNAMSIZ]> {
@@ -99,7 +85,8 @@ fn to_ifreq_name(name: &str) -> anyhow::Result<[i8; IFNAMSIZ]> {
     Ok(buf)
 }
 
-fn set_tap_address(device: &mut NetDevice) -> anyhow::Result<()> {
+/// Takes the hardware address of the interface named after the device.
+pub(super) fn set_hw_address(device: &mut NetDevice) -> anyhow::Result<()> {
     // Open a any socket to call get_hw_addr
     let soc = socket(
         AddressFamily::Inet,
@@ -128,52 +115,8 @@ fn set_tap_address(device: &mut NetDevice) -> anyhow::Result<()> {
     Ok(())
 }
 
-#[tracing::instrument(skip(device, data))]
-pub fn send(
-    device: &mut NetDevice,
-    data: &[u8],
-    ty: NetProtocolType,
-    dst: MacAddress,
-) -> anyhow::Result<()> {
-    let header = EthernetHeader {
-        dst,
-        src: MacAddress::from(device.hw_addr[..MAC_ADDRESS_LEN].as_ref()),
-        ty,
-    };
-
-    let mut frame = header.to_bytes();
-    frame.extend_from_slice(data);
-
-    let len_padding = if data.len() < ETHERNET_FRAME_MIN_SIZE {
-        ETHERNET_FRAME_MIN_SIZE - data.len()
-    } else {
-        0
-    };
-    frame.extend_from_slice(&vec![0; len_padding]);
-    if let Some(mut driver) = device.driver.as_mut() {
-        let DriverType::Tap { ref mut file } = &mut driver;
-        file.write_all(&frame)?;
-    }
-
-    debug!(
-        "ethernet frame transmitted, dev: {}, type: {:#04x}, len: {}",
-        device.name,
-        ty as u16,
-        frame.len()
-    );
-
-    Ok(())
-}
-
-pub fn read(device: &mut NetDevice) -> anyhow::Result<Vec<u8>> {
-    let DriverType::Tap { ref mut file } = device.driver.as_mut().expect("device driver not set");
-    let mut buf = [0; ETHERNET_FRAME_MAX_SIZE];
-    let n = file.read(&mut buf)?;
-    Ok(buf[..n].to_vec())
-}
-
 impl NetDevice {
-    pub fn ethernet_tap() -> Self {
+    pub fn ethernet_tap(name: &str) -> Self {
         let irq_entry = IrqEntry {
             irq: INTR_IRQ_ETHERNET_TAP,
             flags: 0x00,
@@ -181,16 +124,20 @@ impl NetDevice {
 
         Self {
             index: 0,
-            name: "tap0".to_string(),
+            name: name.to_string(),
             ty: NetDeviceType::Ethernet,
             mtu: ETHERNET_PAYLOAD_MAX_SIZE,
-            flags: NET_DEVICE_FLAG_LOOPBACK | NET_DEVICE_FLAG_NEED_ARP,
+            flags: NET_DEVICE_FLAG_BROADCAST | NET_DEVICE_FLAG_NEED_ARP,
             header_len: ETHERNET_HEADER_SIZE as u16,
             addr_len: MAC_ADDRESS_LEN as u16,
             hw_addr: [0; NET_DEVICE_ADDR_LEN],
             // cast_type: CastType::Broadcast(MAC_ADDRESS_BROADCAST),
             cast_type: CastType::Peer([0; NET_DEVICE_ADDR_LEN]),
-            ops: NetDeviceOps { open, close, send },
+            ops: NetDeviceOps {
+                open,
+                close,
+                send: ethernet::transmit,
+            },
             driver: None,
             irq_entry,
             queue: crate::devices::NetDeviceQueueEntry::Null,
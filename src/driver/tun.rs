@@ -0,0 +1,143 @@
+use log::debug;
+use nix::libc::IFF_TUN;
+
+use crate::{
+    devices::{
+        ethernet::MacAddress, CastType, NetDevice, NetDeviceOps, NetDeviceQueueEntry,
+        NetDeviceType, NET_DEVICE_ADDR_LEN, NET_DEVICE_FLAG_P2P,
+    },
+    interrupt::{IrqEntry, INTR_IRQ_TUN},
+    protocols::NetProtocolType,
+};
+
+use super::{tap::open_tun, DriverType};
+
+const TUN_MTU: usize = 1500;
+const IP_VERSION_4: u8 = 4;
+
+fn open(device: &mut NetDevice) -> anyhow::Result<()> {
+    let file = open_tun(device, IFF_TUN)?;
+    device.driver = Some(DriverType::Tun { file, dropped: 0 });
+    Ok(())
+}
+
+fn close(_device: &mut NetDevice) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Writes a raw IP packet, there is no link header and hence no destination address.
+#[tracing::instrument(skip(device, data, _dst))]
+fn send(
+    device: &mut NetDevice,
+    data: &[u8],
+    ty: NetProtocolType,
+    _dst: MacAddress,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        ty == NetProtocolType::Ipv4,
+        "unsupported protocol, dev: {}, type: {:?}",
+        device.name,
+        ty
+    );
+    if let Some(driver) = device.driver.as_mut() {
+        driver.write(data)?;
+    }
+    debug!(
+        "ip packet transmitted, dev: {}, len: {}",
+        device.name,
+        data.len()
+    );
+    Ok(())
+}
+
+/// Reads all pending packets, each of which goes to the ipv4 protocol as is.
+#[tracing::instrument(skip_all)]
+pub fn recv(device: &mut NetDevice) -> anyhow::Result<Vec<(NetProtocolType, Vec<u8>)>> {
+    let mut packets = vec![];
+    loop {
+        let driver = device.driver.as_mut().expect("device driver not set");
+        let Some(data) = driver.read(device.mtu)? else {
+            break;
+        };
+        match data.first() {
+            Some(byte) if byte >> 4 == IP_VERSION_4 => packets.push((NetProtocolType::Ipv4, data)),
+            _ => debug!(
+                "ip packet dropped, dev: {}, len: {}",
+                device.name,
+                data.len()
+            ),
+        }
+    }
+    Ok(packets)
+}
+
+impl NetDevice {
+    pub fn tun(name: &str) -> Self {
+        let irq_entry = IrqEntry {
+            irq: INTR_IRQ_TUN,
+            flags: 0x00,
+        };
+
+        Self {
+            index: 0,
+            name: name.to_string(),
+            ty: NetDeviceType::Tun,
+            mtu: TUN_MTU,
+            flags: NET_DEVICE_FLAG_P2P,
+            header_len: 0,
+            addr_len: 0,
+            hw_addr: [0; NET_DEVICE_ADDR_LEN],
+            cast_type: CastType::Peer([0; NET_DEVICE_ADDR_LEN]),
+            ops: NetDeviceOps { open, close, send },
+            driver: None,
+            irq_entry,
+            queue: NetDeviceQueueEntry::Null,
+            interfaces: std::collections::LinkedList::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::fd::OwnedFd, os::unix::net::UnixDatagram};
+
+    use super::*;
+
+    #[test]
+    fn test_recv_passes_ipv4_packets() {
+        let (tun, peer) = UnixDatagram::pair().unwrap();
+        tun.set_nonblocking(true).unwrap();
+        let mut device = NetDevice::tun("tun0");
+        device.driver = Some(DriverType::Tun {
+            file: File::from(OwnedFd::from(tun)),
+            dropped: 0,
+        });
+
+        peer.send(&[0x45, 0x00, 0x00, 0x14]).unwrap();
+        // An IPv6 packet is dropped.
+        peer.send(&[0x60, 0x00, 0x00, 0x00]).unwrap();
+        peer.send(&[0x45, 0x00, 0x00, 0x15]).unwrap();
+
+        let packets = recv(&mut device).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|(ty, _)| *ty == NetProtocolType::Ipv4));
+        assert_eq!(packets[1].1, [0x45, 0x00, 0x00, 0x15]);
+
+        send(
+            &mut device,
+            &[0x45, 0x01],
+            NetProtocolType::Ipv4,
+            MacAddress([0; 6]),
+        )
+        .unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(peer.recv(&mut buf).unwrap(), 2);
+        assert!(send(
+            &mut device,
+            &[0; 28],
+            NetProtocolType::Arp,
+            MacAddress([0; 6])
+        )
+        .is_err());
+    }
+}
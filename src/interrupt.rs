@@ -1,3 +1,9 @@
+use std::{str::FromStr, sync::OnceLock, time::Duration};
+
+use nix::libc::{self, SIGALRM};
+
+pub mod epoll;
+
 pub const INTR_IRQ_SHARED: u8 = 0x01;
 
 pub const INTR_IRQ_BASE: i32 = 35; // SIGRTMIN + 1
@@ -5,9 +11,80 @@ pub const INTR_IRQ_NULL: i32 = INTR_IRQ_BASE;
 pub const INTR_IRQ_LOOPBACK: i32 = INTR_IRQ_BASE + 1;
 pub const INTR_IRQ_ETHERNET_TAP: i32 = INTR_IRQ_BASE + 2;
 pub const INTR_IRQ_L3: i32 = INTR_IRQ_BASE + 3;
+pub const INTR_IRQ_TUN: i32 = INTR_IRQ_BASE + 4;
+pub const INTR_IRQ_PACKET: i32 = INTR_IRQ_BASE + 5;
+pub const INTR_IRQ_TIMER: i32 = SIGALRM;
+
+/// Interval at which protocol timers are checked.
+pub const TIMER_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone, Debug)]
 pub struct IrqEntry {
     pub irq: i32,
     pub flags: u8,
 }
+
+/// How IRQs are delivered to the main thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventBackend {
+    /// Realtime signals, with device fds set up for `F_SETSIG`.
+    #[default]
+    Signal,
+    /// An epoll instance watching device fds, eventfds and a timerfd.
+    Epoll,
+}
+
+impl FromStr for EventBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signal" => Ok(EventBackend::Signal),
+            "epoll" => Ok(EventBackend::Epoll),
+            _ => anyhow::bail!("unknown event backend: {}", s),
+        }
+    }
+}
+
+static BACKEND: OnceLock<EventBackend> = OnceLock::new();
+
+/// Selects the event backend. It must be called before any device is opened.
+pub fn set_backend(backend: EventBackend) -> anyhow::Result<()> {
+    BACKEND
+        .set(backend)
+        .map_err(|_| anyhow::anyhow!("event backend already set"))
+}
+
+pub fn backend() -> EventBackend {
+    BACKEND.get().copied().unwrap_or_default()
+}
+
+/// Raises a software IRQ through the selected backend.
+pub fn raise_irq(irq: i32) -> anyhow::Result<()> {
+    match backend() {
+        EventBackend::Signal => {
+            signal_hook::low_level::raise(irq)?;
+            Ok(())
+        }
+        EventBackend::Epoll => epoll::raise(irq),
+    }
+}
+
+/// Raises `INTR_IRQ_TIMER` periodically.
+pub fn start_timer(interval: Duration) -> anyhow::Result<()> {
+    if backend() == EventBackend::Epoll {
+        return epoll::start_timer(interval);
+    }
+    let interval = libc::timeval {
+        tv_sec: interval.as_secs() as libc::time_t,
+        tv_usec: interval.subsec_micros() as libc::suseconds_t,
+    };
+    let value = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_REAL, &value, std::ptr::null_mut()) } < 0 {
+        anyhow::bail!("setitimer failed: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
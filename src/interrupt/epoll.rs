@@ -0,0 +1,136 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    os::fd::AsFd,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use log::debug;
+use nix::sys::{
+    epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
+    eventfd::{EfdFlags, EventFd},
+    time::TimeSpec,
+    timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+};
+
+use super::INTR_IRQ_TIMER;
+
+const EPOLL_EVENTS_MAX: usize = 16;
+
+/// Event loop which delivers IRQs through epoll instead of realtime signals.
+#[derive(Debug)]
+struct EventLoop {
+    epoll: Epoll,
+    // Software IRQs raised by the stack itself, e.g. `INTR_IRQ_L3`.
+    events: Mutex<HashMap<i32, EventFd>>,
+    timer: Mutex<Option<TimerFd>>,
+}
+
+static EVENT_LOOP: OnceLock<EventLoop> = OnceLock::new();
+
+fn event_loop() -> anyhow::Result<&'static EventLoop> {
+    if let Some(event_loop) = EVENT_LOOP.get() {
+        return Ok(event_loop);
+    }
+    let event_loop = EventLoop {
+        epoll: Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?,
+        events: Mutex::new(HashMap::new()),
+        timer: Mutex::new(None),
+    };
+    // Another thread may have won the race, in which case its instance is used.
+    let _ = EVENT_LOOP.set(event_loop);
+    Ok(EVENT_LOOP.get().unwrap())
+}
+
+/// Delivers `irq` whenever `fd` becomes readable. The handler is expected to read it.
+pub fn request_irq<Fd: AsFd>(irq: i32, fd: Fd) -> anyhow::Result<()> {
+    event_loop()?
+        .epoll
+        .add(fd, EpollEvent::new(EpollFlags::EPOLLIN, irq as u64))?;
+    debug!("irq requested, irq: {}", irq);
+    Ok(())
+}
+
+pub fn raise(irq: i32) -> anyhow::Result<()> {
+    let event_loop = event_loop()?;
+    let mut events = event_loop.events.lock().unwrap();
+    let event = match events.entry(irq) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let event = EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+            event_loop
+                .epoll
+                .add(&event, EpollEvent::new(EpollFlags::EPOLLIN, irq as u64))?;
+            entry.insert(event)
+        }
+    };
+    event.write(1)?;
+    Ok(())
+}
+
+/// Raises `INTR_IRQ_TIMER` periodically through a timerfd.
+pub fn start_timer(interval: Duration) -> anyhow::Result<()> {
+    let event_loop = event_loop()?;
+    let timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_CLOEXEC | TimerFlags::TFD_NONBLOCK,
+    )?;
+    timer.set(
+        Expiration::Interval(TimeSpec::from_duration(interval)),
+        TimerSetTimeFlags::empty(),
+    )?;
+    event_loop.epoll.add(
+        &timer,
+        EpollEvent::new(EpollFlags::EPOLLIN, INTR_IRQ_TIMER as u64),
+    )?;
+    *event_loop.timer.lock().unwrap() = Some(timer);
+    Ok(())
+}
+
+/// Waits for IRQs and returns them, each at most once.
+/// A timeout of `None` blocks until one is raised.
+pub fn wait(timeout: Option<Duration>) -> anyhow::Result<Vec<i32>> {
+    let event_loop = event_loop()?;
+    let timeout = match timeout {
+        Some(timeout) => EpollTimeout::try_from(timeout)?,
+        None => EpollTimeout::NONE,
+    };
+    let mut events = [EpollEvent::empty(); EPOLL_EVENTS_MAX];
+    let n = match event_loop.epoll.wait(&mut events, timeout) {
+        Ok(n) => n,
+        Err(nix::errno::Errno::EINTR) => 0,
+        Err(err) => return Err(err.into()),
+    };
+    let mut irqs = vec![];
+    for event in &events[..n] {
+        let irq = event.data() as i32;
+        // Counters are cleared here, device fds stay readable until their handler reads them.
+        if let Some(event) = event_loop.events.lock().unwrap().get(&irq) {
+            let _ = event.read();
+        }
+        if irq == INTR_IRQ_TIMER {
+            if let Some(timer) = event_loop.timer.lock().unwrap().as_ref() {
+                let _ = timer.wait();
+            }
+        }
+        if !irqs.contains(&irq) {
+            irqs.push(irq);
+        }
+    }
+    Ok(irqs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::INTR_IRQ_L3;
+
+    #[test]
+    fn test_raise_is_delivered_once() {
+        raise(INTR_IRQ_L3).unwrap();
+        raise(INTR_IRQ_L3).unwrap();
+        let irqs = wait(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(irqs, [INTR_IRQ_L3]);
+        assert!(wait(Some(Duration::ZERO)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+//! A userspace TCP/IP stack on top of TAP, TUN and packet socket devices.
+//!
+//! A [`Stack`] is built from devices, their addresses and routes, either one by one or from a
+//! [`Config`], then started to handle IRQs from a thread of its own:
+//!
+//! ```no_run
+//! use unet::{Endpoint, Ipv4Prefix, NetDevice, StackBuilder, UdpSocket};
+//!
+//! let stack = StackBuilder::new()
+//!     .device(NetDevice::ethernet_tap("tap0"), &[Ipv4Prefix::try_from("192.0.2.2/24")?])
+//!     .route(Ipv4Prefix::network("default")?, Some("192.0.2.1".try_into()?), None, 0)
+//!     .build()?;
+//! stack.start()?;
+//! let socket = UdpSocket::open(&stack)?;
+//! socket.bind(Endpoint::new(&[192, 0, 2, 2], 7))?;
+//! socket.send_to(b"hello", Endpoint::new(&[192, 0, 2, 1], 7))?;
+//! let mut buf = [0; 1500];
+//! let (len, foreign) = socket.recv_from(&mut buf)?;
+//! println!("{} bytes from {}", len, foreign);
+//! socket.close()?;
+//! stack.stop()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod config;
+mod control;
+pub mod devices;
+mod driver;
+mod interrupt;
+mod protocols;
+mod socket;
+mod stack;
+mod timer;
+mod transport;
+mod utils;
+
+/// Internals the benchmarks drive directly, not part of the API.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::protocols::ipv4::{Ipv4Interface, Ipv4Router};
+}
+
+pub use config::{Config, Ipv4Prefix, DEFAULT_CONFIG};
+pub use control::CONTROL_SOCKET_PATH;
+pub use devices::{ethernet::MacAddress, NetDevice};
+pub use interrupt::EventBackend;
+pub use protocols::ipv4::Ipv4Address;
+pub use socket::{Pinger, TcpListener, TcpStream, UdpSocket};
+pub use stack::{Stack, StackBuilder};
+pub use transport::{icmp::PingStatistics, tcp::congestion::CongestionAlgorithm, Endpoint};
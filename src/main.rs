@@ -1,63 +1,133 @@
-use std::sync::{mpsc, Arc, Barrier};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use app::App;
-use interrupt::{INTR_IRQ_ETHERNET_TAP, INTR_IRQ_L3, INTR_IRQ_LOOPBACK, INTR_IRQ_NULL};
-use log::{debug, error, info};
+use log::{error, info};
 use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
+use unet::{
+    Config, Endpoint, Ipv4Address, Pinger, Stack, StackBuilder, UdpSocket, CONTROL_SOCKET_PATH,
+    DEFAULT_CONFIG,
+};
 
-mod app;
-mod devices;
-mod driver;
-mod interrupt;
-mod protocols;
-mod transport;
-mod utils;
+const PING_INTERVAL: Duration = Duration::from_secs(1);
 
 fn main() {
-    // env_logger::init();
-
     tracing_log::LogTracer::init().unwrap();
     let subscriber = tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    if let Err(e) = devices::init_net() {
-        error!("init net failed: {:?}", e);
-        return;
+    if let Err(e) = run() {
+        error!("{:?}", e);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let mut config_path = None;
+    let mut control_path = PathBuf::from(CONTROL_SOCKET_PATH);
+    let mut backend = Default::default();
+    let mut ping = None;
+    let mut count = 4;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            config_path = args.next();
+        } else if arg == "--control" {
+            control_path = args.next().unwrap_or_default().into();
+        } else if arg == "--event-backend" {
+            backend = args.next().unwrap_or_default().parse()?;
+        } else if arg == "--ping" {
+            ping = Some(Ipv4Address::try_from(
+                args.next().unwrap_or_default().as_str(),
+            )?);
+        } else if arg == "--count" {
+            count = args.next().unwrap_or_default().parse()?;
+        }
+    }
+    let config = match config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::parse(DEFAULT_CONFIG)?,
+    };
+
+    let stack = StackBuilder::from_config(&config)
+        .event_backend(backend)
+        .build()?;
+    // Registered before the stack starts, so that no termination signal goes unnoticed.
+    let mut signals = Signals::new(TERM_SIGNALS)?;
+    stack.start()?;
+    if let Some(dst) = ping {
+        let result = run_ping(&stack, dst, count, &mut signals);
+        stack.stop()?;
+        return result;
+    }
+    // The stack runs without a control socket rather than not at all.
+    if let Err(e) = stack.serve_control(&control_path) {
+        error!("serve control socket failed: {:?}", e);
     }
 
-    let (tx, rx) = mpsc::channel();
-    let barrier = Arc::new(Barrier::new(2));
-    let mut app = App::new();
-    let app_join = app.run(rx, barrier.clone());
-
-    let mut signals = vec![
-        INTR_IRQ_NULL,
-        INTR_IRQ_LOOPBACK,
-        INTR_IRQ_ETHERNET_TAP,
-        INTR_IRQ_L3,
-    ];
-    signals.extend(TERM_SIGNALS);
-    debug!("signals: {:?}", signals);
-    let mut signals = Signals::new(signals).unwrap();
-    let handle = signals.handle();
-    // Without waiting for the barrier, a signal may be sent before the app is ready to handle it.
-    barrier.wait();
-    for signal in signals.forever() {
-        match signal {
-            INTR_IRQ_NULL | INTR_IRQ_LOOPBACK | INTR_IRQ_ETHERNET_TAP => app.handle_irq_l2(signal),
-            INTR_IRQ_L3 => app.handle_irq_l3(),
-            signal if TERM_SIGNALS.contains(&signal) => {
-                info!("terminating app");
-                break;
+    info!("running app");
+    let socket = Arc::new(UdpSocket::open(&stack)?);
+    socket.bind(Endpoint::new(&[0, 0, 0, 0], 8000))?;
+    let echo = std::thread::spawn({
+        let socket = socket.clone();
+        move || echo(&socket)
+    });
+    signals.forever().next();
+    info!("terminating app");
+    // Wakes the echo thread up from its receive.
+    socket.close()?;
+    let _ = echo.join();
+    stack.stop()
+}
+
+/// Sends every datagram back to where it came from, until the socket is closed.
+fn echo(socket: &UdpSocket) {
+    let mut buf = [0; 65535];
+    loop {
+        let (len, foreign) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                info!("echo stopped: {}", e);
+                return;
             }
-            _ => {}
+        };
+        if let Err(e) = socket.send_to(&buf[..len], foreign) {
+            error!("echo failed: {:?}", e);
         }
     }
+}
 
-    tx.send(()).unwrap();
-    app_join.join().unwrap();
-    handle.close();
-    app.stop();
+/// Pings `dst` `count` times as ping(8) does, or until a termination signal.
+fn run_ping(
+    stack: &Stack,
+    dst: Ipv4Address,
+    count: usize,
+    signals: &mut Signals,
+) -> anyhow::Result<()> {
+    let mut pinger = Pinger::open(stack, dst)?;
+    println!("PING {}", dst);
+    for i in 0..count {
+        let start = Instant::now();
+        match pinger.ping(PING_INTERVAL)? {
+            (sequence, Some(rtt)) => println!(
+                "from {}: icmp_seq={} time={:.3} ms",
+                dst,
+                sequence,
+                rtt.as_secs_f64() * 1000.0
+            ),
+            (sequence, None) => println!("from {}: icmp_seq={} timeout", dst, sequence),
+        }
+        if signals.pending().next().is_some() {
+            break;
+        }
+        if i + 1 < count {
+            std::thread::sleep(PING_INTERVAL.saturating_sub(start.elapsed()));
+        }
+    }
+    println!("--- {} ping statistics ---", dst);
+    println!("{}", pinger.statistics());
+    pinger.close()
 }
@@ -4,10 +4,10 @@ use std::{
 };
 
 use arp::ArpCache;
-use ipv4::{Ipv4IdGenerator, Ipv4Interface, Ipv4Router};
+use ipv4::{reassembly::Reassembly, Ipv4IdGenerator, Ipv4Interface, Ipv4Router};
 use log::debug;
 
-use crate::transport::ContextBlocks;
+use crate::{timer::Timers, transport::ContextBlocks};
 
 pub mod arp;
 pub mod ipv4;
@@ -102,6 +102,10 @@ pub struct ProtocolStackContext {
     pub arp_cache: ArpCache,
     pub router: Ipv4Router,
     pub id_manager: Ipv4IdGenerator,
+    pub reassembly: Reassembly,
+    pub timers: Timers,
+    /// Whether packets addressed to other hosts are forwarded rather than dropped.
+    pub forwarding: bool,
 }
 
 impl ProtocolStackContext {
@@ -110,6 +114,9 @@ impl ProtocolStackContext {
             arp_cache: ArpCache::new(),
             router: Ipv4Router::new(),
             id_manager: Ipv4IdGenerator::new(),
+            reassembly: Reassembly::new(),
+            timers: Timers::new(),
+            forwarding: false,
         }
     }
 }
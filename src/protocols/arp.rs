@@ -1,4 +1,8 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use log::debug;
 
@@ -11,6 +15,10 @@ use crate::{
         ipv4::{Ipv4Address, Ipv4Interface},
         NetProtocolType,
     },
+    transport::{
+        icmp::{self, UnreachableCode},
+        ContextBlocks,
+    },
 };
 
 use super::ProtocolStackContext;
@@ -19,6 +27,13 @@ const ARP_HARDWARE_TYPE_ETHERNET: u16 = 1;
 const ARP_OPERATION_REQUEST: u16 = 1;
 const ARP_OPERATION_REPLY: u16 = 2;
 const ARP_CACHE_TIMEOUT: Duration = Duration::from_secs(600);
+pub const ARP_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+// Requests sent before giving up on a neighbour, one per interval (same as Linux's defaults)
+const ARP_REQUEST_MAX: usize = 3;
+const ARP_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+// Bytes of the packets waiting for a neighbour to be resolved, the oldest dropped beyond
+// (same as Linux's unres_qlen_bytes), room for the fragments of a few datagrams of any length
+const ARP_PENDING_MAX_BYTES: usize = 212_992;
 
 #[derive(Clone, Debug)]
 struct ArpHeader {
@@ -105,10 +120,16 @@ pub enum ArpCacheState {
     Resolved(MacAddress),
 }
 
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug)]
 struct ArpCacheEntry {
     state: ArpCacheState,
-    timestamp: std::time::Instant,
+    // When the entry was resolved, or when the last request was sent while incomplete
+    timestamp: Instant,
+    // Interface the requests go out of while incomplete
+    interface: Option<Arc<Ipv4Interface>>,
+    requests: usize,
+    // IPv4 packets to send once resolved, oldest first
+    pending: VecDeque<Vec<u8>>,
 }
 
 #[derive(Clone, Debug)]
@@ -126,11 +147,45 @@ impl ArpCache {
     pub fn insert(&mut self, ip_addr: Ipv4Address, state: ArpCacheState) {
         let entry = ArpCacheEntry {
             state,
-            timestamp: std::time::Instant::now(),
+            timestamp: Instant::now(),
+            interface: None,
+            requests: 0,
+            pending: VecDeque::new(),
         };
         self.entries.insert(ip_addr, entry);
     }
 
+    /// Queues `packet` until `ip_addr`, being resolved, is. Returns false when it is not.
+    pub fn enqueue(&mut self, ip_addr: Ipv4Address, packet: Vec<u8>) -> bool {
+        let Some(entry) = self.entries.get_mut(&ip_addr) else {
+            return false;
+        };
+        if entry.state != ArpCacheState::Incomplete {
+            return false;
+        }
+        let mut bytes = entry.pending.iter().map(Vec::len).sum::<usize>();
+        while bytes + packet.len() > ARP_PENDING_MAX_BYTES {
+            let Some(dropped) = entry.pending.pop_front() else {
+                break;
+            };
+            debug!("arp pending queue full, dst: {}", ip_addr);
+            bytes -= dropped.len();
+        }
+        entry.pending.push_back(packet);
+        true
+    }
+
+    /// Records `hw_addr` for `ip_addr`, returns the packets which were waiting for it.
+    fn resolve(&mut self, ip_addr: Ipv4Address, hw_addr: MacAddress) -> VecDeque<Vec<u8>> {
+        let pending = self
+            .entries
+            .remove(&ip_addr)
+            .map(|entry| entry.pending)
+            .unwrap_or_default();
+        self.insert(ip_addr, ArpCacheState::Resolved(hw_addr));
+        pending
+    }
+
     pub fn get(&self, ip_addr: &Ipv4Address) -> Option<ArpCacheState> {
         if let Some(entry) = self.entries.get(ip_addr) {
             if let ArpCacheState::Resolved(_) = &entry.state {
@@ -141,6 +196,80 @@ impl ArpCache {
         }
         None
     }
+
+    /// Every entry, including the incomplete and expired ones not removed yet.
+    pub fn entries(&self) -> impl Iterator<Item = (&Ipv4Address, &ArpCacheState)> {
+        self.entries
+            .iter()
+            .map(|(ip_addr, entry)| (ip_addr, &entry.state))
+    }
+
+    /// Removes the resolved entries, the incomplete ones keep the packets waiting on them.
+    pub fn flush(&mut self) {
+        self.entries
+            .retain(|_, entry| entry.state == ArpCacheState::Incomplete);
+    }
+
+    fn remove_expired(&mut self) {
+        self.entries.retain(|ip_addr, entry| {
+            let alive = entry.state == ArpCacheState::Incomplete
+                || entry.timestamp.elapsed() < ARP_CACHE_TIMEOUT;
+            if !alive {
+                debug!("arp cache entry expired: {}", ip_addr);
+            }
+            alive
+        });
+    }
+
+    /// Requests again the neighbours which did not answer within an interval, and gives up on the
+    /// ones which were requested too many times. Returns the packets which were waiting for those.
+    fn retry(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut failed = vec![];
+        self.entries.retain(|ip_addr, entry| {
+            if entry.state != ArpCacheState::Incomplete
+                || now.duration_since(entry.timestamp) < ARP_REQUEST_INTERVAL
+            {
+                return true;
+            }
+            let device = entry
+                .interface
+                .as_ref()
+                .and_then(|interface| Some((interface, interface.device.as_ref()?.upgrade()?)));
+            match device {
+                Some((interface, device)) if entry.requests < ARP_REQUEST_MAX => {
+                    if let Err(err) = request(&mut device.lock().unwrap(), interface, *ip_addr) {
+                        debug!("arp request failed: {:?}", err);
+                    }
+                    entry.requests += 1;
+                    entry.timestamp = now;
+                    true
+                }
+                _ => {
+                    debug!(
+                        "arp resolution failed, dst: {}, pending: {}",
+                        ip_addr,
+                        entry.pending.len()
+                    );
+                    failed.extend(entry.pending.drain(..));
+                    false
+                }
+            }
+        });
+        failed
+    }
+}
+
+pub fn handle_timer(
+    context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
+) -> anyhow::Result<()> {
+    context.arp_cache.remove_expired();
+    for packet in context.arp_cache.retry(Instant::now()) {
+        if let Err(err) = icmp::report_unreachable(context, pcbs, UnreachableCode::Host, &packet) {
+            debug!("host unreachable not sent: {:?}", err);
+        }
+    }
+    Ok(())
 }
 
 #[tracing::instrument(skip(device, interface))]
@@ -192,7 +321,7 @@ fn send(
         header,
         sha: device.hw_addr[0..MAC_ADDRESS_LEN].into(),
         spa: interface.unicast,
-        tha: target_hw_addr.clone(),
+        tha: target_hw_addr,
         tpa: target,
     };
     debug!("arp send: {:?}", messeage,);
@@ -230,26 +359,41 @@ pub fn recv(
     );
 
     let Some(device) = interface.device.as_ref() else {
-        anyhow::bail!(
-            "device not found, interface: {}",
-            interface.unicast.to_string()
-        );
+        anyhow::bail!("device not found, interface: {}", interface.unicast);
     };
     if interface.unicast == arp.tpa {
-        context
-            .arp_cache
-            .insert(arp.spa, ArpCacheState::Resolved(arp.sha.clone()));
+        let pending = context.arp_cache.resolve(arp.spa, arp.sha);
         let device = device.upgrade().unwrap();
         let mut device = device.lock().unwrap();
-        reply(&mut device, interface, arp.sha, arp.spa)?;
+        // Replying to a reply would bounce between two hosts forever.
+        // The packets taken from the cache are sent whatever fails before them.
+        if arp.header.oper == ARP_OPERATION_REQUEST {
+            if let Err(err) = reply(&mut device, interface, arp.sha, arp.spa) {
+                debug!("arp reply failed: {:?}", err);
+            }
+        }
+        if !pending.is_empty() {
+            debug!(
+                "arp pending packets sent, dst: {}, count: {}",
+                arp.spa,
+                pending.len()
+            );
+        }
+        for packet in pending {
+            if let Err(err) = device.send(&packet, NetProtocolType::Ipv4, arp.sha) {
+                debug!("arp pending packet not sent: {:?}", err);
+            }
+        }
     }
     Ok(())
 }
 
+/// The hardware address of `target`, or `Incomplete` while it is being requested, in which case
+/// packets to it can wait with `ArpCache::enqueue`.
 #[tracing::instrument(skip(device, interface, arp_cache))]
 pub fn resolve_arp(
     device: &mut NetDevice,
-    interface: &Ipv4Interface,
+    interface: &Arc<Ipv4Interface>,
     arp_cache: &mut ArpCache,
     target: Ipv4Address,
 ) -> anyhow::Result<ArpCacheState> {
@@ -257,14 +401,183 @@ pub fn resolve_arp(
         anyhow::bail!("device type not supported: {:?}", device.ty);
     }
 
-    let Some(state) = arp_cache.get(&target) else {
-        arp_cache.insert(target, ArpCacheState::Incomplete);
+    if let Some(state) = arp_cache.get(&target) {
+        debug!("arp resolved: {:?}", state);
+        return Ok(state);
+    }
+    let requested = arp_cache
+        .entries
+        .get(&target)
+        .is_some_and(|entry| entry.state == ArpCacheState::Incomplete);
+    if !requested {
+        arp_cache.entries.insert(
+            target,
+            ArpCacheEntry {
+                state: ArpCacheState::Incomplete,
+                timestamp: Instant::now(),
+                interface: Some(interface.clone()),
+                requests: 1,
+                pending: VecDeque::new(),
+            },
+        );
         request(device, interface, target)?;
-        return Ok(ArpCacheState::Incomplete);
+    }
+    Ok(ArpCacheState::Incomplete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::{
+            ethernet::ETHERNET_HEADER_SIZE,
+            link::testing::{connect, echo, pair, pending, settle, Host},
+        },
+        transport::{icmp::IcmpType, tcp, Endpoint},
     };
-    if state == ArpCacheState::Incomplete {
-        request(device, interface, target)?;
+
+    #[test]
+    fn test_packet_waits_for_resolution() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (mut host_a, mut host_b) = (Host::new(), Host::new());
+        let (_, device_b) = connect(&mut host_a, a, &mut host_b, b, 24);
+
+        echo(&host_a, a, b);
+        // B answers the request, then A sends the echo it queued.
+        while host_b.poll() {}
+        assert!(pending(&device_b).is_empty());
+        while host_a.poll() {}
+        let frames = pending(&device_b);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][ETHERNET_HEADER_SIZE + 20], IcmpType::Echo as u8);
+    }
+
+    #[test]
+    fn test_failed_pending_packet_does_not_hold_others() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (mut host_a, mut host_b) = (Host::new(), Host::new());
+        let (_, device_b) = connect(&mut host_a, a, &mut host_b, b, 24);
+        {
+            let mut context = host_a.context.lock().unwrap();
+            let ip_addr = Ipv4Address::new(&b);
+            context.arp_cache.insert(ip_addr, ArpCacheState::Incomplete);
+            // Longer than the MTU of the link, its sending fails.
+            assert!(context.arp_cache.enqueue(ip_addr, vec![0; 2000]));
+        }
+        echo(&host_a, a, b);
+
+        // The request of B resolves it for A, which sends what it queued.
+        echo(&host_b, b, a);
+        while host_a.poll() {}
+        let frames = pending(&device_b);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1][ETHERNET_HEADER_SIZE + 20], IcmpType::Echo as u8);
+    }
+
+    #[test]
+    fn test_pending_queue_is_bounded() {
+        let mut arp_cache = ArpCache::new();
+        let ip_addr = Ipv4Address::new(&[192, 0, 2, 1]);
+        assert!(!arp_cache.enqueue(ip_addr, vec![0]));
+        arp_cache.insert(ip_addr, ArpCacheState::Incomplete);
+        // The fragments of a datagram of the largest length fit along with other packets.
+        for i in 0..46 {
+            assert!(arp_cache.enqueue(ip_addr, vec![i; 1500]));
+        }
+        let fits = ARP_PENDING_MAX_BYTES / 1500;
+        for i in 46..fits + 8 {
+            assert!(arp_cache.enqueue(ip_addr, vec![i as u8; 1500]));
+        }
+        let pending = arp_cache.resolve(ip_addr, MacAddress([2, 0, 192, 0, 2, 1]));
+        assert_eq!(pending.len(), fits);
+        assert_eq!(pending.front().map(|packet| packet[0]), Some(8));
+    }
+
+    #[test]
+    fn test_unresolved_next_hop_is_unreachable() {
+        let (a, r1, r2, c) = (
+            [192, 0, 2, 1],
+            [192, 0, 2, 254],
+            [198, 51, 100, 254],
+            [198, 51, 100, 1],
+        );
+        let (mut host_a, mut host_r, mut host_c) = (Host::new(), Host::new(), Host::new());
+        let (device_a, _) = connect(&mut host_a, a, &mut host_r, r1, 24);
+        let (_, device_c) = connect(&mut host_r, r2, &mut host_c, c, 24);
+        host_r.context.lock().unwrap().forwarding = true;
+        let interface = device_a.lock().unwrap().interfaces.front().unwrap().clone();
+        host_a
+            .context
+            .lock()
+            .unwrap()
+            .router
+            .register_default(interface, Ipv4Address::new(&r1));
+
+        // C never answers, being left unpolled.
+        echo(&host_a, a, c);
+        settle(&[&host_a, &host_r]);
+        let retry = || {
+            let mut context = host_r.context.lock().unwrap();
+            for entry in context.arp_cache.entries.values_mut() {
+                entry.timestamp -= ARP_REQUEST_INTERVAL;
+            }
+            let mut pcbs = host_r.pcbs.lock().unwrap();
+            handle_timer(&mut context, &mut pcbs).unwrap();
+        };
+        for _ in 1..ARP_REQUEST_MAX {
+            retry();
+        }
+        assert_eq!(pending(&device_c).len(), ARP_REQUEST_MAX);
+        assert!(pending(&device_a).is_empty());
+
+        retry();
+        let frames = pending(&device_a);
+        assert_eq!(frames.len(), 1);
+        let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+        assert_eq!(icmp[0], IcmpType::DestinationUnreachable as u8);
+        assert_eq!(icmp[1], UnreachableCode::Host as u8);
+        assert!(host_r
+            .context
+            .lock()
+            .unwrap()
+            .arp_cache
+            .entries()
+            .all(|(ip_addr, _)| *ip_addr != Ipv4Address::new(&c)));
+    }
+
+    #[test]
+    fn test_unresolved_neighbour_fails_local_sender() {
+        let (a, b, absent) = ([192, 0, 2, 1], [192, 0, 2, 2], [192, 0, 2, 9]);
+        let (host_a, host_b) = pair(a, b);
+
+        std::thread::scope(|s| {
+            let client = s.spawn(|| {
+                tcp::connect(
+                    &host_a.context,
+                    &host_a.pcbs,
+                    Endpoint::new(&[0, 0, 0, 0], 0),
+                    Endpoint::new(&absent, 8000),
+                )
+            });
+            // The requests go unanswered until the SYN waiting on them is given up on.
+            while !client.is_finished() {
+                let mut context = host_a.context.lock().unwrap();
+                for entry in context.arp_cache.entries.values_mut() {
+                    entry.timestamp -= ARP_REQUEST_INTERVAL;
+                }
+                let mut pcbs = host_a.pcbs.lock().unwrap();
+                handle_timer(&mut context, &mut pcbs).unwrap();
+                drop((context, pcbs));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            let err = client.join().unwrap().unwrap_err();
+            assert!(err.to_string().starts_with("no route to host"), "{}", err);
+        });
+        // The error was not sent on the link to the stack itself.
+        let frames = pending(&host_b.device());
+        assert_eq!(frames.len(), ARP_REQUEST_MAX);
+        assert!(frames
+            .iter()
+            .all(|frame| frame[12..14] == (NetProtocolType::Arp as u16).to_be_bytes()));
     }
-    debug!("arp resolved: {:?}", state);
-    Ok(state)
 }
@@ -1,6 +1,6 @@
 use std::{
-    collections::LinkedList,
     sync::{Arc, Mutex, Weak},
+    time::Instant,
 };
 
 use log::debug;
@@ -8,16 +8,35 @@ use log::debug;
 use crate::{
     devices::{ethernet::MAC_ADDRESS_BROADCAST, NetDevice, NET_DEVICE_FLAG_NEED_ARP},
     protocols::arp::{resolve_arp, ArpCacheState},
-    transport::{icmp, udp, ContextBlocks, TransportProtocolNumber},
+    transport::{
+        icmp::{self, TimeExceededCode, Unreachable, UnreachableCode},
+        tcp, udp, ContextBlocks, TransportProtocolNumber,
+    },
 };
 
 use super::{NetInterfaceFamily, NetProtocolType, ProtocolStackContext};
+use trie::RouteTrie;
+
+pub mod reassembly;
+mod trie;
 
 const IPV4_HEADER_MIN_LENGTH: u8 = 20;
 const IPV4_HEADER_MAX_LENGTH: u8 = 60;
 const IPV4_MAX_LENGTH: usize = u16::MAX as usize;
 pub const IPV4_PAYLOAD_MAX_LENGTH: usize = IPV4_MAX_LENGTH - IPV4_HEADER_MIN_LENGTH as usize;
 const IPV4_VERSION: u8 = 4;
+const IPV4_FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+// Fragment offsets count blocks of 8 bytes
+const IPV4_FRAGMENT_UNIT: usize = 8;
+// Option types other than these are followed by their length
+const IPV4_OPTION_END: u8 = 0;
+const IPV4_OPTION_NOP: u8 = 1;
+// Set in the type of the options every fragment carries
+const IPV4_OPTION_COPIED: u8 = 0x80;
+// Every link must carry a 60-byte header and a fragment of 8 bytes (RFC 791)
+pub const IPV4_MTU_MIN: usize = 68;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Ipv4Address(pub u32);
@@ -34,9 +53,14 @@ impl Ipv4Address {
         ]))
     }
 
-    pub fn to_bytes(&self) -> [u8; 4] {
+    pub fn to_bytes(self) -> [u8; 4] {
         self.0.to_be_bytes()
     }
+
+    /// Whether the address is of a group, in 224.0.0.0/4.
+    pub fn is_multicast(self) -> bool {
+        self.0 & 0xf000_0000 == 0xe000_0000
+    }
 }
 
 impl std::ops::BitAnd for Ipv4Address {
@@ -102,7 +126,8 @@ pub struct Ipv4Header {
     pub identification: u16,
     flags_fragment_offset: u16,
     pub ttl: u8,
-    pub protocol: TransportProtocolNumber,
+    // Left raw, so that a packet of an unknown protocol can be answered.
+    pub protocol: u8,
     pub header_checksum: u16,
     pub src: Ipv4Address,
     pub dst: Ipv4Address,
@@ -117,12 +142,17 @@ impl Ipv4Header {
         (self.version_header_length & 0x0f) * 4
     }
 
-    pub fn flags(&self) -> u8 {
-        (self.flags_fragment_offset >> 13) as u8
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment_offset & IPV4_FLAG_MORE_FRAGMENTS != 0
     }
 
-    pub fn fragment_offset(&self) -> u16 {
-        self.flags_fragment_offset & 0x1fff
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_fragment_offset & IPV4_FLAG_DONT_FRAGMENT != 0
+    }
+
+    /// Offset of the fragment in the original payload, in bytes.
+    pub fn fragment_offset(&self) -> usize {
+        (self.flags_fragment_offset & IPV4_FRAGMENT_OFFSET_MASK) as usize * IPV4_FRAGMENT_UNIT
     }
 
     pub fn validate(&self) -> anyhow::Result<()> {
@@ -142,9 +172,6 @@ impl Ipv4Header {
             self.header_length()
         );
         // TODO: check total_length is match the actual length
-        if self.flags() & 0x1 > 0 || self.fragment_offset() & 0x1fff > 0 {
-            anyhow::bail!("fragmentation is not supported");
-        }
         self.validate_checksum()?;
         Ok(())
     }
@@ -167,7 +194,7 @@ impl Ipv4Header {
             (self.flags_fragment_offset >> 8) as u8,
             self.flags_fragment_offset as u8,
             self.ttl,
-            self.protocol as u8,
+            self.protocol,
             (self.header_checksum >> 8) as u8,
             self.header_checksum as u8,
             (self.src.0 >> 24) as u8,
@@ -186,7 +213,11 @@ impl TryFrom<&[u8]> for Ipv4Header {
     type Error = anyhow::Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let protocol = TransportProtocolNumber::try_from(value[9])?;
+        anyhow::ensure!(
+            value.len() >= IPV4_HEADER_MIN_LENGTH as usize,
+            "ipv4 packet too short: {}",
+            value.len()
+        );
         Ok(Ipv4Header {
             version_header_length: value[0],
             tos: value[1],
@@ -194,7 +225,7 @@ impl TryFrom<&[u8]> for Ipv4Header {
             identification: u16::from_be_bytes([value[4], value[5]]),
             flags_fragment_offset: u16::from_be_bytes([value[6], value[7]]),
             ttl: value[8],
-            protocol,
+            protocol: value[9],
             header_checksum: u16::from_be_bytes([value[10], value[11]]),
             src: Ipv4Address(u32::from_be_bytes([
                 value[12], value[13], value[14], value[15],
@@ -217,7 +248,7 @@ pub struct Ipv4Interface {
 
 impl Ipv4Interface {
     pub fn new(unicast: Ipv4Address, netmask: Ipv4Address, device: Arc<Mutex<NetDevice>>) -> Self {
-        let broadcast = Ipv4Address(unicast.0 & 0xffffff00 | !netmask.0 & 0xff);
+        let broadcast = Ipv4Address(unicast.0 & netmask.0 | !netmask.0);
         Ipv4Interface {
             family: NetInterfaceFamily::Ipv4,
             unicast,
@@ -228,55 +259,129 @@ impl Ipv4Interface {
     }
 }
 
-#[derive(Clone, Debug)]
+/// The route table, looked up by longest prefix, then lowest metric.
+#[derive(Clone, Debug, Default)]
 pub struct Ipv4Router {
-    interfaces: LinkedList<IpRoute>,
+    routes: RouteTrie,
 }
 
 impl Ipv4Router {
     pub fn new() -> Self {
         Ipv4Router {
-            interfaces: LinkedList::new(),
+            routes: RouteTrie::new(),
         }
     }
 
+    /// Routes the network of `interface`, which is directly connected.
     pub fn register(&mut self, network: Ipv4Address, interface: Arc<Ipv4Interface>) {
-        self.interfaces.push_back(IpRoute {
-            network,
+        self.routes.insert(IpRoute {
+            network: network & interface.netmask,
             netmask: interface.netmask,
             interface,
             next_hop: None,
+            metric: 0,
         });
     }
 
     pub fn register_default(&mut self, interface: Arc<Ipv4Interface>, gateway: Ipv4Address) {
-        self.interfaces.push_front(IpRoute {
+        self.routes.insert(IpRoute {
             network: Ipv4Address::ANY,
             netmask: Ipv4Address::ANY,
             interface,
             next_hop: Some(gateway),
+            metric: 0,
         });
     }
 
-    fn lookup(&self, dst: Ipv4Address) -> Option<IpRoute> {
-        let mut candidate: Option<&IpRoute> = None;
-        for route in self.interfaces.iter() {
-            if dst & route.netmask == route.network
-                && (candidate.is_none() || route.netmask.0 > candidate.as_ref().unwrap().netmask.0)
-            {
-                candidate = Some(route);
-            }
-        }
-        candidate.cloned()
+    /// Routes `network`/`netmask` through `interface`, via `next_hop` unless directly connected.
+    /// Fails when there is a route to the same network with the same metric already.
+    pub fn register_route(
+        &mut self,
+        network: Ipv4Address,
+        netmask: Ipv4Address,
+        interface: Arc<Ipv4Interface>,
+        next_hop: Option<Ipv4Address>,
+        metric: u32,
+    ) -> anyhow::Result<()> {
+        let network = network & netmask;
+        anyhow::ensure!(
+            self.routes
+                .get(network, netmask)
+                .iter()
+                .all(|route| route.metric != metric),
+            "route already exists, network: {}, netmask: {}, metric: {}",
+            network,
+            netmask,
+            metric
+        );
+        self.routes.insert(IpRoute {
+            network,
+            netmask,
+            interface,
+            next_hop,
+            metric,
+        });
+        Ok(())
+    }
+
+    /// Same as `register_route`, but takes the place of the route with the same metric if any.
+    pub fn replace_route(
+        &mut self,
+        network: Ipv4Address,
+        netmask: Ipv4Address,
+        interface: Arc<Ipv4Interface>,
+        next_hop: Option<Ipv4Address>,
+        metric: u32,
+    ) {
+        let network = network & netmask;
+        self.routes.remove(network, netmask, Some(metric));
+        self.routes.insert(IpRoute {
+            network,
+            netmask,
+            interface,
+            next_hop,
+            metric,
+        });
+    }
+
+    /// Removes the route to `network`/`netmask` with `metric`, or the one in use when `None`.
+    /// Returns false when there is none.
+    pub fn unregister_route(
+        &mut self,
+        network: Ipv4Address,
+        netmask: Ipv4Address,
+        metric: Option<u32>,
+    ) -> bool {
+        self.routes
+            .remove(network & netmask, netmask, metric)
+            .is_some()
+    }
+
+    /// Removes every route going out of `interface`.
+    pub fn unregister_interface(&mut self, interface: &Arc<Ipv4Interface>) {
+        self.routes
+            .retain(|route| !Arc::ptr_eq(&route.interface, interface));
+    }
+
+    /// Every route, in the order they are looked up.
+    pub fn routes(&self) -> impl Iterator<Item = &IpRoute> {
+        self.routes.routes().into_iter()
+    }
+
+    /// The route to `dst` with the longest prefix, the lowest metric among those.
+    pub fn lookup(&self, dst: Ipv4Address) -> Option<IpRoute> {
+        self.routes.lookup(dst).cloned()
     }
 }
 
 #[derive(Clone, Debug)]
-struct IpRoute {
-    network: Ipv4Address,
-    netmask: Ipv4Address,
-    interface: Arc<Ipv4Interface>,
-    next_hop: Option<Ipv4Address>,
+pub struct IpRoute {
+    pub network: Ipv4Address,
+    pub netmask: Ipv4Address,
+    pub interface: Arc<Ipv4Interface>,
+    pub next_hop: Option<Ipv4Address>,
+    /// Preference among routes with the same prefix length, the lowest being used.
+    pub metric: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -296,6 +401,20 @@ impl Ipv4IdGenerator {
     }
 }
 
+/// Largest payload a packet to `dst` can carry, limited by the MTU of the outgoing device.
+pub fn max_payload_length(context: &ProtocolStackContext, dst: Ipv4Address) -> Option<usize> {
+    let route = context.router.lookup(dst)?;
+    Some(route_mtu(&route)? - IPV4_HEADER_MIN_LENGTH as usize)
+}
+
+/// Largest packet the outgoing device of `route` can carry.
+fn route_mtu(route: &IpRoute) -> Option<usize> {
+    let device = route.interface.device.as_ref()?.upgrade()?;
+    let mtu = device.lock().unwrap().mtu;
+    Some(mtu.min(IPV4_MAX_LENGTH))
+}
+
+/// Sends `data` to `dst`, split into fragments when it does not fit in the MTU unless `dont_fragment`.
 #[tracing::instrument(skip(context, protocol, data))]
 pub fn send(
     context: &mut ProtocolStackContext,
@@ -303,20 +422,15 @@ pub fn send(
     data: &[u8],
     src: Ipv4Address,
     dst: Ipv4Address,
+    dont_fragment: bool,
 ) -> anyhow::Result<()> {
     let Some(route) = context.router.lookup(dst) else {
-        anyhow::bail!("no route found, dst: {}", dst.to_string());
-    };
-    let interface = route.interface;
-    let Some(device) = interface.device.as_ref() else {
-        anyhow::bail!(
-            "device not found, interface: {}",
-            interface.unicast.to_string()
-        );
+        return Err(icmp::unreachable(
+            UnreachableCode::Net,
+            format!("no route found, dst: {}", dst),
+        ));
     };
-    let device = device.upgrade().unwrap();
-    let mut device = device.lock().unwrap();
-
+    let interface = &route.interface;
     anyhow::ensure!(
         src != Ipv4Address::ANY || dst != Ipv4Address::BROADCAST,
         "source address is required for broadcast packet"
@@ -328,16 +442,54 @@ pub fn send(
         interface.unicast.to_string()
     );
     anyhow::ensure!(
-        data.len() < device.mtu,
-        "packet too long, len: {}, mtu: {}",
-        data.len(),
-        device.mtu
+        data.len() <= IPV4_PAYLOAD_MAX_LENGTH,
+        "packet too long, len: {}",
+        data.len()
     );
 
+    let flags = if dont_fragment {
+        IPV4_FLAG_DONT_FRAGMENT
+    } else {
+        0
+    };
     let id = context.id_manager.next();
-    let mut output_data = create_ip_header(id, protocol, interface.unicast, dst, data);
-    output_data.extend(data);
+    let mut packet = create_ip_header(id, flags, protocol, interface.unicast, dst, data);
+    packet.extend_from_slice(data);
+    transmit(context, &route, &packet)
+}
+
+/// Transmits `packet` out of the interface of `route`, split into fragments when it does not
+/// fit in the MTU. A packet with DF set fails with `Unreachable(FragmentationNeeded)` instead.
+fn transmit(
+    context: &mut ProtocolStackContext,
+    route: &IpRoute,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    let interface = &route.interface;
+    let Some(device) = interface.device.as_ref() else {
+        anyhow::bail!("device not found, interface: {}", interface.unicast);
+    };
+    let device = device.upgrade().unwrap();
+    let mut device = device.lock().unwrap();
+
+    let header = Ipv4Header::try_from(packet)?;
+    let mtu = device.mtu.min(IPV4_MAX_LENGTH);
+    let fragments = if packet.len() <= mtu {
+        vec![packet.to_vec()]
+    } else if header.dont_fragment() {
+        return Err(icmp::unreachable(
+            UnreachableCode::FragmentationNeeded,
+            format!(
+                "packet too long to be sent without fragmentation, len: {}, mtu: {}",
+                packet.len(),
+                device.mtu
+            ),
+        ));
+    } else {
+        fragment(&header, packet, mtu)?
+    };
 
+    let dst = header.dst;
     let dst_hw_address = if device.flags & NET_DEVICE_FLAG_NEED_ARP != 0 {
         // Handle broadcast address
         if dst == interface.broadcast || dst == Ipv4Address::BROADCAST {
@@ -350,9 +502,12 @@ pub fn send(
                 dst
             };
             let ArpCacheState::Resolved(hw_address) =
-                resolve_arp(&mut device, &interface, &mut context.arp_cache, next_hop)?
+                resolve_arp(&mut device, interface, &mut context.arp_cache, next_hop)?
             else {
-                debug!("no arp cache hit, dst: {}", next_hop.to_string());
+                debug!("arp resolution pending, dst: {}", next_hop);
+                for fragment in fragments {
+                    context.arp_cache.enqueue(next_hop, fragment);
+                }
                 return Ok(());
             };
             hw_address
@@ -360,11 +515,96 @@ pub fn send(
     } else {
         MAC_ADDRESS_BROADCAST
     };
-    device.send(&output_data, NetProtocolType::Ipv4, dst_hw_address)
+
+    for fragment in fragments.iter() {
+        device.send(fragment, NetProtocolType::Ipv4, dst_hw_address)?;
+    }
+    if fragments.len() > 1 {
+        debug!(
+            "ipv4 packet fragmented, id: {}, len: {}, fragments: {}",
+            header.identification,
+            packet.len(),
+            fragments.len()
+        );
+    }
+    Ok(())
+}
+
+/// Splits `packet` into fragments of at most `mtu` bytes, which may be fragments themselves
+/// when `packet` is a fragment. The fragments after the first carry the copied options only.
+fn fragment(header: &Ipv4Header, packet: &[u8], mtu: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+    let header_length = header.header_length() as usize;
+    let later_header = fragment_header(&packet[..header_length]);
+    let mut payload = &packet[header_length..];
+    let mut offset = header.fragment_offset();
+    let mut fragments = vec![];
+    while !payload.is_empty() {
+        let mut fragment = if fragments.is_empty() {
+            packet[..header_length].to_vec()
+        } else {
+            later_header.clone()
+        };
+        // Every fragment but the last carries a multiple of 8 bytes.
+        let chunk_length =
+            mtu.saturating_sub(fragment.len()) / IPV4_FRAGMENT_UNIT * IPV4_FRAGMENT_UNIT;
+        anyhow::ensure!(
+            chunk_length > 0,
+            "mtu too small to fragment, mtu: {}, header length: {}",
+            mtu,
+            fragment.len()
+        );
+        let (chunk, rest) = payload.split_at(chunk_length.min(payload.len()));
+        let mut flags_fragment_offset = (offset / IPV4_FRAGMENT_UNIT) as u16;
+        if !rest.is_empty() || header.more_fragments() {
+            flags_fragment_offset |= IPV4_FLAG_MORE_FRAGMENTS;
+        }
+        let total_length = (fragment.len() + chunk.len()) as u16;
+        fragment[2..4].copy_from_slice(&total_length.to_be_bytes());
+        fragment[6..8].copy_from_slice(&flags_fragment_offset.to_be_bytes());
+        update_checksum(&mut fragment);
+        fragment.extend_from_slice(chunk);
+        fragments.push(fragment);
+        offset += chunk.len();
+        payload = rest;
+    }
+    Ok(fragments)
+}
+
+/// The header of the fragments after the first, `header` without the options not to be copied
+/// into every fragment (RFC 791). A malformed option ends the ones kept.
+fn fragment_header(header: &[u8]) -> Vec<u8> {
+    let mut fragment = header[..IPV4_HEADER_MIN_LENGTH as usize].to_vec();
+    let mut options = &header[IPV4_HEADER_MIN_LENGTH as usize..];
+    while let Some(&ty) = options.first() {
+        let length = match ty {
+            IPV4_OPTION_END => break,
+            IPV4_OPTION_NOP => 1,
+            _ => match options.get(1) {
+                Some(&length) if length >= 2 && length as usize <= options.len() => length as usize,
+                _ => break,
+            },
+        };
+        if ty & IPV4_OPTION_COPIED != 0 {
+            fragment.extend_from_slice(&options[..length]);
+        }
+        options = &options[length..];
+    }
+    // Padded with the end of the options to a multiple of 4 bytes
+    fragment.resize(fragment.len().next_multiple_of(4), IPV4_OPTION_END);
+    fragment[0] = IPV4_VERSION << 4 | (fragment.len() / 4) as u8;
+    fragment
+}
+
+/// Recomputes the checksum of `header`, the header of a packet without its payload.
+fn update_checksum(header: &mut [u8]) {
+    header[10..12].fill(0);
+    let checksum = crate::utils::calculate_checksum(header, 0);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
 }
 
 fn create_ip_header(
     id: u16,
+    flags_fragment_offset: u16,
     protocol: TransportProtocolNumber,
     src: Ipv4Address,
     dst: Ipv4Address,
@@ -376,17 +616,15 @@ fn create_ip_header(
         tos: 0,
         total_length,
         identification: id,
-        flags_fragment_offset: 0,
+        flags_fragment_offset,
         ttl: 64,
-        protocol,
+        protocol: protocol as u8,
         header_checksum: 0,
         src,
         dst,
     };
     let mut bytes = header.to_bytes();
-    let checksum = crate::utils::calculate_checksum(&bytes, 0);
-    bytes[10] = (checksum >> 8) as u8;
-    bytes[11] = checksum as u8;
+    update_checksum(&mut bytes);
     bytes
 }
 
@@ -399,31 +637,158 @@ pub fn recv(
 ) -> anyhow::Result<()> {
     let header = Ipv4Header::try_from(data)?;
     header.validate()?;
-    if header.dst != interface.unicast
-        && header.dst != interface.broadcast
-        && header.dst != Ipv4Address::BROADCAST
+    // Ethernet pads short frames, anything past the total length is not part of the packet.
+    let total_length = header.total_length as usize;
+    anyhow::ensure!(
+        (header.header_length() as usize..=data.len()).contains(&total_length),
+        "invalid total length: {}, len: {}",
+        total_length,
+        data.len()
+    );
+    let packet = &data[..total_length];
+
+    let interface = if header.dst == interface.unicast
+        || header.dst == interface.broadcast
+        || header.dst == Ipv4Address::BROADCAST
     {
+        interface
+    } else if context.forwarding {
+        let route = context.router.lookup(header.dst);
+        match route {
+            // Addressed to another interface of the stack.
+            Some(route) if route.interface.unicast == header.dst => route.interface,
+            route => return forward(context, &header, packet, route),
+        }
+    } else {
         return Ok(());
-    }
+    };
     debug!(
         "ipv4 packet received, src:{}, dst: {}, interface: {:?}",
         header.src.to_string(),
         header.dst.to_string(),
         interface
     );
+    if header.more_fragments() || header.fragment_offset() > 0 {
+        let Some(packet) = context.reassembly.add(&header, packet, Instant::now())? else {
+            return Ok(());
+        };
+        return deliver(context, pcbs, &interface, &packet);
+    }
+    deliver(context, pcbs, &interface, packet)
+}
+
+/// Hands a whole packet addressed to `interface` over to its transport protocol.
+fn deliver(
+    context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
+    interface: &Ipv4Interface,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    let header = Ipv4Header::try_from(packet)?;
+    let payload = &packet[header.header_length() as usize..];
+    let result = match TransportProtocolNumber::try_from(header.protocol) {
+        Ok(TransportProtocolNumber::Icmp) => {
+            icmp::recv(context, pcbs, payload, header.src, header.dst)
+        }
+        Ok(TransportProtocolNumber::Tcp) => {
+            tcp::recv(context, pcbs, payload, header.src, header.dst)
+        }
+        Ok(TransportProtocolNumber::Udp) => udp::recv(pcbs, payload, header.src, header.dst),
+        Err(err) => Err(err.context(Unreachable(UnreachableCode::Protocol))),
+    };
+
+    let Err(err) = &result else {
+        return result;
+    };
+    // Only the packet itself is answered, not the failures of replies sent while handling it.
+    match err.downcast_ref::<Unreachable>() {
+        Some(&Unreachable(code @ (UnreachableCode::Protocol | UnreachableCode::Port)))
+            if header.dst == interface.unicast =>
+        {
+            debug!("{:#}", err);
+            icmp::send_unreachable(context, code, packet)
+        }
+        _ => result,
+    }
+}
+
+/// Sends `packet`, addressed to another host, on along `route` with its TTL decremented.
+/// The sender is told with ICMP when it can go no further.
+fn forward(
+    context: &mut ProtocolStackContext,
+    header: &Ipv4Header,
+    packet: &[u8],
+    route: Option<IpRoute>,
+) -> anyhow::Result<()> {
+    if header.src == Ipv4Address::ANY
+        || header.src == Ipv4Address::BROADCAST
+        || header.dst == Ipv4Address::ANY
+        || route
+            .as_ref()
+            .is_some_and(|route| header.dst == route.interface.broadcast)
+    {
+        debug!(
+            "ipv4 packet not forwarded, src: {}, dst: {}",
+            header.src, header.dst
+        );
+        return Ok(());
+    }
+    if header.ttl <= 1 {
+        debug!(
+            "ipv4 ttl exceeded, src: {}, dst: {}",
+            header.src, header.dst
+        );
+        return icmp::send_time_exceeded(context, TimeExceededCode::Ttl, packet);
+    }
+    let Some(route) = route else {
+        debug!("no route to forward, dst: {}", header.dst);
+        return icmp::send_unreachable(context, UnreachableCode::Net, packet);
+    };
 
-    let payload = &data[header.header_length() as usize..data.len()];
-    match header.protocol {
-        TransportProtocolNumber::Icmp => icmp::recv(context, payload, header.src, header.dst)?,
-        TransportProtocolNumber::Udp => udp::recv(pcbs, payload, header.src, header.dst)?,
+    let mut forwarded = packet.to_vec();
+    forwarded[8] -= 1;
+    update_checksum(&mut forwarded[..header.header_length() as usize]);
+    debug!(
+        "ipv4 packet forwarded, src: {}, dst: {}, interface: {}",
+        header.src, header.dst, route.interface.unicast
+    );
+    let result = transmit(context, &route, &forwarded);
+    match result
+        .as_ref()
+        .map_err(|err| err.downcast_ref::<Unreachable>())
+    {
+        Err(Some(&Unreachable(UnreachableCode::FragmentationNeeded))) => {
+            let mtu = route_mtu(&route).unwrap_or_default();
+            icmp::send_fragmentation_needed(context, mtu, packet)
+        }
+        _ => result,
     }
+}
 
+/// Gives up on the datagrams whose fragments stopped arriving, telling their senders.
+pub fn handle_reassembly_timer(
+    context: &mut ProtocolStackContext,
+    _pcbs: &mut ContextBlocks,
+) -> anyhow::Result<()> {
+    for quoted in context.reassembly.expire(Instant::now()) {
+        icmp::send_time_exceeded(context, TimeExceededCode::Reassembly, &quoted)?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+
+    use crate::{
+        devices::{
+            ethernet::{MacAddress, ETHERNET_HEADER_SIZE},
+            link::testing::{connect, echo, pair, pending, settle, Host},
+        },
+        driver::DriverType,
+        transport::Endpoint,
+    };
 
     #[test]
     fn test_ipv4_header() {
@@ -489,4 +854,351 @@ mod tests {
         let dst = Ipv4Address::from(&[8, 8, 8, 8]);
         assert_eq!(router.lookup(dst).unwrap().interface.unicast, gateway);
     }
+
+    #[test]
+    fn test_route_metrics() {
+        let mut router = Ipv4Router::new();
+        let interface = |unicast: &str| {
+            Arc::new(Ipv4Interface::new(
+                Ipv4Address::try_from(unicast).unwrap(),
+                Ipv4Address::try_from("255.255.255.0").unwrap(),
+                Arc::new(Mutex::new(NetDevice::null())),
+            ))
+        };
+        let (eth0, eth1) = (interface("192.0.2.1"), interface("198.51.100.1"));
+        router.register(eth0.unicast, eth0.clone());
+        router.register(eth1.unicast, eth1.clone());
+        let gateway = |address: &str| Some(Ipv4Address::try_from(address).unwrap());
+        let any = Ipv4Address::ANY;
+        router
+            .register_route(any, any, eth0.clone(), gateway("192.0.2.254"), 100)
+            .unwrap();
+        router
+            .register_route(any, any, eth1.clone(), gateway("198.51.100.254"), 50)
+            .unwrap();
+        assert!(router
+            .register_route(any, any, eth0.clone(), gateway("192.0.2.253"), 50)
+            .is_err());
+        let next_hop = |router: &Ipv4Router| {
+            router
+                .lookup(Ipv4Address::new(&[203, 0, 113, 1]))
+                .and_then(|route| route.next_hop)
+        };
+        assert_eq!(next_hop(&router), gateway("198.51.100.254"));
+
+        router.replace_route(any, any, eth0.clone(), gateway("192.0.2.253"), 50);
+        assert_eq!(next_hop(&router), gateway("192.0.2.253"));
+        let table = router
+            .routes()
+            .map(|route| (route.netmask.0.count_ones(), route.metric))
+            .collect::<Vec<_>>();
+        assert_eq!(table, [(24, 0), (24, 0), (0, 50), (0, 100)]);
+
+        assert!(!router.unregister_route(any, any, Some(7)));
+        assert!(router.unregister_route(any, any, None));
+        assert_eq!(next_hop(&router), gateway("192.0.2.254"));
+        assert!(router.unregister_route(any, any, Some(100)));
+        assert_eq!(next_hop(&router), None);
+    }
+
+    #[test]
+    fn test_unknown_protocol_is_unreachable() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let device_a = host_a.device();
+
+        // Protocol 253 is for experiments (RFC 3692).
+        let payload = [0; 12];
+        let (src, dst) = (Ipv4Address::new(&a), Ipv4Address::new(&b));
+        let mut packet = create_ip_header(0, 0, TransportProtocolNumber::Udp, src, dst, &payload);
+        packet[9] = 253;
+        packet[10..12].fill(0);
+        let checksum = crate::utils::calculate_checksum(&packet, 0);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(&payload);
+        device_a
+            .lock()
+            .unwrap()
+            .send(
+                &packet,
+                NetProtocolType::Ipv4,
+                MacAddress([0x02, 0x00, 192, 0, 2, 2]),
+            )
+            .unwrap();
+        while host_b.poll() {}
+
+        let frames = pending(&device_a);
+        assert_eq!(frames.len(), 1);
+        let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+        assert_eq!(icmp[0], icmp::IcmpType::DestinationUnreachable as u8);
+        assert_eq!(icmp[1], UnreachableCode::Protocol as u8);
+        assert_eq!(&icmp[8..8 + 28], &packet[..28]);
+    }
+
+    #[test]
+    fn test_oversize_packet_is_fragmented() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let device_b = host_b.device();
+
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let (src, dst) = (Ipv4Address::new(&a), Ipv4Address::new(&b));
+        let mut context = host_a.context.lock().unwrap();
+        send(
+            &mut context,
+            TransportProtocolNumber::Udp,
+            &data,
+            src,
+            dst,
+            false,
+        )
+        .unwrap();
+        assert!(send(
+            &mut context,
+            TransportProtocolNumber::Udp,
+            &data,
+            src,
+            dst,
+            true
+        )
+        .is_err());
+        drop(context);
+
+        // 1480 bytes fit in the 1500 bytes MTU along with the header, a multiple of 8 already.
+        let headers = pending(&device_b)
+            .iter()
+            .map(|frame| Ipv4Header::try_from(&frame[ETHERNET_HEADER_SIZE..]).unwrap())
+            .collect::<Vec<_>>();
+        let fragments = headers
+            .iter()
+            .map(|header| {
+                (
+                    header.fragment_offset(),
+                    header.total_length as usize - 20,
+                    header.more_fragments(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fragments,
+            [(0, 1480, true), (1480, 1480, true), (2960, 40, false)]
+        );
+        assert!(headers
+            .iter()
+            .all(|header| header.identification == headers[0].identification
+                && header.validate_checksum().is_ok()));
+    }
+
+    #[test]
+    fn test_later_fragments_carry_copied_options() {
+        let (src, dst) = (
+            Ipv4Address::new(&[192, 0, 2, 1]),
+            Ipv4Address::new(&[192, 0, 2, 2]),
+        );
+        let payload = (0..100).collect::<Vec<u8>>();
+        let mut packet = create_ip_header(0, 0, TransportProtocolNumber::Udp, src, dst, &payload);
+        // A no-operation, a record route which is not copied and a router alert which is.
+        let options = [1, 7, 7, 4, 0, 0, 0, 0, 0x94, 4, 0, 0];
+        packet.splice(20..20, options);
+        packet[0] = 0x48;
+        packet[2..4].copy_from_slice(&((32 + payload.len()) as u16).to_be_bytes());
+        update_checksum(&mut packet);
+        packet.extend_from_slice(&payload);
+        let header = Ipv4Header::try_from(packet.as_slice()).unwrap();
+
+        let fragments = fragment(&header, &packet, 72).unwrap();
+        let headers = fragments
+            .iter()
+            .map(|fragment| Ipv4Header::try_from(fragment.as_slice()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers
+                .iter()
+                .map(|header| (header.header_length(), header.fragment_offset()))
+                .collect::<Vec<_>>(),
+            [(32, 0), (24, 40), (24, 88)]
+        );
+        assert_eq!(fragments[0][20..32], options);
+        assert_eq!(fragments[1][20..24], [0x94, 4, 0, 0]);
+        // The checksums cover the options as well.
+        assert!(fragments.iter().zip(&headers).all(|(fragment, header)| {
+            crate::utils::calculate_checksum(&fragment[..header.header_length() as usize], 0) == 0
+        }));
+        let data = fragments
+            .iter()
+            .zip(&headers)
+            .flat_map(|(fragment, header)| &fragment[header.header_length() as usize..])
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(data, payload);
+
+        // No room for a single block of 8 bytes after the header.
+        assert!(fragment(&header, &packet, 39).is_err());
+    }
+
+    #[test]
+    fn test_fragments_are_reassembled() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let device_a = host_a.device();
+        let device_b = host_b.device();
+        let mut pcbs = host_b.pcbs.lock().unwrap();
+        let id = udp::open(&mut pcbs).unwrap();
+        udp::bind(&mut pcbs, id, &Endpoint::new(&b, 7)).unwrap();
+        drop(pcbs);
+
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let (src, dst) = (Endpoint::new(&a, 40000), Endpoint::new(&b, 7));
+        udp::output(&mut host_a.context.lock().unwrap(), &data, src, dst).unwrap();
+        settle(&[&host_a, &host_b]);
+        let mut buf = [0; 4000];
+        let (len, _) = udp::receive_from(&host_b.pcbs, id, &mut buf, None).unwrap();
+        assert_eq!(&buf[..len], &data);
+
+        // Without its last fragment, the datagram times out.
+        udp::output(&mut host_a.context.lock().unwrap(), &data, src, dst).unwrap();
+        if let Some(DriverType::Link { rx, .. }) = &device_b.lock().unwrap().driver {
+            rx.lock().unwrap().pop_back();
+        }
+        while host_b.poll() {}
+        let mut context = host_b.context.lock().unwrap();
+        let expired = context
+            .reassembly
+            .expire(Instant::now() + Duration::from_secs(60));
+        assert_eq!(expired.len(), 1);
+        icmp::send_time_exceeded(&mut context, TimeExceededCode::Reassembly, &expired[0]).unwrap();
+        drop(context);
+
+        let frames = pending(&device_a);
+        assert_eq!(frames.len(), 1);
+        let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+        assert_eq!(icmp[0], icmp::IcmpType::TimeExceeded as u8);
+        assert_eq!(icmp[1], TimeExceededCode::Reassembly as u8);
+        // The UDP header of the datagram follows its IP header.
+        assert_eq!(&icmp[8 + 20..8 + 24], &[0x9c, 0x40, 0x00, 0x07]);
+    }
+
+    /// Hosts `a` and `c` on two links joined by `r`, which forwards between them.
+    fn routed() -> ([Host; 3], [Arc<Mutex<NetDevice>>; 4]) {
+        let (a, r1, r2, c) = ROUTED;
+        let (mut host_a, mut host_r, mut host_c) = (Host::new(), Host::new(), Host::new());
+        let (device_a, device_r1) = connect(&mut host_a, a, &mut host_r, r1, 24);
+        let (device_r2, device_c) = connect(&mut host_r, r2, &mut host_c, c, 24);
+        host_r.context.lock().unwrap().forwarding = true;
+        for (host, device, gateway) in [(&host_a, &device_a, r1), (&host_c, &device_c, r2)] {
+            let interface = device.lock().unwrap().interfaces.front().unwrap().clone();
+            host.context
+                .lock()
+                .unwrap()
+                .router
+                .register_default(interface, Ipv4Address::new(&gateway));
+        }
+        // Resolves every hop, so that the tests see only their own packets.
+        echo(&host_a, a, c);
+        settle(&[&host_a, &host_r, &host_c]);
+        (
+            [host_a, host_r, host_c],
+            [device_a, device_r1, device_r2, device_c],
+        )
+    }
+
+    const ROUTED: ([u8; 4], [u8; 4], [u8; 4], [u8; 4]) = (
+        [192, 0, 2, 1],
+        [192, 0, 2, 254],
+        [198, 51, 100, 254],
+        [198, 51, 100, 1],
+    );
+
+    #[test]
+    fn test_forwarding_decrements_ttl() {
+        let (a, _, _, c) = ROUTED;
+        let ([host_a, host_r, host_c], [_, _, _, device_c]) = routed();
+        let mut pcbs = host_c.pcbs.lock().unwrap();
+        let id = udp::open(&mut pcbs).unwrap();
+        udp::bind(&mut pcbs, id, &Endpoint::new(&c, 7)).unwrap();
+        drop(pcbs);
+
+        let (src, dst) = (Endpoint::new(&a, 40000), Endpoint::new(&c, 7));
+        udp::output(&mut host_a.context.lock().unwrap(), b"hello", src, dst).unwrap();
+        settle(&[&host_a, &host_r]);
+        let frames = pending(&device_c);
+        assert_eq!(frames.len(), 1);
+        let header = Ipv4Header::try_from(&frames[0][ETHERNET_HEADER_SIZE..]).unwrap();
+        assert_eq!(header.ttl, 63);
+        assert!(header.validate().is_ok());
+
+        settle(&[&host_a, &host_r, &host_c]);
+        let mut buf = [0; 16];
+        let (len, from) = udp::receive_from(&host_c.pcbs, id, &mut buf, None).unwrap();
+        assert_eq!((&buf[..len], from), (&b"hello"[..], src));
+
+        // Without forwarding, the packet is dropped.
+        host_r.context.lock().unwrap().forwarding = false;
+        udp::output(&mut host_a.context.lock().unwrap(), b"hello", src, dst).unwrap();
+        settle(&[&host_a, &host_r]);
+        assert!(pending(&device_c).is_empty());
+    }
+
+    #[test]
+    fn test_forwarding_errors() {
+        let (a, _, _, c) = ROUTED;
+        let ([host_a, host_r, _], [device_a, device_r1, device_r2, device_c]) = routed();
+        let interface = device_r1
+            .lock()
+            .unwrap()
+            .interfaces
+            .front()
+            .unwrap()
+            .clone();
+        let receive = |dst: [u8; 4], ttl: u8, flags: u16, len: usize| {
+            let data = vec![0; len];
+            let mut packet = create_ip_header(
+                1,
+                flags,
+                TransportProtocolNumber::Udp,
+                Ipv4Address::new(&a),
+                Ipv4Address::new(&dst),
+                &data,
+            );
+            packet[8] = ttl;
+            update_checksum(&mut packet);
+            packet.extend_from_slice(&data);
+            let mut context = host_r.context.lock().unwrap();
+            let mut pcbs = host_r.pcbs.lock().unwrap();
+            recv(&mut context, &mut pcbs, interface.clone(), &packet).unwrap();
+        };
+        let error = || {
+            let frames = pending(&device_a);
+            assert_eq!(frames.len(), 1);
+            host_a.poll();
+            let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+            (icmp[0], icmp[1], u16::from_be_bytes([icmp[6], icmp[7]]))
+        };
+
+        receive(c, 1, 0, 8);
+        assert_eq!(error(), (11, TimeExceededCode::Ttl as u8, 0));
+        receive([203, 0, 113, 1], 64, 0, 8);
+        assert_eq!(error(), (3, UnreachableCode::Net as u8, 0));
+
+        // Too long for the next link, split unless DF is set.
+        device_r2.lock().unwrap().mtu = 576;
+        receive(c, 64, IPV4_FLAG_DONT_FRAGMENT, 1000);
+        assert_eq!(
+            error(),
+            (3, UnreachableCode::FragmentationNeeded as u8, 576)
+        );
+        receive(c, 64, 0, 1000);
+        let fragments = pending(&device_c)
+            .iter()
+            .map(|frame| Ipv4Header::try_from(&frame[ETHERNET_HEADER_SIZE..]).unwrap())
+            .map(|header| {
+                (
+                    header.fragment_offset(),
+                    header.more_fragments(),
+                    header.ttl,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fragments, [(0, true, 63), (552, false, 63)]);
+    }
 }
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{
+    update_checksum, Ipv4Address, Ipv4Header, IPV4_FLAG_DONT_FRAGMENT, IPV4_FRAGMENT_UNIT,
+    IPV4_MAX_LENGTH,
+};
+
+pub const REASSEMBLY_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+// Same as the default of Linux (ipfrag_time)
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// Bytes buffered for all the datagrams under reassembly
+const REASSEMBLY_MEMORY_MAX: usize = 1 << 20;
+// Bytes of the payload quoted along with the header in Time Exceeded
+const REASSEMBLY_QUOTED_LENGTH: usize = 8;
+
+/// Fragments belong to the same datagram when these match (RFC 791).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct DatagramKey {
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    protocol: u8,
+    id: u16,
+}
+
+#[derive(Clone, Debug)]
+struct Datagram {
+    // Header of the first fragment, once it arrived
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    // Ranges of the payload not received yet (RFC 815), the last one open-ended until the length is known
+    holes: Vec<Range<usize>>,
+    // Length of the payload, known from the last fragment
+    length: Option<usize>,
+    expires: Instant,
+}
+
+/// Datagrams whose fragments are arriving.
+#[derive(Clone, Debug)]
+pub struct Reassembly {
+    datagrams: HashMap<DatagramKey, Datagram>,
+    memory: usize,
+    memory_max: usize,
+    timeout: Duration,
+}
+
+impl Reassembly {
+    pub fn new() -> Self {
+        Reassembly::with_limits(REASSEMBLY_TIMEOUT, REASSEMBLY_MEMORY_MAX)
+    }
+
+    /// Gives up on a datagram `timeout` after its first fragment arrived, and on the oldest
+    /// datagrams when their fragments would take more than `memory_max` bytes.
+    pub fn with_limits(timeout: Duration, memory_max: usize) -> Self {
+        Reassembly {
+            datagrams: HashMap::new(),
+            memory: 0,
+            memory_max,
+            timeout,
+        }
+    }
+
+    /// Adds the fragment `packet`, returns the whole packet once its last missing fragment arrives.
+    /// Bytes received already are kept when fragments overlap.
+    pub fn add(
+        &mut self,
+        header: &Ipv4Header,
+        packet: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = DatagramKey {
+            src: header.src,
+            dst: header.dst,
+            protocol: header.protocol,
+            id: header.identification,
+        };
+        let header_length = header.header_length() as usize;
+        let payload = &packet[header_length..];
+        let more = header.more_fragments();
+        let first = header.fragment_offset();
+        let last = first + payload.len();
+        anyhow::ensure!(
+            header_length + last <= IPV4_MAX_LENGTH,
+            "fragment beyond the maximum length, offset: {}, len: {}",
+            first,
+            payload.len()
+        );
+        anyhow::ensure!(
+            !more || payload.len().is_multiple_of(IPV4_FRAGMENT_UNIT),
+            "fragment length not a multiple of 8, len: {}",
+            payload.len()
+        );
+
+        let timeout = self.timeout;
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            data: vec![],
+            // The whole payload is missing at first.
+            #[allow(clippy::single_range_in_vec_init)]
+            holes: vec![0..usize::MAX],
+            length: None,
+            expires: now + timeout,
+        });
+        let inconsistent = match datagram.length {
+            Some(length) => last > length || (!more && last != length),
+            None => !more && datagram.data.len() > last,
+        };
+        if inconsistent {
+            self.remove(&key);
+            anyhow::bail!(
+                "inconsistent fragment, id: {}, offset: {}, len: {}",
+                key.id,
+                first,
+                payload.len()
+            );
+        }
+
+        let growth = last.saturating_sub(datagram.data.len());
+        if !self.reserve(&key, growth) {
+            self.remove(&key);
+            anyhow::bail!("reassembly memory exhausted, id: {}", key.id);
+        }
+        let datagram = self.datagrams.get_mut(&key).unwrap();
+        if growth > 0 {
+            datagram.data.resize(last, 0);
+            self.memory += growth;
+        }
+        if first == 0 && datagram.header.is_none() {
+            datagram.header = Some(packet[..header_length].to_vec());
+        }
+        if !more {
+            datagram.length = Some(last);
+        }
+
+        let mut holes = vec![];
+        for hole in std::mem::take(&mut datagram.holes) {
+            if hole.end <= first || last <= hole.start {
+                holes.push(hole);
+                continue;
+            }
+            let (start, end) = (hole.start.max(first), hole.end.min(last));
+            datagram.data[start..end].copy_from_slice(&payload[start - first..end - first]);
+            if hole.start < first {
+                holes.push(hole.start..first);
+            }
+            if last < hole.end {
+                holes.push(last..hole.end);
+            }
+        }
+        if let Some(length) = datagram.length {
+            holes = holes
+                .into_iter()
+                .filter(|hole| hole.start < length)
+                .map(|hole| hole.start..hole.end.min(length))
+                .collect();
+        }
+        datagram.holes = holes;
+        debug!(
+            "fragment added, id: {}, offset: {}, len: {}, holes: {:?}",
+            key.id,
+            first,
+            payload.len(),
+            datagram.holes
+        );
+        if !datagram.holes.is_empty() {
+            return Ok(None);
+        }
+
+        let datagram = self.remove(&key).unwrap();
+        // No hole left means the first and the last fragments arrived.
+        let mut packet = datagram.header.unwrap();
+        // The first fragment may carry more options than the others.
+        anyhow::ensure!(
+            packet.len() + datagram.data.len() <= IPV4_MAX_LENGTH,
+            "reassembled datagram too long, id: {}",
+            key.id
+        );
+        let total_length = (packet.len() + datagram.data.len()) as u16;
+        let flags_fragment_offset =
+            u16::from_be_bytes([packet[6], packet[7]]) & IPV4_FLAG_DONT_FRAGMENT;
+        packet[2..4].copy_from_slice(&total_length.to_be_bytes());
+        packet[6..8].copy_from_slice(&flags_fragment_offset.to_be_bytes());
+        update_checksum(&mut packet);
+        packet.extend_from_slice(&datagram.data);
+        debug!(
+            "datagram reassembled, id: {}, len: {}",
+            key.id, total_length
+        );
+        Ok(Some(packet))
+    }
+
+    /// Drops the datagrams which timed out, returns for each of them whose first fragment arrived
+    /// its header and the beginning of its payload.
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let expired = self
+            .datagrams
+            .iter()
+            .filter(|(_, datagram)| datagram.expires <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        let mut quoted = vec![];
+        for key in expired {
+            let datagram = self.remove(&key).unwrap();
+            debug!(
+                "reassembly timed out, id: {}, holes: {:?}",
+                key.id, datagram.holes
+            );
+            if let Some(mut header) = datagram.header {
+                let length = datagram.data.len().min(REASSEMBLY_QUOTED_LENGTH);
+                header.extend_from_slice(&datagram.data[..length]);
+                quoted.push(header);
+            }
+        }
+        quoted
+    }
+
+    /// Makes room for `growth` more bytes of the datagram `key`, dropping the oldest other ones.
+    fn reserve(&mut self, key: &DatagramKey, growth: usize) -> bool {
+        while self.memory + growth > self.memory_max {
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(other, _)| *other != key)
+                .min_by_key(|(_, datagram)| datagram.expires)
+                .map(|(other, _)| *other);
+            let Some(oldest) = oldest else {
+                return false;
+            };
+            debug!("reassembly memory full, id dropped: {}", oldest.id);
+            self.remove(&oldest);
+        }
+        true
+    }
+
+    fn remove(&mut self, key: &DatagramKey) -> Option<Datagram> {
+        let datagram = self.datagrams.remove(key)?;
+        self.memory -= datagram.data.len();
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocols::ipv4::create_ip_header, transport::TransportProtocolNumber};
+
+    fn fragment(id: u16, offset: usize, more: bool, data: &[u8]) -> (Ipv4Header, Vec<u8>) {
+        let mut flags_fragment_offset = (offset / IPV4_FRAGMENT_UNIT) as u16;
+        if more {
+            flags_fragment_offset |= super::super::IPV4_FLAG_MORE_FRAGMENTS;
+        }
+        let mut packet = create_ip_header(
+            id,
+            flags_fragment_offset,
+            TransportProtocolNumber::Udp,
+            Ipv4Address::new(&[192, 0, 2, 1]),
+            Ipv4Address::new(&[192, 0, 2, 2]),
+            data,
+        );
+        packet.extend_from_slice(data);
+        (Ipv4Header::try_from(&packet[..]).unwrap(), packet)
+    }
+
+    #[test]
+    fn test_out_of_order_and_overlapping_fragments() {
+        let mut reassembly = Reassembly::new();
+        let now = Instant::now();
+        let data = (0..24).collect::<Vec<u8>>();
+        for (offset, more, payload) in [
+            (16, false, &data[16..]),
+            (8, true, &[0xff; 8][..]),
+            (0, true, &data[..16]),
+        ] {
+            let (header, packet) = fragment(1, offset, more, payload);
+            let result = reassembly.add(&header, &packet, now).unwrap();
+            assert_eq!(result.is_some(), offset == 0);
+            if let Some(packet) = result {
+                let header = Ipv4Header::try_from(&packet[..]).unwrap();
+                assert!(header.validate().is_ok());
+                assert_eq!(header.total_length, 20 + 24);
+                assert!(!header.more_fragments());
+                // The bytes which arrived first are kept.
+                assert_eq!(&packet[20..28], &data[..8]);
+                assert_eq!(&packet[28..36], &[0xff; 8]);
+                assert_eq!(&packet[36..], &data[16..]);
+            }
+        }
+        assert!(reassembly.datagrams.is_empty());
+        assert_eq!(reassembly.memory, 0);
+
+        // A last fragment ending before bytes received already.
+        let (header, packet) = fragment(2, 0, true, &data[..16]);
+        reassembly.add(&header, &packet, now).unwrap();
+        let (header, packet) = fragment(2, 0, false, &data[..8]);
+        assert!(reassembly.add(&header, &packet, now).is_err());
+        assert!(reassembly.datagrams.is_empty());
+    }
+
+    #[test]
+    fn test_timed_out_datagrams_are_quoted() {
+        let mut reassembly = Reassembly::with_limits(Duration::from_secs(30), 1024);
+        let now = Instant::now();
+        let data = [7; 16];
+        for (id, offset) in [(1, 0), (2, 8)] {
+            let (header, packet) = fragment(id, offset, true, &data);
+            reassembly.add(&header, &packet, now).unwrap();
+        }
+        assert!(reassembly.expire(now).is_empty());
+
+        // Only the datagram whose first fragment arrived can be quoted.
+        let quoted = reassembly.expire(now + Duration::from_secs(30));
+        assert_eq!(quoted.len(), 1);
+        assert_eq!(quoted[0], fragment(1, 0, true, &data).1[..20 + 8]);
+        assert!(reassembly.datagrams.is_empty());
+    }
+
+    #[test]
+    fn test_memory_cap_drops_oldest() {
+        let mut reassembly = Reassembly::with_limits(Duration::from_secs(30), 32);
+        let now = Instant::now();
+        let (header, packet) = fragment(1, 0, true, &[0; 16]);
+        reassembly.add(&header, &packet, now).unwrap();
+        let (header, packet) = fragment(2, 0, true, &[0; 24]);
+        reassembly
+            .add(&header, &packet, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(reassembly.datagrams.len(), 1);
+        assert_eq!(reassembly.memory, 24);
+
+        // A datagram larger than the cap by itself is dropped.
+        let (header, packet) = fragment(3, 0, true, &[0; 40]);
+        assert!(reassembly.add(&header, &packet, now).is_err());
+        assert!(reassembly.datagrams.is_empty());
+        assert_eq!(reassembly.memory, 0);
+    }
+}
@@ -0,0 +1,201 @@
+use super::{IpRoute, Ipv4Address};
+
+/// Routes indexed by a binary trie on the bits of their network, one level per bit of the prefix.
+/// Netmasks are expected to be contiguous.
+#[derive(Clone, Debug, Default)]
+pub struct RouteTrie {
+    root: Node,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    // Routes to the prefix ending at this node, lowest metric first
+    routes: Vec<IpRoute>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.routes.is_empty() && self.children.iter().all(Option::is_none)
+    }
+
+    /// Removes the routes not kept by `f` below this node, along with the nodes left empty.
+    fn retain(&mut self, f: &mut impl FnMut(&IpRoute) -> bool) {
+        self.routes.retain(|route| f(route));
+        for child in self.children.iter_mut() {
+            if let Some(node) = child {
+                node.retain(f);
+                if node.is_empty() {
+                    *child = None;
+                }
+            }
+        }
+    }
+
+    /// Removes a route to the prefix of `length` bits of `network`, this node being at `depth`.
+    fn remove(
+        &mut self,
+        network: Ipv4Address,
+        length: u32,
+        depth: u32,
+        metric: Option<u32>,
+    ) -> Option<IpRoute> {
+        if depth == length {
+            let i = self
+                .routes
+                .iter()
+                .position(|route| metric.is_none_or(|metric| route.metric == metric))?;
+            return Some(self.routes.remove(i));
+        }
+        let child = &mut self.children[bit(network, depth)];
+        let removed = child.as_mut()?.remove(network, length, depth + 1, metric);
+        if child.as_ref().is_some_and(|node| node.is_empty()) {
+            *child = None;
+        }
+        removed
+    }
+
+    fn collect<'a>(&'a self, routes: &mut Vec<&'a IpRoute>) {
+        routes.extend(self.routes.iter());
+        for node in self.children.iter().flatten() {
+            node.collect(routes);
+        }
+    }
+}
+
+/// The bit of `address` at `depth`, counted from the most significant one.
+fn bit(address: Ipv4Address, depth: u32) -> usize {
+    (address.0 >> (31 - depth) & 1) as usize
+}
+
+fn prefix_length(netmask: Ipv4Address) -> u32 {
+    netmask.0.count_ones()
+}
+
+impl RouteTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `route` after the routes to the same network with a metric as low, older routes winning ties.
+    pub fn insert(&mut self, route: IpRoute) {
+        let mut node = &mut self.root;
+        for depth in 0..prefix_length(route.netmask) {
+            node = node.children[bit(route.network, depth)].get_or_insert_with(Box::default);
+        }
+        let i = node
+            .routes
+            .partition_point(|other| other.metric <= route.metric);
+        node.routes.insert(i, route);
+    }
+
+    /// Routes to `network`/`netmask`, lowest metric first.
+    pub fn get(&self, network: Ipv4Address, netmask: Ipv4Address) -> &[IpRoute] {
+        let mut node = &self.root;
+        for depth in 0..prefix_length(netmask) {
+            match &node.children[bit(network, depth)] {
+                Some(child) => node = child,
+                None => return &[],
+            }
+        }
+        &node.routes
+    }
+
+    /// Removes the route to `network`/`netmask` with `metric`, or with the lowest metric when `None`.
+    pub fn remove(
+        &mut self,
+        network: Ipv4Address,
+        netmask: Ipv4Address,
+        metric: Option<u32>,
+    ) -> Option<IpRoute> {
+        self.root.remove(network, prefix_length(netmask), 0, metric)
+    }
+
+    /// Removes the routes `f` does not keep.
+    pub fn retain(&mut self, mut f: impl FnMut(&IpRoute) -> bool) {
+        self.root.retain(&mut f);
+    }
+
+    /// The route to `dst` with the longest prefix, the lowest metric among those.
+    pub fn lookup(&self, dst: Ipv4Address) -> Option<&IpRoute> {
+        let mut node = &self.root;
+        let mut found = node.routes.first();
+        for depth in 0..32 {
+            let Some(child) = &node.children[bit(dst, depth)] else {
+                break;
+            };
+            node = child;
+            found = node.routes.first().or(found);
+        }
+        found
+    }
+
+    /// Every route, longest prefix first, then lowest metric.
+    pub fn routes(&self) -> Vec<&IpRoute> {
+        let mut routes = vec![];
+        self.root.collect(&mut routes);
+        routes.sort_by_key(|route| {
+            (
+                std::cmp::Reverse(prefix_length(route.netmask)),
+                route.metric,
+            )
+        });
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{devices::NetDevice, protocols::ipv4::Ipv4Interface};
+
+    #[test]
+    fn test_lookup_matches_scan() {
+        let interface = Arc::new(Ipv4Interface::new(
+            Ipv4Address::new(&[192, 0, 2, 1]),
+            Ipv4Address::new(&[255, 255, 255, 0]),
+            Arc::new(Mutex::new(NetDevice::null())),
+        ));
+        // Few distinct prefixes, so that networks nest and metrics tie.
+        let mut seed = 1u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let mut trie = RouteTrie::new();
+        for _ in 0..500 {
+            let netmask = Ipv4Address(u32::MAX.checked_shl(32 - random() % 33).unwrap_or(0));
+            trie.insert(IpRoute {
+                network: Ipv4Address(random() & 0xff0f_0000) & netmask,
+                netmask,
+                interface: interface.clone(),
+                next_hop: Some(Ipv4Address(random())),
+                metric: random() % 3,
+            });
+        }
+        let routes = trie.routes();
+        for _ in 0..2000 {
+            let dst = Ipv4Address(random() & 0xff0f_ffff);
+            let scanned = routes
+                .iter()
+                .find(|route| dst & route.netmask == route.network);
+            assert_eq!(
+                trie.lookup(dst).map(|route| route.next_hop),
+                scanned.map(|route| route.next_hop)
+            );
+        }
+
+        let keys = routes
+            .iter()
+            .map(|route| (route.network, route.netmask, route.metric))
+            .collect::<Vec<_>>();
+        for (network, netmask, metric) in keys {
+            assert!(trie.remove(network, netmask, Some(metric)).is_some());
+        }
+        assert!(trie.root.is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    protocols::{ipv4::Ipv4Address, ProtocolStackContext},
+    stack::Stack,
+    transport::{
+        icmp::{self, PingStatistics},
+        tcp::{self, congestion::CongestionAlgorithm},
+        udp, ContextBlocks, Endpoint,
+    },
+};
+
+/// A TCP socket waiting for connections on a local endpoint. Dropping it closes it.
+pub struct TcpListener {
+    context: Arc<Mutex<ProtocolStackContext>>,
+    pcbs: Arc<Mutex<ContextBlocks>>,
+    id: usize,
+    closed: bool,
+}
+
+impl TcpListener {
+    pub fn bind(stack: &Stack, local: Endpoint) -> anyhow::Result<Self> {
+        let id = tcp::listen(&mut stack.pcbs.lock().unwrap(), &local)?;
+        Ok(TcpListener {
+            context: stack.context.clone(),
+            pcbs: stack.pcbs.clone(),
+            id,
+            closed: false,
+        })
+    }
+
+    /// Blocks until a connection is established.
+    pub fn accept(&self) -> anyhow::Result<TcpStream> {
+        let id = tcp::accept(&self.pcbs, self.id)?;
+        Ok(TcpStream {
+            context: self.context.clone(),
+            pcbs: self.pcbs.clone(),
+            id,
+            closed: false,
+        })
+    }
+
+    /// Stops listening, resetting the connections not accepted yet.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.closed = true;
+        tcp::close(&self.context, &self.pcbs, self.id)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = tcp::close(&self.context, &self.pcbs, self.id);
+        }
+    }
+}
+
+/// A TCP connection. It is released by `close` or `abort`, dropping it closes it.
+pub struct TcpStream {
+    context: Arc<Mutex<ProtocolStackContext>>,
+    pcbs: Arc<Mutex<ContextBlocks>>,
+    id: usize,
+    closed: bool,
+}
+
+impl TcpStream {
+    /// Opens a connection from `local`, whose port is chosen when zero, and blocks until it is established.
+    pub fn connect(stack: &Stack, local: Endpoint, foreign: Endpoint) -> anyhow::Result<Self> {
+        let id = tcp::connect(&stack.context, &stack.pcbs, local, foreign)?;
+        Ok(TcpStream {
+            context: stack.context.clone(),
+            pcbs: stack.pcbs.clone(),
+            id,
+            closed: false,
+        })
+    }
+
+    pub fn set_congestion_control(&self, algorithm: CongestionAlgorithm) -> anyhow::Result<()> {
+        tcp::set_congestion_control(&mut self.pcbs.lock().unwrap(), self.id, algorithm)
+    }
+
+    /// Blocks until all of `data` is queued for transmission.
+    pub fn send(&self, data: &[u8]) -> anyhow::Result<usize> {
+        tcp::send(&self.context, &self.pcbs, self.id, data)
+    }
+
+    /// Blocks until some data is received, returns 0 once the peer has closed.
+    pub fn receive(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        tcp::receive(&self.context, &self.pcbs, self.id, buf)
+    }
+
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.closed = true;
+        tcp::close(&self.context, &self.pcbs, self.id)
+    }
+
+    /// Resets the connection.
+    pub fn abort(mut self) -> anyhow::Result<()> {
+        self.closed = true;
+        tcp::abort(&self.context, &self.pcbs, self.id)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = tcp::close(&self.context, &self.pcbs, self.id);
+        }
+    }
+}
+
+/// A UDP socket. It can be shared between threads, one of which may close it while others receive.
+/// Dropping it closes it.
+pub struct UdpSocket {
+    context: Arc<Mutex<ProtocolStackContext>>,
+    pcbs: Arc<Mutex<ContextBlocks>>,
+    id: usize,
+    read_timeout: Option<Duration>,
+    // The id may belong to another socket once released.
+    closed: AtomicBool,
+}
+
+impl UdpSocket {
+    /// Opens a socket, which receives nothing until it is bound.
+    pub fn open(stack: &Stack) -> anyhow::Result<Self> {
+        let id = udp::open(&mut stack.pcbs.lock().unwrap())?;
+        Ok(UdpSocket {
+            context: stack.context.clone(),
+            pcbs: stack.pcbs.clone(),
+            id,
+            read_timeout: None,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn bind(&self, local: Endpoint) -> anyhow::Result<()> {
+        udp::bind(&mut self.pcbs.lock().unwrap(), self.id, &local)
+    }
+
+    /// Makes `send` go to `foreign`, and `recv_from` receive from it only.
+    pub fn connect(&self, foreign: Endpoint) -> anyhow::Result<()> {
+        udp::connect(&mut self.pcbs.lock().unwrap(), self.id, &foreign)
+    }
+
+    /// Sends to the endpoint the socket is connected to.
+    pub fn send(&self, data: &[u8]) -> anyhow::Result<usize> {
+        udp::send(&self.context, &self.pcbs, self.id, data)
+    }
+
+    /// Sends to `foreign`, which must be the endpoint a connected socket is connected to.
+    pub fn send_to(&self, data: &[u8], foreign: Endpoint) -> anyhow::Result<usize> {
+        udp::send_to(&self.context, &self.pcbs, self.id, data, foreign)
+    }
+
+    /// Blocks until a datagram arrives, see `set_read_timeout` to bound the wait.
+    /// A datagram longer than `buf` is truncated.
+    pub fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, Endpoint)> {
+        udp::receive_from(&self.pcbs, self.id, buf, self.read_timeout)
+    }
+
+    /// Makes `recv_from` fail with `std::io::ErrorKind::TimedOut` after `timeout`, `None` to wait forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Releases the socket. Threads blocked in `recv_from` wake up with an error.
+    pub fn close(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.closed.swap(true, Ordering::Relaxed),
+            "udp socket already closed, id: {}",
+            self.id
+        );
+        udp::close(&self.pcbs, self.id)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Sends ICMP echo requests to a host and times its replies. Dropping it closes it.
+pub struct Pinger {
+    context: Arc<Mutex<ProtocolStackContext>>,
+    pcbs: Arc<Mutex<ContextBlocks>>,
+    id: u16,
+    dst: Ipv4Address,
+    sequence: u16,
+    statistics: PingStatistics,
+    closed: bool,
+}
+
+impl Pinger {
+    pub fn open(stack: &Stack, dst: Ipv4Address) -> anyhow::Result<Self> {
+        let id = icmp::open(&mut stack.pcbs.lock().unwrap())?;
+        Ok(Pinger {
+            context: stack.context.clone(),
+            pcbs: stack.pcbs.clone(),
+            id,
+            dst,
+            sequence: 0,
+            statistics: PingStatistics::default(),
+            closed: false,
+        })
+    }
+
+    /// Sends the next request and blocks until its reply, returns its sequence number along with
+    /// the round-trip time, `None` when no reply came within `timeout`.
+    pub fn ping(&mut self, timeout: Duration) -> anyhow::Result<(u16, Option<Duration>)> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let rtt = icmp::echo(
+            &self.context,
+            &self.pcbs,
+            self.id,
+            self.sequence,
+            self.dst,
+            timeout,
+        )?;
+        self.statistics.record(rtt);
+        Ok((self.sequence, rtt))
+    }
+
+    pub fn statistics(&self) -> &PingStatistics {
+        &self.statistics
+    }
+
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.closed = true;
+        icmp::close(&mut self.pcbs.lock().unwrap(), self.id)
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = icmp::close(&mut self.pcbs.lock().unwrap(), self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Ipv4Prefix, devices::NetDevice, stack::StackBuilder};
+
+    fn stack() -> Stack {
+        StackBuilder::new()
+            .device(
+                NetDevice::loopback(),
+                &[Ipv4Prefix::try_from("127.0.0.1/8").unwrap()],
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_dropped_sockets_release_their_endpoints() {
+        let stack = stack();
+        let local = Endpoint::new(&[127, 0, 0, 1], 7);
+
+        let socket = UdpSocket::open(&stack).unwrap();
+        socket.bind(local).unwrap();
+        drop(socket);
+        let socket = UdpSocket::open(&stack).unwrap();
+        socket.bind(local).unwrap();
+        socket.close().unwrap();
+        assert!(socket.close().is_err());
+
+        drop(TcpListener::bind(&stack, local).unwrap());
+        TcpListener::bind(&stack, local).unwrap().close().unwrap();
+        TcpListener::bind(&stack, local).unwrap();
+    }
+}
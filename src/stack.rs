@@ -0,0 +1,322 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use log::{debug, error, info};
+use signal_hook::iterator::Signals;
+
+use crate::{
+    config::{Config, Ipv4Config, Ipv4Prefix, RouteConfig, UdpConfig},
+    control::{remove_socket, Control},
+    devices::{run_net, stop_net, NetDevice, NetDevices},
+    interrupt::{
+        self, backend, raise_irq, set_backend, start_timer, EventBackend, INTR_IRQ_ETHERNET_TAP,
+        INTR_IRQ_L3, INTR_IRQ_LOOPBACK, INTR_IRQ_NULL, INTR_IRQ_PACKET, INTR_IRQ_TIMER,
+        INTR_IRQ_TUN, TIMER_INTERVAL,
+    },
+    protocols::{
+        arp::{self, ARP_TIMER_INTERVAL},
+        ipv4::{self, reassembly::REASSEMBLY_TIMER_INTERVAL, Ipv4Address, Ipv4Interface},
+        NetProtocol, NetProtocols, ProtocolStackContext,
+    },
+    transport::{tcp, ContextBlocks},
+};
+
+const IRQS: [i32; 7] = [
+    INTR_IRQ_NULL,
+    INTR_IRQ_LOOPBACK,
+    INTR_IRQ_ETHERNET_TAP,
+    INTR_IRQ_TUN,
+    INTR_IRQ_PACKET,
+    INTR_IRQ_L3,
+    INTR_IRQ_TIMER,
+];
+
+/// Devices, addresses and routes of a stack to build.
+#[derive(Default)]
+pub struct StackBuilder {
+    backend: EventBackend,
+    devices: Vec<(NetDevice, Vec<Ipv4Prefix>)>,
+    routes: Vec<RouteConfig>,
+    ipv4: Ipv4Config,
+    udp: UdpConfig,
+}
+
+impl StackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A builder for the topology `config` declares.
+    pub fn from_config(config: &Config) -> Self {
+        let mut builder = StackBuilder::new();
+        for device in config.devices.iter() {
+            builder = builder.device(device.build(), &device.addresses);
+        }
+        builder.routes = config.routes.clone();
+        builder.ipv4 = config.ipv4.clone();
+        builder.udp = config.udp.clone();
+        builder
+    }
+
+    /// Selects how IRQs are delivered, which is common to every stack of the process.
+    pub fn event_backend(mut self, backend: EventBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Adds a device with an IPv4 interface for each of `addresses`.
+    pub fn device(mut self, device: NetDevice, addresses: &[Ipv4Prefix]) -> Self {
+        self.devices.push((device, addresses.to_vec()));
+        self
+    }
+
+    /// Adds a route, going out of the interface on the gateway's network unless `device` is given.
+    /// Among routes of the same prefix length, the one with the lowest `metric` is used.
+    pub fn route(
+        mut self,
+        network: Ipv4Prefix,
+        gateway: Option<Ipv4Address>,
+        device: Option<&str>,
+        metric: u32,
+    ) -> Self {
+        self.routes.push(RouteConfig {
+            network,
+            gateway,
+            device: device.map(str::to_string),
+            metric,
+        });
+        self
+    }
+
+    /// Makes the stack a router, forwarding the packets addressed to other hosts.
+    pub fn forwarding(mut self, forwarding: bool) -> Self {
+        self.ipv4.forwarding = forwarding;
+        self
+    }
+
+    /// Limits the number of UDP sockets open at once.
+    pub fn udp_max_sockets(mut self, max_sockets: usize) -> Self {
+        self.udp.max_sockets = Some(max_sockets);
+        self
+    }
+
+    /// Limits the datagrams a UDP socket queues, the ones arriving beyond are dropped.
+    pub fn udp_queue_length(mut self, queue_length: usize) -> Self {
+        self.udp.queue_length = Some(queue_length);
+        self
+    }
+
+    /// Opens the devices. IRQs are not handled until the stack is started.
+    pub fn build(self) -> anyhow::Result<Stack> {
+        if backend() != self.backend {
+            set_backend(self.backend)?;
+        }
+        info!("event backend: {:?}", backend());
+
+        let mut context = ProtocolStackContext::new();
+        context.forwarding = self.ipv4.forwarding;
+        let mut devices = NetDevices::new();
+        for (device, addresses) in self.devices {
+            let device = Arc::new(Mutex::new(device));
+            for address in addresses {
+                let interface = Arc::new(Ipv4Interface::new(
+                    address.address,
+                    address.netmask,
+                    device.clone(),
+                ));
+                device
+                    .lock()
+                    .unwrap()
+                    .register_interface(&mut context, interface);
+            }
+            devices.push_back(device);
+        }
+        for route in self.routes.iter() {
+            let interface = route.interface(&devices)?;
+            context.router.register_route(
+                route.network.address,
+                route.network.netmask,
+                interface,
+                route.gateway,
+                route.metric,
+            )?;
+        }
+        run_net(&mut devices)?;
+
+        let mut protocols = NetProtocols::new();
+        protocols.push_back(NetProtocol::ipv4());
+        protocols.push_back(NetProtocol::arp());
+
+        context
+            .timers
+            .register("arp", ARP_TIMER_INTERVAL, arp::handle_timer);
+        context
+            .timers
+            .register("tcp", TIMER_INTERVAL, tcp::handle_timer);
+        context.timers.register(
+            "ipv4 reassembly",
+            REASSEMBLY_TIMER_INTERVAL,
+            ipv4::handle_reassembly_timer,
+        );
+
+        let mut pcbs = ContextBlocks::new();
+        if let Some(max_sockets) = self.udp.max_sockets {
+            pcbs.udp_pcb.set_max_sockets(max_sockets);
+        }
+        if let Some(queue_length) = self.udp.queue_length {
+            pcbs.udp_pcb.set_queue_length(queue_length);
+        }
+
+        Ok(Stack {
+            devices: Arc::new(Mutex::new(devices)),
+            protocols: Arc::new(Mutex::new(protocols)),
+            context: Arc::new(Mutex::new(context)),
+            pcbs: Arc::new(Mutex::new(pcbs)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            event_loop: Arc::new(Mutex::new(None)),
+            control_path: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+/// A running protocol stack. Clones share the same stack.
+#[derive(Clone)]
+pub struct Stack {
+    devices: Arc<Mutex<NetDevices>>,
+    protocols: Arc<Mutex<NetProtocols>>,
+    pub(crate) context: Arc<Mutex<ProtocolStackContext>>,
+    pub(crate) pcbs: Arc<Mutex<ContextBlocks>>,
+    stopping: Arc<AtomicBool>,
+    event_loop: Arc<Mutex<Option<JoinHandle<()>>>>,
+    control_path: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl Stack {
+    /// Handles IRQs from a thread of its own until the stack is stopped.
+    pub fn start(&self) -> anyhow::Result<()> {
+        let mut event_loop = self.event_loop.lock().unwrap();
+        anyhow::ensure!(event_loop.is_none(), "stack already started");
+        let stack = self.clone();
+        let handle = match backend() {
+            EventBackend::Signal => {
+                debug!("signals: {:?}", IRQS);
+                // Registered before the timer starts, so that no IRQ is raised unhandled.
+                let mut signals = Signals::new(IRQS)?;
+                start_timer(TIMER_INTERVAL)?;
+                std::thread::spawn(move || {
+                    for irq in signals.forever() {
+                        if !stack.dispatch(irq) {
+                            break;
+                        }
+                    }
+                })
+            }
+            EventBackend::Epoll => {
+                start_timer(TIMER_INTERVAL)?;
+                std::thread::spawn(move || loop {
+                    let irqs = match interrupt::epoll::wait(None) {
+                        Ok(irqs) => irqs,
+                        Err(err) => {
+                            error!("event loop failed: {:?}", err);
+                            return;
+                        }
+                    };
+                    for irq in irqs {
+                        if !stack.dispatch(irq) {
+                            return;
+                        }
+                    }
+                })
+            }
+        };
+        *event_loop = Some(handle);
+        info!("stack started");
+        Ok(())
+    }
+
+    /// Stops handling IRQs and closes the devices.
+    pub fn stop(&self) -> anyhow::Result<()> {
+        info!("stopping stack");
+        if let Some(handle) = self.event_loop.lock().unwrap().take() {
+            self.stopping.store(true, Ordering::SeqCst);
+            // Wakes the event loop up to notice it is stopping.
+            raise_irq(INTR_IRQ_L3)?;
+            let _ = handle.join();
+        }
+        if let Some(path) = self.control_path.lock().unwrap().take() {
+            remove_socket(&path);
+        }
+        let mut devices = self.devices.lock().unwrap();
+        stop_net(&mut devices)
+    }
+
+    /// Serves the `unetctl` commands on a unix socket at `path`, removed when the stack stops.
+    pub fn serve_control(&self, path: &Path) -> anyhow::Result<()> {
+        let control = Control::new(
+            self.devices.clone(),
+            self.context.clone(),
+            self.pcbs.clone(),
+        );
+        control.serve(path)?;
+        *self.control_path.lock().unwrap() = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Handles an IRQ, returns false once the stack is stopping.
+    fn dispatch(&self, irq: i32) -> bool {
+        match irq {
+            INTR_IRQ_NULL
+            | INTR_IRQ_LOOPBACK
+            | INTR_IRQ_ETHERNET_TAP
+            | INTR_IRQ_TUN
+            | INTR_IRQ_PACKET => self.handle_irq_l2(irq),
+            INTR_IRQ_L3 => self.handle_irq_l3(),
+            INTR_IRQ_TIMER => self.handle_irq_timer(),
+            _ => {}
+        }
+        !self.stopping.load(Ordering::SeqCst)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn handle_irq_l2(&self, irq: i32) {
+        for device in self.devices.lock().unwrap().iter() {
+            let mut device = device.lock().unwrap();
+            if device.irq_entry.irq == irq {
+                let mut protocols = self.protocols.lock().unwrap();
+                if let Err(err) = device.handle_isr(&mut protocols) {
+                    error!("handle irq failed: {:?}", err);
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn handle_irq_l3(&self) {
+        let mut context = self.context.lock().unwrap();
+        let mut pcbs = self.pcbs.lock().unwrap();
+        for protocol in self.protocols.lock().unwrap().iter() {
+            debug!("handle irq, protocol: {:?}", protocol.protocol_type);
+            if let Err(err) = protocol.recv(&mut context, &mut pcbs) {
+                error!("handle irq failed: {:?}", err);
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn handle_irq_timer(&self) {
+        let mut context = self.context.lock().unwrap();
+        let mut pcbs = self.pcbs.lock().unwrap();
+        for (name, handler) in context.timers.expired(Instant::now()) {
+            if let Err(err) = handler(&mut context, &mut pcbs) {
+                error!("timer failed, name: {}, err: {:?}", name, err);
+            }
+        }
+    }
+}
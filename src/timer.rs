@@ -0,0 +1,101 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{protocols::ProtocolStackContext, transport::ContextBlocks};
+
+pub type TimerHandler = fn(&mut ProtocolStackContext, &mut ContextBlocks) -> anyhow::Result<()>;
+
+#[derive(Clone, Debug)]
+struct TimerEntry {
+    name: &'static str,
+    interval: Duration,
+    handler: TimerHandler,
+}
+
+/// Periodic timers registered by the protocols, fired from the timer IRQ.
+#[derive(Clone, Debug)]
+pub struct Timers {
+    entries: Vec<TimerEntry>,
+    // Next expiry and index of each entry, the earliest first.
+    queue: BinaryHeap<Reverse<(Instant, usize)>>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers {
+            entries: vec![],
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &'static str, interval: Duration, handler: TimerHandler) {
+        debug!("timer registered, name: {}, interval: {:?}", name, interval);
+        self.queue
+            .push(Reverse((Instant::now() + interval, self.entries.len())));
+        self.entries.push(TimerEntry {
+            name,
+            interval,
+            handler,
+        });
+    }
+
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((expiry, _))| *expiry)
+    }
+
+    /// Takes the timers expired at `now` and schedules their next expiry.
+    pub fn expired(&mut self, now: Instant) -> Vec<(&'static str, TimerHandler)> {
+        let mut expired = vec![];
+        while let Some(&Reverse((expiry, i))) = self.queue.peek() {
+            if expiry > now {
+                break;
+            }
+            self.queue.pop();
+            let entry = &self.entries[i];
+            // A timer which fell behind does not fire again for every interval it missed.
+            let next = if expiry + entry.interval > now {
+                expiry + entry.interval
+            } else {
+                now + entry.interval
+            };
+            self.queue.push(Reverse((next, i)));
+            expired.push((entry.name, entry.handler));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_: &mut ProtocolStackContext, _: &mut ContextBlocks) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    #[test]
+    fn test_timers_fire_in_order() {
+        let mut timers = Timers::new();
+        let start = Instant::now();
+        timers.register("slow", Duration::from_secs(1), nop);
+        timers.register("fast", Duration::from_millis(100), nop);
+        assert!(timers.expired(start).is_empty());
+
+        let names = |expired: Vec<(&'static str, TimerHandler)>| {
+            expired
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        let expired = timers.expired(start + Duration::from_millis(150));
+        assert_eq!(names(expired), ["fast"]);
+        let expired = timers.expired(start + Duration::from_millis(1050));
+        assert_eq!(names(expired), ["fast", "slow"]);
+        assert!(timers.next_expiry().unwrap() > start + Duration::from_millis(1050));
+    }
+}
@@ -1,14 +1,18 @@
+use icmp::IcmpContext;
+use tcp::TcpContext;
 use udp::UdpContext;
 
 use crate::protocols::ipv4::Ipv4Address;
 
 pub mod icmp;
+pub mod tcp;
 pub mod udp;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TransportProtocolNumber {
     Icmp = 1,
+    Tcp = 6,
     Udp = 17,
 }
 
@@ -18,6 +22,7 @@ impl TryFrom<u8> for TransportProtocolNumber {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(TransportProtocolNumber::Icmp),
+            6 => Ok(TransportProtocolNumber::Tcp),
             17 => Ok(TransportProtocolNumber::Udp),
             _ => Err(anyhow::anyhow!(
                 "unknown transport protocol number: {}",
@@ -48,14 +53,40 @@ impl std::fmt::Display for Endpoint {
     }
 }
 
+/// Pseudo header prepended to TCP and UDP segments when computing the checksum.
+#[derive(Debug, Clone)]
+struct PseudoHeader {
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    zero: u8,
+    protocol: TransportProtocolNumber,
+    length: u16,
+}
+
+impl PseudoHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.src.to_bytes());
+        bytes.extend_from_slice(&self.dst.to_bytes());
+        bytes.push(self.zero);
+        bytes.push(self.protocol as u8);
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+}
+
 pub struct ContextBlocks {
+    pub icmp_pcb: IcmpContext,
     pub udp_pcb: UdpContext,
+    pub tcp_pcb: TcpContext,
 }
 
 impl ContextBlocks {
     pub fn new() -> Self {
         ContextBlocks {
+            icmp_pcb: IcmpContext::new(),
             udp_pcb: UdpContext::new(),
+            tcp_pcb: TcpContext::new(),
         }
     }
 }
@@ -1,13 +1,31 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
 use log::debug;
 
-use crate::protocols::{self, ipv4::Ipv4Address, ProtocolStackContext};
-use crate::transport::TransportProtocolNumber;
+use crate::protocols::{
+    self,
+    ipv4::{Ipv4Address, Ipv4Header},
+    ProtocolStackContext,
+};
+use crate::transport::{tcp, ContextBlocks, TransportProtocolNumber};
+
+const ICMP_HEADER_LENGTH: usize = 8;
+// Bytes of the payload quoted after the IP header of the packet an error is about (RFC 792)
+const ICMP_ERROR_QUOTED_LENGTH: usize = 8;
+// Payload of the echo requests `echo` sends, as long as the one of ping(8) by default
+const ICMP_ECHO_DATA_LENGTH: usize = 56;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IcmpType {
     EchoReply = 0,
+    DestinationUnreachable = 3,
     Echo = 8,
+    TimeExceeded = 11,
 }
 
 impl TryFrom<u8> for IcmpType {
@@ -16,12 +34,77 @@ impl TryFrom<u8> for IcmpType {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(IcmpType::EchoReply),
+            3 => Ok(IcmpType::DestinationUnreachable),
             8 => Ok(IcmpType::Echo),
+            11 => Ok(IcmpType::TimeExceeded),
             _ => Err(anyhow::anyhow!("unknown icmp type: {}", value)),
         }
     }
 }
 
+/// Codes of Destination Unreachable.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnreachableCode {
+    Net = 0,
+    Host = 1,
+    Protocol = 2,
+    Port = 3,
+    FragmentationNeeded = 4,
+}
+
+impl TryFrom<u8> for UnreachableCode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(UnreachableCode::Net),
+            1 => Ok(UnreachableCode::Host),
+            2 => Ok(UnreachableCode::Protocol),
+            3 => Ok(UnreachableCode::Port),
+            4 => Ok(UnreachableCode::FragmentationNeeded),
+            _ => Err(anyhow::anyhow!("unknown unreachable code: {}", value)),
+        }
+    }
+}
+
+/// Codes of Time Exceeded.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeExceededCode {
+    Ttl = 0,
+    Reassembly = 1,
+}
+
+impl TryFrom<u8> for TimeExceededCode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TimeExceededCode::Ttl),
+            1 => Ok(TimeExceededCode::Reassembly),
+            _ => Err(anyhow::anyhow!("unknown time exceeded code: {}", value)),
+        }
+    }
+}
+
+/// Error of a packet that could not be delivered, which the sender may be told with `send_unreachable`.
+#[derive(Debug)]
+pub struct Unreachable(pub UnreachableCode);
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination unreachable: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// An `Unreachable` error described by `message`.
+pub fn unreachable(code: UnreachableCode, message: String) -> anyhow::Error {
+    anyhow::Error::new(Unreachable(code)).context(message)
+}
+
 #[derive(Clone, Debug)]
 pub struct IcmpHeader {
     pub ty: IcmpType,
@@ -49,6 +132,11 @@ impl TryFrom<&[u8]> for IcmpHeader {
     type Error = anyhow::Error;
 
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        anyhow::ensure!(
+            data.len() >= ICMP_HEADER_LENGTH,
+            "too short icmp header, len: {}",
+            data.len()
+        );
         let ty = IcmpType::try_from(data[0])?;
         let code = data[1];
         let checksum = u16::from_be_bytes([data[2], data[3]]);
@@ -62,6 +150,197 @@ impl TryFrom<&[u8]> for IcmpHeader {
     }
 }
 
+/// Replies to the echo requests sent with an identifier, the ones of its last request only.
+struct EchoPcb {
+    // Sequence number and arrival time of each reply
+    replies: VecDeque<(u16, Instant)>,
+    cond: Arc<Condvar>,
+}
+
+pub struct IcmpContext {
+    echoes: HashMap<u16, EchoPcb>,
+    next_id: u16,
+}
+
+impl IcmpContext {
+    pub fn new() -> Self {
+        IcmpContext {
+            echoes: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+/// Round-trip times of echo requests, summarized as ping(8) does.
+#[derive(Clone, Debug, Default)]
+pub struct PingStatistics {
+    pub transmitted: usize,
+    pub received: usize,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    // Sums of the round-trip times and of their squares, in milliseconds
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl PingStatistics {
+    /// Counts a request, along with the round-trip time of its reply unless it was lost.
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        self.transmitted += 1;
+        let Some(rtt) = rtt else {
+            return;
+        };
+        self.received += 1;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+        let ms = rtt.as_secs_f64() * 1000.0;
+        self.sum += ms;
+        self.sum_squares += ms * ms;
+    }
+
+    /// Percentage of the requests left unanswered.
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        (self.transmitted - self.received) as f64 * 100.0 / self.transmitted as f64
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        (self.received > 0)
+            .then(|| Duration::from_secs_f64(self.sum / self.received as f64 / 1000.0))
+    }
+
+    /// Standard deviation of the round-trip times.
+    pub fn mdev(&self) -> Option<Duration> {
+        (self.received > 0).then(|| {
+            let avg = self.sum / self.received as f64;
+            let variance = (self.sum_squares / self.received as f64 - avg * avg).max(0.0);
+            Duration::from_secs_f64(variance.sqrt() / 1000.0)
+        })
+    }
+}
+
+impl std::fmt::Display for PingStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets transmitted, {} received, {}% packet loss",
+            self.transmitted,
+            self.received,
+            self.loss().round()
+        )?;
+        if let (Some(min), Some(avg), Some(max), Some(mdev)) =
+            (self.min(), self.avg(), self.max(), self.mdev())
+        {
+            let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+            write!(
+                f,
+                "\nrtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+                ms(min),
+                ms(avg),
+                ms(max),
+                ms(mdev)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Allocates an identifier for echo requests.
+pub fn open(pcbs: &mut ContextBlocks) -> anyhow::Result<u16> {
+    let icmp = &mut pcbs.icmp_pcb;
+    let Some(id) = (0..=u16::MAX)
+        .map(|i| icmp.next_id.wrapping_add(i))
+        .find(|id| !icmp.echoes.contains_key(id))
+    else {
+        anyhow::bail!("no echo identifier available");
+    };
+    icmp.next_id = id.wrapping_add(1);
+    icmp.echoes.insert(
+        id,
+        EchoPcb {
+            replies: VecDeque::new(),
+            cond: Arc::new(Condvar::new()),
+        },
+    );
+    debug!("icmp echo identifier allocated, id: {}", id);
+    Ok(id)
+}
+
+/// Sends an echo request to `dst` and waits for its reply, returns the round-trip time or `None`
+/// once `timeout` elapses. The replies to earlier requests arriving meanwhile are ignored.
+pub fn echo(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: u16,
+    sequence: u16,
+    dst: Ipv4Address,
+    timeout: Duration,
+) -> anyhow::Result<Option<Duration>> {
+    let mut context = context.lock().unwrap();
+    let mut guard = pcbs.lock().unwrap();
+    let Some(pcb) = guard.icmp_pcb.echoes.get_mut(&id) else {
+        anyhow::bail!("icmp echo identifier not found, id: {}", id);
+    };
+    pcb.replies.clear();
+    let cond = pcb.cond.clone();
+
+    let data = (0..ICMP_ECHO_DATA_LENGTH)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    let values = (id as u32) << 16 | sequence as u32;
+    let sent = Instant::now();
+    send(
+        &mut context,
+        IcmpType::Echo,
+        0,
+        values,
+        &data,
+        Ipv4Address::ANY,
+        dst,
+    )?;
+    drop(context);
+
+    let deadline = sent + timeout;
+    loop {
+        let pcb = guard
+            .icmp_pcb
+            .echoes
+            .get_mut(&id)
+            .expect("icmp echo identifier released while waiting");
+        while let Some((reply, received)) = pcb.replies.pop_front() {
+            if reply == sequence {
+                return Ok(Some(received - sent));
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        guard = cond.wait_timeout(guard, deadline - now).unwrap().0;
+    }
+}
+
+/// Releases an identifier, the replies still to come for it are discarded.
+pub fn close(pcbs: &mut ContextBlocks, id: u16) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        pcbs.icmp_pcb.echoes.remove(&id).is_some(),
+        "icmp echo identifier not found, id: {}",
+        id
+    );
+    debug!("icmp echo identifier released, id: {}", id);
+    Ok(())
+}
+
 #[tracing::instrument(skip(context, code, values, data))]
 pub fn send(
     context: &mut ProtocolStackContext,
@@ -72,6 +351,26 @@ pub fn send(
     src: Ipv4Address,
     dst: Ipv4Address,
 ) -> anyhow::Result<()> {
+    let buffer = message(ty, code, values, data);
+    debug!(
+        "icmp packet transmitted, ty: {:?}, src: {}, dst: {}",
+        ty,
+        src.to_string(),
+        dst.to_string(),
+    );
+
+    protocols::ipv4::send(
+        context,
+        TransportProtocolNumber::Icmp,
+        &buffer,
+        src,
+        dst,
+        false,
+    )
+}
+
+/// An ICMP message carrying `data`, with its checksum.
+fn message(ty: IcmpType, code: u8, values: u32, data: &[u8]) -> Vec<u8> {
     let header = IcmpHeader {
         ty,
         code,
@@ -81,21 +380,131 @@ pub fn send(
     let mut buffer = header.to_bytes();
     buffer.extend_from_slice(data);
     let checksum = crate::utils::calculate_checksum(&buffer, 0);
-    buffer[2] = checksum.to_be_bytes()[0];
-    buffer[3] = checksum.to_be_bytes()[1];
-    debug!(
-        "icmp packet transmitted, ty: {:?}, src: {}, dst: {}",
-        header.ty,
-        src.to_string(),
-        dst.to_string(),
+    buffer[2..4].copy_from_slice(&checksum.to_be_bytes());
+    buffer
+}
+
+/// The IP header of `packet` and the beginning of its payload, as an error about it quotes them.
+fn quoted<'a>(header: &Ipv4Header, packet: &'a [u8]) -> &'a [u8] {
+    &packet[..packet
+        .len()
+        .min(header.header_length() as usize + ICMP_ERROR_QUOTED_LENGTH)]
+}
+
+/// Tells the sender of `packet`, from its IP header on, that it could not be delivered.
+pub fn send_unreachable(
+    context: &mut ProtocolStackContext,
+    code: UnreachableCode,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    send_error(
+        context,
+        IcmpType::DestinationUnreachable,
+        code as u8,
+        0,
+        packet,
+    )
+}
+
+/// Tells the sender of `packet`, which has DF set, the largest packet the next hop takes (RFC 1191).
+pub fn send_fragmentation_needed(
+    context: &mut ProtocolStackContext,
+    mtu: usize,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    send_error(
+        context,
+        IcmpType::DestinationUnreachable,
+        UnreachableCode::FragmentationNeeded as u8,
+        mtu.min(u16::MAX as usize) as u32,
+        packet,
+    )
+}
+
+/// Tells the sender of `packet`, from its IP header on, that it was dropped after too long.
+pub fn send_time_exceeded(
+    context: &mut ProtocolStackContext,
+    code: TimeExceededCode,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    send_error(context, IcmpType::TimeExceeded, code as u8, 0, packet)
+}
+
+/// Tells the sender of `packet` that it could not be delivered, handing the error to the
+/// protocol of the packet when the sender is this host, e.g. once its next hop did not answer ARP.
+pub fn report_unreachable(
+    context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
+    code: UnreachableCode,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    let header = Ipv4Header::try_from(packet)?;
+    let local = context
+        .router
+        .lookup(header.src)
+        .is_some_and(|route| route.interface.unicast == header.src);
+    if !local {
+        return send_unreachable(context, code, packet);
+    }
+    let data = message(
+        IcmpType::DestinationUnreachable,
+        code as u8,
+        0,
+        quoted(&header, packet),
     );
+    recv(context, pcbs, &data, header.src, header.src)
+}
 
-    protocols::ipv4::send(context, TransportProtocolNumber::Icmp, &buffer, src, dst)
+/// Sends an error about `packet` quoting its IP header and the beginning of its payload,
+/// unless that could make errors multiply (RFC 1122 3.2.2).
+fn send_error(
+    context: &mut ProtocolStackContext,
+    ty: IcmpType,
+    code: u8,
+    values: u32,
+    packet: &[u8],
+) -> anyhow::Result<()> {
+    let header = Ipv4Header::try_from(packet)?;
+    let header_length = header.header_length() as usize;
+    let about_error = header.protocol == TransportProtocolNumber::Icmp as u8
+        && packet.get(header_length).is_some_and(|&ty| {
+            !matches!(
+                IcmpType::try_from(ty),
+                Ok(IcmpType::Echo | IcmpType::EchoReply)
+            )
+        });
+    if about_error
+        || header.fragment_offset() != 0
+        || header.src == Ipv4Address::ANY
+        || header.src == Ipv4Address::BROADCAST
+        || header.dst == Ipv4Address::BROADCAST
+        || header.dst.is_multicast()
+        || context
+            .router
+            .lookup(header.dst)
+            .is_some_and(|route| header.dst == route.interface.broadcast)
+    {
+        debug!(
+            "icmp error not sent, ty: {:?}, src: {}, dst: {}",
+            ty, header.src, header.dst
+        );
+        return Ok(());
+    }
+    send(
+        context,
+        ty,
+        code,
+        values,
+        quoted(&header, packet),
+        Ipv4Address::ANY,
+        header.src,
+    )
 }
 
-#[tracing::instrument(skip(context, data))]
+#[tracing::instrument(skip(context, pcbs, data))]
 pub fn recv(
     context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
     data: &[u8],
     src: Ipv4Address,
     dst: Ipv4Address,
@@ -107,8 +516,8 @@ pub fn recv(
         src.to_string(),
         dst.to_string(),
     );
-    if header.ty == IcmpType::Echo {
-        send(
+    match header.ty {
+        IcmpType::Echo => send(
             context,
             IcmpType::EchoReply,
             header.code,
@@ -116,7 +525,38 @@ pub fn recv(
             &data[8..],
             dst,
             src,
-        )?;
+        )?,
+        IcmpType::DestinationUnreachable => {
+            let quoted = Ipv4Header::try_from(&data[8..])?;
+            let code = UnreachableCode::try_from(header.code);
+            debug!(
+                "destination unreachable, code: {:?}, dst: {}, protocol: {}",
+                code, quoted.dst, quoted.protocol
+            );
+            if let (Ok(code), Ok(TransportProtocolNumber::Tcp)) =
+                (code, TransportProtocolNumber::try_from(quoted.protocol))
+            {
+                let offset = ICMP_HEADER_LENGTH + quoted.header_length() as usize;
+                let segment = data.get(offset..).unwrap_or_default();
+                tcp::handle_unreachable(pcbs, code, &quoted, segment)?;
+            }
+        }
+        IcmpType::TimeExceeded => {
+            let quoted = Ipv4Header::try_from(&data[8..])?;
+            debug!(
+                "time exceeded, code: {:?}, dst: {}, protocol: {}",
+                TimeExceededCode::try_from(header.code),
+                quoted.dst,
+                quoted.protocol
+            );
+        }
+        IcmpType::EchoReply => {
+            let (id, sequence) = ((header.values >> 16) as u16, header.values as u16);
+            if let Some(pcb) = pcbs.icmp_pcb.echoes.get_mut(&id) {
+                pcb.replies.push_back((sequence, Instant::now()));
+                pcb.cond.notify_all();
+            }
+        }
     }
     Ok(())
 }
@@ -124,6 +564,7 @@ pub fn recv(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::devices::link::testing::{pair, pending};
 
     #[test]
     fn test_parse_icmp_header() {
@@ -136,5 +577,90 @@ mod tests {
         assert_eq!(header.code, 0x00);
         assert_eq!(header.checksum, 0x3564);
         assert_eq!(header.values, 0x00800001);
+        assert!(IcmpHeader::try_from(&data[..ICMP_HEADER_LENGTH - 1]).is_err());
+    }
+
+    #[test]
+    fn test_no_error_about_group_packets() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let device_a = host_a.device();
+        // The IP and UDP headers of a datagram from `a` to `dst`.
+        let packet = |dst: [u8; 4]| {
+            let mut packet = vec![0x45, 0x00, 0x00, 0x1c, 0, 0, 0, 0, 64, 17, 0, 0];
+            packet.extend_from_slice(&a);
+            packet.extend_from_slice(&dst);
+            packet.extend_from_slice(&[0; 8]);
+            packet
+        };
+
+        let mut context = host_b.context.lock().unwrap();
+        for dst in [[255, 255, 255, 255], [192, 0, 2, 255], [224, 0, 0, 1]] {
+            send_unreachable(&mut context, UnreachableCode::Port, &packet(dst)).unwrap();
+        }
+        assert!(pending(&device_a).is_empty());
+        send_unreachable(&mut context, UnreachableCode::Port, &packet(b)).unwrap();
+        assert_eq!(pending(&device_a).len(), 1);
+    }
+
+    #[test]
+    fn test_echo_reply_is_timed() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+
+        let id = open(&mut host_a.pcbs.lock().unwrap()).unwrap();
+        let timeout = Duration::from_secs(5);
+        std::thread::scope(|s| {
+            let probe = s.spawn(|| {
+                echo(
+                    &host_a.context,
+                    &host_a.pcbs,
+                    id,
+                    1,
+                    Ipv4Address::new(&b),
+                    timeout,
+                )
+            });
+            // The hosts are polled until the reply wakes the probe up.
+            while !probe.is_finished() {
+                host_a.poll();
+                host_b.poll();
+            }
+            let rtt = probe.join().unwrap().unwrap();
+            assert!(rtt.is_some_and(|rtt| rtt < timeout));
+        });
+
+        // Nobody answers at 192.0.2.3.
+        let rtt = echo(
+            &host_a.context,
+            &host_a.pcbs,
+            id,
+            2,
+            Ipv4Address::new(&[192, 0, 2, 3]),
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        assert_eq!(rtt, None);
+        close(&mut host_a.pcbs.lock().unwrap(), id).unwrap();
+    }
+
+    #[test]
+    fn test_ping_statistics() {
+        let mut statistics = PingStatistics::default();
+        for rtt in [Some(1), None, Some(3), Some(2)] {
+            statistics.record(rtt.map(Duration::from_millis));
+        }
+        assert_eq!(statistics.loss(), 25.0);
+        assert_eq!(statistics.min(), Some(Duration::from_millis(1)));
+        assert_eq!(statistics.max(), Some(Duration::from_millis(3)));
+        assert_eq!(
+            statistics.to_string(),
+            "4 packets transmitted, 3 received, 25% packet loss\n\
+             rtt min/avg/max/mdev = 1.000/2.000/3.000/0.816 ms"
+        );
+        assert_eq!(
+            PingStatistics::default().to_string(),
+            "0 packets transmitted, 0 received, 0% packet loss"
+        );
     }
 }
@@ -0,0 +1,2275 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::BuildHasher,
+    sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use congestion::{CongestionAlgorithm, CongestionControl};
+use log::{debug, error};
+use options::{
+    SackBlock, TcpOptions, Timestamps, TCP_OPTIONS_MAX_LENGTH, TCP_SACK_BLOCKS_MAX,
+    TCP_SACK_BLOCKS_MAX_WITH_TIMESTAMPS,
+};
+use rtt::RttEstimator;
+
+use crate::{
+    interrupt::TIMER_INTERVAL,
+    protocols::{
+        ipv4::{self, Ipv4Address, Ipv4Header},
+        ProtocolStackContext,
+    },
+    transport::icmp::UnreachableCode,
+    utils::calculate_checksum,
+};
+
+use super::{ContextBlocks, Endpoint, PseudoHeader, TransportProtocolNumber};
+
+pub mod congestion;
+mod options;
+pub mod rtt;
+
+const TCP_PCB_LENGTH: usize = 16;
+const TCP_HEADER_MIN_LENGTH: usize = 20;
+const TCP_DEFAULT_MSS: usize = 536;
+// Floor of the MSS the peer announces, same as Linux (TCP_MIN_MSS)
+const TCP_MIN_MSS: usize = 88;
+const TCP_BUFFER_SIZE: usize = 256 * 1024;
+// Receive window scale which lets the whole receive buffer be advertised (RFC 7323 2.3).
+const TCP_WINDOW_SHIFT: u8 = {
+    let mut shift = 0;
+    while TCP_BUFFER_SIZE >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+};
+// Space taken by the timestamps option in every segment once they are in use.
+const TCP_TIMESTAMPS_LENGTH: usize = 12;
+// Maximum segment lifetime. RFC 9293 suggests 2 minutes, we use a shorter one like Linux does.
+const TCP_MSL: Duration = Duration::from_secs(30);
+// Retransmissions before the connection is given up, which takes a few minutes with the backoff.
+const TCP_RETRANSMIT_MAX: u32 = 8;
+const TCP_EPHEMERAL_PORT_MIN: u16 = 49152;
+const TCP_EPHEMERAL_PORT_MAX: u16 = 65535;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_URG: u8 = 0x20;
+
+// Sequence numbers are compared in modulo 2^32 arithmetic (RFC 9293 3.4).
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+fn seq_gt(a: u32, b: u32) -> bool {
+    seq_lt(b, a)
+}
+
+#[derive(Debug, Clone)]
+struct TcpHeader {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    offset: u8,
+    flags: u8,
+    window: u16,
+    checksum: u16,
+    urgent: u16,
+    options: TcpOptions,
+}
+
+impl TcpHeader {
+    fn new(src_port: u16, dst_port: u16, seq: u32, ack: u32, flags: u8, window: u16) -> Self {
+        TcpHeader {
+            src_port,
+            dst_port,
+            seq,
+            ack,
+            offset: ((TCP_HEADER_MIN_LENGTH / 4) as u8) << 4,
+            flags,
+            window,
+            checksum: 0,
+            urgent: 0,
+            options: TcpOptions::default(),
+        }
+    }
+
+    fn with_options(mut self, options: TcpOptions) -> Self {
+        self.offset = (((TCP_HEADER_MIN_LENGTH + options.len()) / 4) as u8) << 4;
+        self.options = options;
+        self
+    }
+
+    fn header_length(&self) -> usize {
+        (self.offset >> 4) as usize * 4
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn echo(&self) -> Option<u32> {
+        self.options.timestamps.map(|timestamps| timestamps.echo)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.src_port.to_be_bytes());
+        bytes.extend_from_slice(&self.dst_port.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+        bytes.push(self.offset);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        bytes.extend_from_slice(&self.urgent.to_be_bytes());
+        bytes.extend_from_slice(&self.options.to_bytes());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for TcpHeader {
+    type Error = anyhow::Error;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        anyhow::ensure!(
+            data.len() >= TCP_HEADER_MIN_LENGTH,
+            "tcp segment too short, len: {}",
+            data.len()
+        );
+        let header_length = (data[12] >> 4) as usize * 4;
+        anyhow::ensure!(
+            header_length >= TCP_HEADER_MIN_LENGTH && header_length <= data.len(),
+            "invalid tcp header length: {}",
+            header_length
+        );
+        Ok(TcpHeader {
+            src_port: u16::from_be_bytes([data[0], data[1]]),
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            offset: data[12],
+            flags: data[13] & 0x3f,
+            window: u16::from_be_bytes([data[14], data[15]]),
+            checksum: u16::from_be_bytes([data[16], data[17]]),
+            urgent: u16::from_be_bytes([data[18], data[19]]),
+            options: TcpOptions::try_from(&data[TCP_HEADER_MIN_LENGTH..header_length])?,
+        })
+    }
+}
+
+fn flags_to_string(flags: u8) -> String {
+    [
+        (TCP_FLAG_URG, "URG"),
+        (TCP_FLAG_ACK, "ACK"),
+        (TCP_FLAG_PSH, "PSH"),
+        (TCP_FLAG_RST, "RST"),
+        (TCP_FLAG_SYN, "SYN"),
+        (TCP_FLAG_FIN, "FIN"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>()
+    .join("|")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SendSequence {
+    una: u32,
+    nxt: u32,
+    wnd: u32,
+    wl1: u32,
+    wl2: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ReceiveSequence {
+    nxt: u32,
+    wnd: u32,
+}
+
+/// A transmitted segment which occupies sequence space and waits to be acknowledged.
+#[derive(Debug, Clone)]
+struct RetransmitEntry {
+    seq: u32,
+    len: u32,
+    flags: u8,
+    sent: Instant,
+    retransmitted: bool,
+    // Reported as received by the peer's SACK blocks
+    sacked: bool,
+    // Sent before a retransmission timeout and not resent since (RFC 5681 3.1)
+    lost: bool,
+}
+
+impl RetransmitEntry {
+    fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.len)
+    }
+
+    // Length of the text, which is kept in the send buffer rather than in the entry.
+    fn data_len(&self) -> u32 {
+        self.len - (self.flags & TCP_FLAG_SYN != 0) as u32 - (self.flags & TCP_FLAG_FIN != 0) as u32
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpPcb {
+    state: TcpState,
+    local: Endpoint,
+    foreign: Endpoint,
+    snd: SendSequence,
+    iss: u32,
+    rcv: ReceiveSequence,
+    irs: u32,
+    // Text carried by a full sized segment, which leaves room for the options in use.
+    mss: usize,
+    // Window scale shifts (RFC 7323 2.2), both zero unless the peer agreed to scaling.
+    snd_wscale: u8,
+    rcv_wscale: u8,
+    sack_permitted: bool,
+    timestamps: bool,
+    // TS.Recent (RFC 7323 4.3)
+    ts_recent: u32,
+    // Text received beyond RCV.NXT, sorted by sequence number.
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    // Sequence number of the latest out-of-order segment, whose block is reported first (RFC 2018 4).
+    latest_out_of_order: u32,
+    // Bytes from SND.UNA onwards: unacknowledged ones first, then the ones not sent yet.
+    send_buffer: VecDeque<u8>,
+    recv_buffer: VecDeque<u8>,
+    fin_sent: bool,
+    fin_received: bool,
+    error: Option<&'static str>,
+    // Listening pcb which created this one, until the connection is accepted.
+    parent: Option<usize>,
+    backlog: VecDeque<usize>,
+    time_wait: Option<Instant>,
+    retransmit_queue: VecDeque<RetransmitEntry>,
+    rtt: RttEstimator,
+    retries: u32,
+    // Expiry of the retransmission timer, which doubles as the persist timer while the window is closed.
+    rto_deadline: Option<Instant>,
+    congestion: Box<dyn CongestionControl>,
+    cond: Arc<Condvar>,
+}
+
+impl TcpPcb {
+    const DEFAULT: Option<Self> = None;
+
+    fn new(state: TcpState, local: Endpoint, foreign: Endpoint) -> Self {
+        TcpPcb {
+            state,
+            local,
+            foreign,
+            snd: SendSequence::default(),
+            iss: 0,
+            rcv: ReceiveSequence {
+                nxt: 0,
+                wnd: TCP_BUFFER_SIZE as u32,
+            },
+            irs: 0,
+            mss: TCP_DEFAULT_MSS,
+            // Everything is offered in our SYN and turned off unless the peer agrees.
+            snd_wscale: 0,
+            rcv_wscale: TCP_WINDOW_SHIFT,
+            sack_permitted: true,
+            timestamps: true,
+            ts_recent: 0,
+            out_of_order: vec![],
+            latest_out_of_order: 0,
+            send_buffer: VecDeque::new(),
+            recv_buffer: VecDeque::new(),
+            fin_sent: false,
+            fin_received: false,
+            error: None,
+            parent: None,
+            backlog: VecDeque::new(),
+            time_wait: None,
+            retransmit_queue: VecDeque::new(),
+            rtt: RttEstimator::new(TIMER_INTERVAL),
+            retries: 0,
+            rto_deadline: None,
+            congestion: CongestionAlgorithm::default().build(TCP_DEFAULT_MSS),
+            cond: Arc::new(Condvar::new()),
+        }
+    }
+
+    fn set_state(&mut self, state: TcpState) {
+        debug!(
+            "tcp state changed, local: {}, foreign: {}, {:?} -> {:?}",
+            self.local, self.foreign, self.state, state
+        );
+        self.state = state;
+        self.cond.notify_all();
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.set_state(TcpState::TimeWait);
+        self.time_wait = Some(Instant::now());
+        self.retransmit_queue.clear();
+        self.rto_deadline = None;
+    }
+
+    fn log_congestion(&self) {
+        debug!(
+            "tcp congestion window, local: {}, foreign: {}, algorithm: {:?}, cwnd: {}, ssthresh: {}",
+            self.local,
+            self.foreign,
+            self.congestion.algorithm(),
+            self.congestion.cwnd(),
+            self.congestion.ssthresh()
+        );
+    }
+
+    /// Settles the options of the connection from the peer's SYN, our own SYN offering all of them.
+    fn negotiate(&mut self, options: &TcpOptions, local_mss: usize) {
+        match options.window_scale {
+            Some(shift) => self.snd_wscale = shift,
+            None => self.rcv_wscale = 0,
+        }
+        self.sack_permitted = options.sack_permitted;
+        match options.timestamps {
+            Some(timestamps) => self.ts_recent = timestamps.value,
+            None => self.timestamps = false,
+        }
+        // A tiny MSS would leave no room for data, or none for the window to grow by.
+        let mss = options
+            .mss
+            .map_or(TCP_DEFAULT_MSS, |mss| (mss as usize).max(TCP_MIN_MSS))
+            .min(local_mss);
+        self.mss = if self.timestamps {
+            mss.saturating_sub(TCP_TIMESTAMPS_LENGTH)
+        } else {
+            mss
+        };
+        self.congestion = self.congestion.algorithm().build(self.mss);
+        debug!(
+            "tcp options negotiated, local: {}, foreign: {}, mss: {}, wscale: {}/{}, sack: {}, timestamps: {}",
+            self.local,
+            self.foreign,
+            self.mss,
+            self.snd_wscale,
+            self.rcv_wscale,
+            self.sack_permitted,
+            self.timestamps
+        );
+    }
+
+    /// Appends in-order text, followed by whatever out-of-order text it connects to.
+    fn accept_text(&mut self, text: &[u8]) {
+        let len = text.len().min(self.rcv.wnd as usize);
+        self.append_text(&text[..len]);
+        while let Some(i) = self
+            .out_of_order
+            .iter()
+            .position(|(seq, _)| seq_le(*seq, self.rcv.nxt))
+        {
+            let (seq, data) = self.out_of_order.remove(i);
+            let offset = self.rcv.nxt.wrapping_sub(seq) as usize;
+            if offset < data.len() {
+                let len = (data.len() - offset).min(self.rcv.wnd as usize);
+                self.append_text(&data[offset..offset + len]);
+            }
+        }
+    }
+
+    fn append_text(&mut self, text: &[u8]) {
+        // After the user closed, there is no reader and the text is acknowledged and discarded.
+        if self.state == TcpState::Established {
+            self.recv_buffer.extend(text);
+            self.rcv.wnd = (TCP_BUFFER_SIZE - self.recv_buffer.len()) as u32;
+            self.cond.notify_all();
+        }
+        self.rcv.nxt = self.rcv.nxt.wrapping_add(text.len() as u32);
+    }
+
+    fn queue_out_of_order(&mut self, seq: u32, data: &[u8]) {
+        let rcv_end = self.rcv.nxt.wrapping_add(self.rcv.wnd);
+        let len = (rcv_end.wrapping_sub(seq) as usize).min(data.len());
+        let offset = seq.wrapping_sub(self.rcv.nxt);
+        let i = self
+            .out_of_order
+            .partition_point(|(s, _)| s.wrapping_sub(self.rcv.nxt) < offset);
+        match self.out_of_order.get_mut(i) {
+            Some((s, queued)) if *s == seq => {
+                if queued.len() < len {
+                    *queued = data[..len].to_vec();
+                }
+            }
+            _ => self.out_of_order.insert(i, (seq, data[..len].to_vec())),
+        }
+        self.latest_out_of_order = seq;
+    }
+
+    fn sack_blocks(&self) -> Vec<SackBlock> {
+        let mut blocks: Vec<SackBlock> = vec![];
+        for (seq, data) in &self.out_of_order {
+            let end = seq.wrapping_add(data.len() as u32);
+            match blocks.last_mut() {
+                Some(block) if seq_le(*seq, block.right) => {
+                    if seq_gt(end, block.right) {
+                        block.right = end;
+                    }
+                }
+                _ => blocks.push(SackBlock {
+                    left: *seq,
+                    right: end,
+                }),
+            }
+        }
+        let latest = self.latest_out_of_order;
+        if let Some(i) = blocks
+            .iter()
+            .position(|block| seq_le(block.left, latest) && seq_lt(latest, block.right))
+        {
+            blocks[..=i].rotate_right(1);
+        }
+        blocks
+    }
+
+    fn mark_sacked(&mut self, blocks: &[SackBlock]) {
+        for entry in self.retransmit_queue.iter_mut() {
+            if blocks
+                .iter()
+                .any(|block| seq_le(block.left, entry.seq) && seq_le(entry.end(), block.right))
+            {
+                entry.sacked = true;
+            }
+        }
+    }
+
+    // A segment is considered lost when at least three segments sent after it have been SACKed (RFC 6675 4).
+    fn next_lost(&self) -> Option<usize> {
+        let mut sacked_after = 0;
+        let mut lost = None;
+        for (i, entry) in self.retransmit_queue.iter().enumerate().rev() {
+            if entry.sacked {
+                sacked_after += 1;
+            } else if sacked_after >= 3 && !entry.retransmitted {
+                lost = Some(i);
+            }
+        }
+        lost
+    }
+
+    /// Removes the segments covered by `ack` from the retransmission queue and updates the RTT estimate.
+    /// `echo` is the timestamp echoed by the peer, which gives a sample even for retransmitted segments.
+    fn acknowledge(&mut self, ack: u32, echo: Option<u32>) {
+        let now = Instant::now();
+        let mut sample = echo
+            .filter(|&echo| self.timestamps && echo != 0)
+            .map(|echo| Duration::from_millis(timestamp_now().wrapping_sub(echo) as u64));
+        let timestamped = sample.is_some();
+        while let Some(entry) = self.retransmit_queue.front() {
+            if seq_gt(entry.end(), ack) {
+                break;
+            }
+            if !entry.retransmitted {
+                if !timestamped {
+                    sample = Some(now - entry.sent);
+                }
+            } else if entry.flags & TCP_FLAG_SYN != 0 {
+                self.rtt.syn_lost();
+            }
+            self.retransmit_queue.pop_front();
+        }
+        if let Some(rtt) = sample {
+            self.rtt.sample(rtt);
+            debug!(
+                "tcp rtt sampled, local: {}, foreign: {}, rtt: {:?}, srtt: {:?}, rto: {:?}",
+                self.local,
+                self.foreign,
+                rtt,
+                self.rtt.srtt(),
+                self.rtt.rto()
+            );
+        }
+        self.retries = 0;
+        self.rto_deadline = if self.retransmit_queue.is_empty() {
+            None
+        } else {
+            Some(now + self.rtt.rto())
+        };
+    }
+
+    // Window advertised in a segment other than a SYN
+    fn send_window(&self, header: &TcpHeader) -> u32 {
+        (header.window as u32) << self.snd_wscale
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_sent && self.snd.una == self.snd.nxt
+    }
+
+    fn is_synchronized(&self) -> bool {
+        !matches!(
+            self.state,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent
+        )
+    }
+}
+
+pub struct TcpContext {
+    pcbs: [Option<TcpPcb>; TCP_PCB_LENGTH],
+}
+
+impl TcpContext {
+    pub fn new() -> Self {
+        Self {
+            pcbs: [TcpPcb::DEFAULT; TCP_PCB_LENGTH],
+        }
+    }
+
+    /// Id, state and endpoints of every pcb in use.
+    pub fn list(&self) -> Vec<(usize, TcpState, Endpoint, Endpoint)> {
+        self.pcbs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, pcb)| {
+                pcb.as_ref()
+                    .map(|pcb| (i, pcb.state, pcb.local, pcb.foreign))
+            })
+            .collect()
+    }
+
+    fn alloc(&mut self, pcb: TcpPcb) -> Option<usize> {
+        let (i, slot) = self
+            .pcbs
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())?;
+        debug!(
+            "tcp pcb allocated, i: {}, local: {}, foreign: {}",
+            i, pcb.local, pcb.foreign
+        );
+        *slot = Some(pcb);
+        Some(i)
+    }
+
+    fn release(&mut self, id: usize) {
+        if let Some(pcb) = self.pcbs[id].take() {
+            debug!(
+                "tcp pcb released, i: {}, local: {}, foreign: {}",
+                id, pcb.local, pcb.foreign
+            );
+            pcb.cond.notify_all();
+        }
+    }
+
+    /// Tears down a connection. A user who still owns the socket finds it closed with `error`.
+    fn terminate(&mut self, id: usize, error: &'static str) {
+        let Ok(pcb) = self.get_mut(id) else {
+            return;
+        };
+        match pcb.state {
+            TcpState::SynReceived if pcb.parent.is_some() => self.release(id),
+            TcpState::SynSent
+            | TcpState::SynReceived
+            | TcpState::Established
+            | TcpState::CloseWait => {
+                pcb.error = Some(error);
+                pcb.send_buffer.clear();
+                pcb.retransmit_queue.clear();
+                pcb.rto_deadline = None;
+                pcb.set_state(TcpState::Closed);
+            }
+            // The user has already closed, nobody waits for this pcb.
+            _ => self.release(id),
+        }
+    }
+
+    fn get(&self, id: usize) -> anyhow::Result<&TcpPcb> {
+        self.pcbs
+            .get(id)
+            .and_then(|pcb| pcb.as_ref())
+            .ok_or_else(|| anyhow::anyhow!("tcp socket not found, id: {}", id))
+    }
+
+    fn get_mut(&mut self, id: usize) -> anyhow::Result<&mut TcpPcb> {
+        self.pcbs
+            .get_mut(id)
+            .and_then(|pcb| pcb.as_mut())
+            .ok_or_else(|| anyhow::anyhow!("tcp socket not found, id: {}", id))
+    }
+
+    /// Finds the pcb for an incoming segment, preferring a fully specified connection over a listener.
+    fn select(&self, local: &Endpoint, foreign: &Endpoint) -> Option<usize> {
+        let mut listener = None;
+        for (i, pcb) in self.pcbs.iter().enumerate() {
+            let Some(pcb) = pcb else {
+                continue;
+            };
+            if pcb.local.port != local.port
+                || (pcb.local.address != Ipv4Address::ANY && pcb.local.address != local.address)
+            {
+                continue;
+            }
+            if pcb.foreign == *foreign {
+                return Some(i);
+            }
+            if pcb.state == TcpState::Listen {
+                listener = Some(i);
+            }
+        }
+        listener
+    }
+
+    fn ephemeral_port(&self) -> Option<u16> {
+        (TCP_EPHEMERAL_PORT_MIN..=TCP_EPHEMERAL_PORT_MAX)
+            .find(|&port| self.pcbs.iter().flatten().all(|pcb| pcb.local.port != port))
+    }
+}
+
+// RFC 9293 3.4.1: ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+fn generate_isn(local: &Endpoint, foreign: &Endpoint) -> u32 {
+    static SECRET: OnceLock<RandomState> = OnceLock::new();
+    let hash = SECRET
+        .get_or_init(RandomState::new)
+        .hash_one((local, foreign));
+    let clock = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+        / 4;
+    (clock as u32).wrapping_add(hash as u32)
+}
+
+// Timestamp clock ticking every millisecond (RFC 7323 5.4)
+fn timestamp_now() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    (START.get_or_init(Instant::now).elapsed().as_millis() as u32).wrapping_add(1)
+}
+
+// MSS advertised to the peer, so that a full sized segment fits in the MTU of the outgoing device.
+fn local_mss(context: &ProtocolStackContext, foreign: &Endpoint) -> usize {
+    ipv4::max_payload_length(context, foreign.address)
+        .map_or(TCP_DEFAULT_MSS, |len| len - TCP_HEADER_MIN_LENGTH)
+}
+
+fn segment_options(
+    context: &ProtocolStackContext,
+    pcb: &TcpPcb,
+    flags: u8,
+    data: &[u8],
+) -> TcpOptions {
+    let mut options = TcpOptions::default();
+    if pcb.timestamps {
+        options.timestamps = Some(Timestamps {
+            value: timestamp_now(),
+            echo: pcb.ts_recent,
+        });
+    }
+    if flags & TCP_FLAG_SYN != 0 {
+        options.mss = Some(local_mss(context, &pcb.foreign) as u16);
+        options.window_scale = (pcb.rcv_wscale != 0).then_some(pcb.rcv_wscale);
+        options.sack_permitted = pcb.sack_permitted;
+    } else if pcb.sack_permitted && data.is_empty() {
+        // SACK blocks only go with pure ACKs, so that data segments always fit in the MSS.
+        let max = if pcb.timestamps {
+            TCP_SACK_BLOCKS_MAX_WITH_TIMESTAMPS
+        } else {
+            TCP_SACK_BLOCKS_MAX
+        };
+        options.sack = pcb.sack_blocks();
+        options.sack.truncate(max);
+    }
+    debug_assert!(options.len() <= TCP_OPTIONS_MAX_LENGTH);
+    options
+}
+
+fn build_segment(local: &Endpoint, foreign: &Endpoint, header: &TcpHeader, data: &[u8]) -> Vec<u8> {
+    let length = (header.header_length() + data.len()) as u16;
+    let pseudo_header = PseudoHeader {
+        src: local.address,
+        dst: foreign.address,
+        zero: 0,
+        protocol: TransportProtocolNumber::Tcp,
+        length,
+    };
+    let sum = calculate_checksum(&pseudo_header.to_bytes(), 0);
+    let mut segment = [header.to_bytes(), data.to_vec()].concat();
+    let sum = calculate_checksum(&segment, !sum);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+fn output_segment(
+    context: &mut ProtocolStackContext,
+    local: &Endpoint,
+    foreign: &Endpoint,
+    header: TcpHeader,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let segment = build_segment(local, foreign, &header, data);
+    debug!(
+        "tcp segment transmitted, local: {}, foreign: {}, flags: {}, seq: {}, ack: {}, wnd: {}, len: {}",
+        local,
+        foreign,
+        flags_to_string(header.flags),
+        header.seq,
+        header.ack,
+        header.window,
+        data.len()
+    );
+    ipv4::send(
+        context,
+        TransportProtocolNumber::Tcp,
+        &segment,
+        local.address,
+        foreign.address,
+        // Segments are sized to the MTU already.
+        true,
+    )
+}
+
+fn output(
+    context: &mut ProtocolStackContext,
+    pcb: &TcpPcb,
+    seq: u32,
+    flags: u8,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let ack = if flags & TCP_FLAG_ACK != 0 {
+        pcb.rcv.nxt
+    } else {
+        0
+    };
+    // The window field of a SYN is never scaled.
+    let shift = if flags & TCP_FLAG_SYN != 0 {
+        0
+    } else {
+        pcb.rcv_wscale
+    };
+    let window = (pcb.rcv.wnd >> shift).min(u16::MAX as u32) as u16;
+    let options = segment_options(context, pcb, flags, data);
+    let header = TcpHeader::new(pcb.local.port, pcb.foreign.port, seq, ack, flags, window)
+        .with_options(options);
+    output_segment(context, &pcb.local, &pcb.foreign, header, data)
+}
+
+fn send_ack(context: &mut ProtocolStackContext, pcb: &TcpPcb) -> anyhow::Result<()> {
+    output(context, pcb, pcb.snd.nxt, TCP_FLAG_ACK, &[])
+}
+
+/// Sends a segment occupying sequence space and keeps it for retransmission until acknowledged.
+fn transmit(
+    context: &mut ProtocolStackContext,
+    pcb: &mut TcpPcb,
+    seq: u32,
+    flags: u8,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    output(context, pcb, seq, flags, data)?;
+    let len =
+        data.len() as u32 + (flags & TCP_FLAG_SYN != 0) as u32 + (flags & TCP_FLAG_FIN != 0) as u32;
+    let now = Instant::now();
+    pcb.retransmit_queue.push_back(RetransmitEntry {
+        seq,
+        len,
+        flags,
+        sent: now,
+        retransmitted: false,
+        sacked: false,
+        lost: false,
+    });
+    if pcb.rto_deadline.is_none() {
+        pcb.rto_deadline = Some(now + pcb.rtt.rto());
+    }
+    Ok(())
+}
+
+/// Retransmits the `i`th segment in the retransmission queue, the earliest unacknowledged one being 0.
+fn retransmit(
+    context: &mut ProtocolStackContext,
+    pcb: &mut TcpPcb,
+    i: usize,
+) -> anyhow::Result<()> {
+    let Some(entry) = pcb.retransmit_queue.get_mut(i) else {
+        return Ok(());
+    };
+    entry.retransmitted = true;
+    entry.lost = false;
+    let entry = entry.clone();
+    // The head of the segment may have been acknowledged already.
+    let seq = if seq_lt(entry.seq, pcb.snd.una) {
+        pcb.snd.una
+    } else {
+        entry.seq
+    };
+    let offset = seq.wrapping_sub(pcb.snd.una) as usize;
+    let len = entry.seq.wrapping_add(entry.data_len()).wrapping_sub(seq) as usize;
+    let data = pcb
+        .send_buffer
+        .range(offset..offset + len)
+        .copied()
+        .collect::<Vec<_>>();
+    debug!(
+        "tcp segment retransmitted, local: {}, foreign: {}, seq: {}, len: {}, rto: {:?}",
+        pcb.local,
+        pcb.foreign,
+        seq,
+        len,
+        pcb.rtt.rto()
+    );
+    output(context, pcb, seq, entry.flags, &data)
+}
+
+/// Resends the segments outstanding at a retransmission timeout in order, as far as the windows allow.
+/// Each ACK opens the collapsed congestion window a little more (go-back-N, RFC 5681 3.1).
+fn retransmit_lost(context: &mut ProtocolStackContext, pcb: &mut TcpPcb) -> anyhow::Result<()> {
+    let window = (pcb.snd.wnd as usize).min(pcb.congestion.cwnd());
+    while let Some(i) = pcb.retransmit_queue.iter().position(|entry| entry.lost) {
+        let in_flight = pcb
+            .retransmit_queue
+            .iter()
+            .filter(|entry| !entry.lost)
+            .map(|entry| entry.len as usize)
+            .sum::<usize>();
+        if in_flight > 0 && in_flight + pcb.retransmit_queue[i].len as usize > window {
+            break;
+        }
+        retransmit(context, pcb, i)?;
+    }
+    Ok(())
+}
+
+/// Sends as much queued data as the send window allows, followed by a FIN once the user has closed.
+fn output_data(context: &mut ProtocolStackContext, pcb: &mut TcpPcb) -> anyhow::Result<()> {
+    if pcb.fin_sent
+        || !matches!(
+            pcb.state,
+            TcpState::Established
+                | TcpState::CloseWait
+                | TcpState::FinWait1
+                | TcpState::Closing
+                | TcpState::LastAck
+        )
+    {
+        return Ok(());
+    }
+
+    loop {
+        let in_flight = pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize;
+        let unsent = pcb.send_buffer.len().saturating_sub(in_flight);
+        let window = (pcb.snd.wnd as usize)
+            .min(pcb.congestion.cwnd())
+            .saturating_sub(in_flight);
+        let len = unsent.min(window).min(pcb.mss);
+        if len == 0 {
+            break;
+        }
+        let data = pcb
+            .send_buffer
+            .range(in_flight..in_flight + len)
+            .copied()
+            .collect::<Vec<_>>();
+        transmit(
+            context,
+            pcb,
+            pcb.snd.nxt,
+            TCP_FLAG_ACK | TCP_FLAG_PSH,
+            &data,
+        )?;
+        pcb.snd.nxt = pcb.snd.nxt.wrapping_add(len as u32);
+    }
+
+    // Arm the persist timer so that a window update lost in the network does not stall us.
+    let unsent = pcb.send_buffer.len() - pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize;
+    if pcb.snd.wnd == 0 && unsent > 0 && pcb.rto_deadline.is_none() {
+        pcb.rto_deadline = Some(Instant::now() + pcb.rtt.rto());
+    }
+
+    let all_sent = pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize == pcb.send_buffer.len();
+    if all_sent
+        && matches!(
+            pcb.state,
+            TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck
+        )
+    {
+        transmit(context, pcb, pcb.snd.nxt, TCP_FLAG_FIN | TCP_FLAG_ACK, &[])?;
+        pcb.snd.nxt = pcb.snd.nxt.wrapping_add(1);
+        pcb.fin_sent = true;
+    }
+    Ok(())
+}
+
+// Answers a segment which does not belong to any connection (RFC 9293 3.10.7.1).
+fn reply_reset(
+    context: &mut ProtocolStackContext,
+    header: &TcpHeader,
+    seg_len: u32,
+    local: &Endpoint,
+    foreign: &Endpoint,
+) -> anyhow::Result<()> {
+    if header.has(TCP_FLAG_RST) {
+        return Ok(());
+    }
+    let reset = if header.has(TCP_FLAG_ACK) {
+        TcpHeader::new(local.port, foreign.port, header.ack, 0, TCP_FLAG_RST, 0)
+    } else {
+        TcpHeader::new(
+            local.port,
+            foreign.port,
+            0,
+            header.seq.wrapping_add(seg_len),
+            TCP_FLAG_RST | TCP_FLAG_ACK,
+            0,
+        )
+    };
+    output_segment(context, local, foreign, reset, &[])
+}
+
+fn is_acceptable(pcb: &TcpPcb, seq: u32, seg_len: u32) -> bool {
+    let rcv_end = pcb.rcv.nxt.wrapping_add(pcb.rcv.wnd);
+    let in_window = |seq: u32| seq_le(pcb.rcv.nxt, seq) && seq_lt(seq, rcv_end);
+    match (seg_len, pcb.rcv.wnd) {
+        (0, 0) => seq == pcb.rcv.nxt,
+        (0, _) => in_window(seq),
+        // With a zero window only ACK processing happens, the text gets trimmed away.
+        (_, 0) => seq == pcb.rcv.nxt,
+        _ => in_window(seq) || in_window(seq.wrapping_add(seg_len - 1)),
+    }
+}
+
+#[tracing::instrument(skip(context, pcbs, data))]
+pub fn recv(
+    context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
+    data: &[u8],
+    src: Ipv4Address,
+    dst: Ipv4Address,
+) -> anyhow::Result<()> {
+    let header = TcpHeader::try_from(data)?;
+    let header_length = header.header_length();
+    let pseudo_header = PseudoHeader {
+        src,
+        dst,
+        zero: 0,
+        protocol: TransportProtocolNumber::Tcp,
+        length: data.len() as u16,
+    };
+    let sum = calculate_checksum(data, 0);
+    let sum = calculate_checksum(&pseudo_header.to_bytes(), !sum);
+    if sum != 0 {
+        anyhow::bail!(
+            "invalid tcp checksum: 0x{:04x}, 0x{:04x}",
+            sum,
+            header.checksum
+        );
+    }
+    if src == Ipv4Address::BROADCAST || dst == Ipv4Address::BROADCAST {
+        debug!("tcp segment with broadcast address dropped");
+        return Ok(());
+    }
+
+    let payload = &data[header_length..];
+    let local = Endpoint {
+        address: dst,
+        port: header.dst_port,
+    };
+    let foreign = Endpoint {
+        address: src,
+        port: header.src_port,
+    };
+    debug!(
+        "tcp segment received, local: {}, foreign: {}, flags: {}, seq: {}, ack: {}, wnd: {}, len: {}",
+        local,
+        foreign,
+        flags_to_string(header.flags),
+        header.seq,
+        header.ack,
+        header.window,
+        payload.len()
+    );
+    segment_arrives(context, &mut pcbs.tcp_pcb, &header, payload, local, foreign)
+}
+
+/// Aborts the connection being opened by the segment starting at `segment`, which could not be
+/// delivered. Established connections ride over such errors (RFC 1122 4.2.3.9).
+pub fn handle_unreachable(
+    pcbs: &mut ContextBlocks,
+    code: UnreachableCode,
+    quoted: &Ipv4Header,
+    segment: &[u8],
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        segment.len() >= 4,
+        "too short quoted tcp segment, len: {}",
+        segment.len()
+    );
+    let local = Endpoint {
+        address: quoted.src,
+        port: u16::from_be_bytes([segment[0], segment[1]]),
+    };
+    let foreign = Endpoint {
+        address: quoted.dst,
+        port: u16::from_be_bytes([segment[2], segment[3]]),
+    };
+    let error = match code {
+        UnreachableCode::Net => "network unreachable",
+        UnreachableCode::Host => "no route to host",
+        UnreachableCode::Protocol => "protocol unreachable",
+        UnreachableCode::Port => "connection refused",
+        // Path MTU discovery is not implemented, the segment is retransmitted as it is.
+        UnreachableCode::FragmentationNeeded => return Ok(()),
+    };
+    let tcp = &mut pcbs.tcp_pcb;
+    let Some(id) = tcp.select(&local, &foreign) else {
+        return Ok(());
+    };
+    let pcb = tcp.get(id)?;
+    if pcb.foreign == foreign && pcb.state == TcpState::SynSent {
+        debug!(
+            "tcp connection aborted, local: {}, foreign: {}, {}",
+            local, foreign, error
+        );
+        tcp.terminate(id, error);
+    }
+    Ok(())
+}
+
+// Event processing for SEGMENT ARRIVES, see RFC 9293 3.10.7.
+fn segment_arrives(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    header: &TcpHeader,
+    data: &[u8],
+    local: Endpoint,
+    foreign: Endpoint,
+) -> anyhow::Result<()> {
+    let seg_len =
+        data.len() as u32 + header.has(TCP_FLAG_SYN) as u32 + header.has(TCP_FLAG_FIN) as u32;
+    let Some(id) = tcp.select(&local, &foreign) else {
+        return reply_reset(context, header, seg_len, &local, &foreign);
+    };
+    match tcp.get(id)?.state {
+        TcpState::Closed => reply_reset(context, header, seg_len, &local, &foreign),
+        TcpState::Listen => listen_arrives(context, tcp, id, header, local, foreign),
+        TcpState::SynSent => syn_sent_arrives(context, tcp, id, header),
+        _ => synchronized_arrives(context, tcp, id, header, data, seg_len),
+    }
+}
+
+fn listen_arrives(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    id: usize,
+    header: &TcpHeader,
+    local: Endpoint,
+    foreign: Endpoint,
+) -> anyhow::Result<()> {
+    if header.has(TCP_FLAG_RST) {
+        return Ok(());
+    }
+    if header.has(TCP_FLAG_ACK) {
+        let reset = TcpHeader::new(local.port, foreign.port, header.ack, 0, TCP_FLAG_RST, 0);
+        return output_segment(context, &local, &foreign, reset, &[]);
+    }
+    if !header.has(TCP_FLAG_SYN) {
+        return Ok(());
+    }
+
+    // Text carried by the SYN is not queued, the peer retransmits it after the handshake.
+    let algorithm = tcp.get(id)?.congestion.algorithm();
+    let mut pcb = TcpPcb::new(TcpState::SynReceived, local, foreign);
+    pcb.congestion = algorithm.build(pcb.mss);
+    pcb.negotiate(&header.options, local_mss(context, &foreign));
+    pcb.parent = Some(id);
+    pcb.irs = header.seq;
+    pcb.rcv.nxt = header.seq.wrapping_add(1);
+    pcb.iss = generate_isn(&local, &foreign);
+    pcb.snd.una = pcb.iss;
+    pcb.snd.nxt = pcb.iss.wrapping_add(1);
+    pcb.snd.wnd = header.window as u32;
+    pcb.snd.wl1 = header.seq;
+    let Some(child) = tcp.alloc(pcb) else {
+        anyhow::bail!("no free tcp pcb, local: {}, foreign: {}", local, foreign);
+    };
+    let pcb = tcp.get_mut(child)?;
+    transmit(context, pcb, pcb.iss, TCP_FLAG_SYN | TCP_FLAG_ACK, &[])
+}
+
+fn syn_sent_arrives(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    id: usize,
+    header: &TcpHeader,
+) -> anyhow::Result<()> {
+    let pcb = tcp.get_mut(id)?;
+    let mut acceptable = false;
+    if header.has(TCP_FLAG_ACK) {
+        if seq_le(header.ack, pcb.iss) || seq_gt(header.ack, pcb.snd.nxt) {
+            if header.has(TCP_FLAG_RST) {
+                return Ok(());
+            }
+            let reset = TcpHeader::new(
+                pcb.local.port,
+                pcb.foreign.port,
+                header.ack,
+                0,
+                TCP_FLAG_RST,
+                0,
+            );
+            return output_segment(context, &pcb.local, &pcb.foreign, reset, &[]);
+        }
+        acceptable = seq_le(pcb.snd.una, header.ack);
+    }
+    if header.has(TCP_FLAG_RST) {
+        if acceptable {
+            pcb.error = Some("connection refused");
+            pcb.set_state(TcpState::Closed);
+        }
+        return Ok(());
+    }
+    if !header.has(TCP_FLAG_SYN) {
+        return Ok(());
+    }
+
+    let local_mss = local_mss(context, &pcb.foreign);
+    pcb.negotiate(&header.options, local_mss);
+    pcb.irs = header.seq;
+    pcb.rcv.nxt = header.seq.wrapping_add(1);
+    pcb.snd.wnd = header.window as u32;
+    pcb.snd.wl1 = header.seq;
+    pcb.snd.wl2 = header.ack;
+    if acceptable {
+        pcb.snd.una = header.ack;
+        pcb.acknowledge(header.ack, header.echo());
+    }
+    if seq_gt(pcb.snd.una, pcb.iss) {
+        pcb.set_state(TcpState::Established);
+        send_ack(context, pcb)
+    } else {
+        // Simultaneous open, the SYN|ACK replaces our SYN in the retransmission queue.
+        pcb.set_state(TcpState::SynReceived);
+        pcb.retransmit_queue.clear();
+        pcb.rto_deadline = None;
+        transmit(context, pcb, pcb.iss, TCP_FLAG_SYN | TCP_FLAG_ACK, &[])
+    }
+}
+
+// RFC 5681 2
+fn is_duplicate_ack(pcb: &TcpPcb, header: &TcpHeader, data: &[u8]) -> bool {
+    header.ack == pcb.snd.una
+        && pcb.snd.una != pcb.snd.nxt
+        && data.is_empty()
+        && !header.has(TCP_FLAG_SYN | TCP_FLAG_FIN)
+        && pcb.send_window(header) == pcb.snd.wnd
+}
+
+fn synchronized_arrives(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    id: usize,
+    header: &TcpHeader,
+    data: &[u8],
+    seg_len: u32,
+) -> anyhow::Result<()> {
+    let pcb = tcp.get_mut(id)?;
+
+    // RFC 7323 5.3: segments with an older timestamp are duplicates from an earlier incarnation
+    if let Some(timestamps) = header.options.timestamps {
+        if pcb.timestamps && seq_lt(timestamps.value, pcb.ts_recent) && !header.has(TCP_FLAG_RST) {
+            debug!(
+                "tcp segment rejected by paws, local: {}, foreign: {}, tsval: {}, ts_recent: {}",
+                pcb.local, pcb.foreign, timestamps.value, pcb.ts_recent
+            );
+            return send_ack(context, pcb);
+        }
+    }
+
+    // first, check sequence number
+    if !is_acceptable(pcb, header.seq, seg_len) {
+        if !header.has(TCP_FLAG_RST) {
+            send_ack(context, pcb)?;
+        }
+        return Ok(());
+    }
+    if let Some(timestamps) = header.options.timestamps {
+        if pcb.timestamps && seq_le(header.seq, pcb.rcv.nxt) {
+            pcb.ts_recent = timestamps.value;
+        }
+    }
+
+    // second, check the RST bit
+    if header.has(TCP_FLAG_RST) {
+        // RFC 5961 3.2: only an exact match resets the connection, otherwise send a challenge ACK.
+        if header.seq != pcb.rcv.nxt {
+            return send_ack(context, pcb);
+        }
+        // A passively opened connection is just dropped, the listener keeps waiting for others.
+        let error = if pcb.state == TcpState::SynReceived {
+            "connection refused"
+        } else {
+            "connection reset"
+        };
+        tcp.terminate(id, error);
+        return Ok(());
+    }
+
+    // fourth, check the SYN bit
+    if header.has(TCP_FLAG_SYN) {
+        if pcb.state == TcpState::SynReceived && pcb.parent.is_some() {
+            tcp.release(id);
+            return Ok(());
+        }
+        // RFC 5961 4.2: challenge ACK
+        return send_ack(context, pcb);
+    }
+
+    // fifth, check the ACK field
+    if !header.has(TCP_FLAG_ACK) {
+        return Ok(());
+    }
+    if pcb.state == TcpState::SynReceived {
+        if !(seq_lt(pcb.snd.una, header.ack) && seq_le(header.ack, pcb.snd.nxt)) {
+            let reset = TcpHeader::new(
+                pcb.local.port,
+                pcb.foreign.port,
+                header.ack,
+                0,
+                TCP_FLAG_RST,
+                0,
+            );
+            return output_segment(context, &pcb.local, &pcb.foreign, reset, &[]);
+        }
+        pcb.snd.wnd = pcb.send_window(header);
+        pcb.snd.wl1 = header.seq;
+        pcb.snd.wl2 = header.ack;
+        // Consume the SYN so that only text is counted against the send buffer.
+        pcb.snd.una = pcb.snd.una.wrapping_add(1);
+        pcb.acknowledge(header.ack, header.echo());
+        pcb.set_state(TcpState::Established);
+        if let Some(parent) = pcb.parent {
+            let listener = tcp.get_mut(parent)?;
+            listener.backlog.push_back(id);
+            listener.cond.notify_all();
+        }
+    }
+    let pcb = tcp.get_mut(id)?;
+    if seq_lt(pcb.snd.una, header.ack) && seq_le(header.ack, pcb.snd.nxt) {
+        let acked = header.ack.wrapping_sub(pcb.snd.una) as usize;
+        let len = acked.min(pcb.send_buffer.len());
+        pcb.send_buffer.drain(..len);
+        pcb.snd.una = header.ack;
+        pcb.acknowledge(header.ack, header.echo());
+        if pcb.sack_permitted {
+            pcb.mark_sacked(&header.options.sack);
+        }
+        if pcb
+            .congestion
+            .on_ack(header.ack, acked, pcb.rtt.srtt(), Instant::now())
+        {
+            retransmit(context, pcb, 0)?;
+        }
+        retransmit_lost(context, pcb)?;
+        pcb.log_congestion();
+        // Wake up writers waiting for space in the send buffer
+        pcb.cond.notify_all();
+    } else if seq_gt(header.ack, pcb.snd.nxt) {
+        return send_ack(context, pcb);
+    } else if is_duplicate_ack(pcb, header, data) {
+        if pcb.sack_permitted {
+            pcb.mark_sacked(&header.options.sack);
+        }
+        let flight_size = pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize;
+        if pcb.congestion.on_duplicate_ack(flight_size, pcb.snd.nxt) {
+            debug!(
+                "tcp fast retransmit, local: {}, foreign: {}",
+                pcb.local, pcb.foreign
+            );
+            retransmit(context, pcb, 0)?;
+        } else if let Some(i) = pcb.next_lost() {
+            // SACK shows more holes than the one fast retransmit repaired.
+            retransmit(context, pcb, i)?;
+        }
+        pcb.log_congestion();
+    }
+    if seq_le(pcb.snd.una, header.ack)
+        && (seq_lt(pcb.snd.wl1, header.seq)
+            || (pcb.snd.wl1 == header.seq && seq_le(pcb.snd.wl2, header.ack)))
+    {
+        pcb.snd.wnd = pcb.send_window(header);
+        pcb.snd.wl1 = header.seq;
+        pcb.snd.wl2 = header.ack;
+    }
+    if pcb.snd.wnd == 0 {
+        // The peer answers our window probes, keep probing for as long as it does.
+        pcb.retries = 0;
+    }
+    match pcb.state {
+        TcpState::FinWait1 if pcb.fin_acked() => pcb.set_state(TcpState::FinWait2),
+        TcpState::Closing if pcb.fin_acked() => pcb.enter_time_wait(),
+        TcpState::LastAck if pcb.fin_acked() => {
+            tcp.release(id);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // seventh, process the segment text
+    let mut need_ack = false;
+    if !data.is_empty()
+        && matches!(
+            pcb.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        )
+    {
+        if seq_gt(header.seq, pcb.rcv.nxt) {
+            // Kept until the hole is filled, the duplicate ACK asks for the missing text.
+            pcb.queue_out_of_order(header.seq, data);
+        } else {
+            let offset = (pcb.rcv.nxt.wrapping_sub(header.seq) as usize).min(data.len());
+            pcb.accept_text(&data[offset..]);
+        }
+        need_ack = true;
+    }
+
+    // eighth, check the FIN bit
+    if header.has(TCP_FLAG_FIN) {
+        match pcb.state {
+            TcpState::CloseWait | TcpState::Closing | TcpState::LastAck => need_ack = true,
+            TcpState::TimeWait => {
+                pcb.time_wait = Some(Instant::now());
+                need_ack = true;
+            }
+            // The FIN is processed only when all the preceding text has been received.
+            _ if header.seq.wrapping_add(data.len() as u32) == pcb.rcv.nxt => {
+                pcb.rcv.nxt = pcb.rcv.nxt.wrapping_add(1);
+                pcb.fin_received = true;
+                need_ack = true;
+                match pcb.state {
+                    TcpState::Established => pcb.set_state(TcpState::CloseWait),
+                    TcpState::FinWait1 if pcb.fin_acked() => pcb.enter_time_wait(),
+                    TcpState::FinWait1 => pcb.set_state(TcpState::Closing),
+                    TcpState::FinWait2 => pcb.enter_time_wait(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if need_ack {
+        send_ack(context, pcb)?;
+    }
+    // The ACK may have opened the send window for queued data.
+    output_data(context, pcb)
+}
+
+/// Runs the retransmission, persist and TIME-WAIT timers of every connection.
+#[tracing::instrument(skip_all)]
+pub fn handle_timer(
+    context: &mut ProtocolStackContext,
+    pcbs: &mut ContextBlocks,
+) -> anyhow::Result<()> {
+    let now = Instant::now();
+    for id in 0..TCP_PCB_LENGTH {
+        if let Err(err) = timer_expired(context, &mut pcbs.tcp_pcb, id, now) {
+            error!("tcp timer failed, id: {}, err: {:?}", id, err);
+        }
+    }
+    Ok(())
+}
+
+fn timer_expired(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    id: usize,
+    now: Instant,
+) -> anyhow::Result<()> {
+    let Some(pcb) = tcp.pcbs[id].as_mut() else {
+        return Ok(());
+    };
+    if pcb
+        .time_wait
+        .is_some_and(|since| now.duration_since(since) >= 2 * TCP_MSL)
+    {
+        tcp.release(id);
+        return Ok(());
+    }
+    if pcb.rto_deadline.is_none_or(|deadline| now < deadline) {
+        return Ok(());
+    }
+
+    if pcb.retries >= TCP_RETRANSMIT_MAX {
+        debug!(
+            "tcp connection timed out, local: {}, foreign: {}",
+            pcb.local, pcb.foreign
+        );
+        tcp.terminate(id, "connection timed out");
+        return Ok(());
+    }
+    pcb.retries += 1;
+    pcb.rtt.backoff();
+    pcb.rto_deadline = Some(now + pcb.rtt.rto());
+    if !pcb.retransmit_queue.is_empty() {
+        let flight_size = pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize;
+        pcb.congestion.on_timeout(flight_size);
+        pcb.log_congestion();
+        // The receiver may have discarded what it SACKed (RFC 2018 8).
+        for entry in pcb.retransmit_queue.iter_mut() {
+            entry.sacked = false;
+            entry.lost = true;
+        }
+        return retransmit(context, pcb, 0);
+    }
+
+    // Persist timer: probe the closed window with a single byte (RFC 9293 3.8.6.1).
+    let in_flight = pcb.snd.nxt.wrapping_sub(pcb.snd.una) as usize;
+    let Some(&byte) = pcb.send_buffer.get(in_flight) else {
+        pcb.rto_deadline = None;
+        return Ok(());
+    };
+    debug!(
+        "tcp window probe, local: {}, foreign: {}",
+        pcb.local, pcb.foreign
+    );
+    transmit(context, pcb, pcb.snd.nxt, TCP_FLAG_ACK, &[byte])?;
+    pcb.snd.nxt = pcb.snd.nxt.wrapping_add(1);
+    Ok(())
+}
+
+type StackGuard<'a> = (
+    MutexGuard<'a, ProtocolStackContext>,
+    MutexGuard<'a, ContextBlocks>,
+);
+
+// Takes the locks in the same order as the IRQ handler.
+fn lock<'a>(
+    context: &'a Mutex<ProtocolStackContext>,
+    pcbs: &'a Mutex<ContextBlocks>,
+) -> StackGuard<'a> {
+    let context = context.lock().unwrap();
+    let pcbs = pcbs.lock().unwrap();
+    (context, pcbs)
+}
+
+// Sleeps until the pcb is signalled, releasing the stack meanwhile.
+fn wait<'a>(
+    context: &'a Mutex<ProtocolStackContext>,
+    pcbs: &'a Mutex<ContextBlocks>,
+    guard: StackGuard<'a>,
+    id: usize,
+) -> anyhow::Result<StackGuard<'a>> {
+    let (context_guard, pcbs_guard) = guard;
+    let cond = pcbs_guard.tcp_pcb.get(id)?.cond.clone();
+    drop(context_guard);
+    let pcbs_guard = cond.wait(pcbs_guard).unwrap();
+    drop(pcbs_guard);
+    Ok(lock(context, pcbs))
+}
+
+/// Passive OPEN: creates a listening socket which connections are accepted from.
+pub fn listen(pcbs: &mut ContextBlocks, local: &Endpoint) -> anyhow::Result<usize> {
+    let tcp = &mut pcbs.tcp_pcb;
+    if tcp
+        .pcbs
+        .iter()
+        .flatten()
+        .any(|pcb| pcb.state == TcpState::Listen && pcb.local.port == local.port)
+    {
+        anyhow::bail!("tcp socket already listening, endpoint: {}", local);
+    }
+    let pcb = TcpPcb::new(
+        TcpState::Listen,
+        *local,
+        Endpoint {
+            address: Ipv4Address::ANY,
+            port: 0,
+        },
+    );
+    let Some(id) = tcp.alloc(pcb) else {
+        anyhow::bail!("no free tcp pcb, local: {}", local);
+    };
+    debug!("tcp socket listening, id: {}, local: {}", id, local);
+    Ok(id)
+}
+
+/// Selects the congestion control of a socket, connections accepted on a listening socket inherit it.
+/// The congestion window starts over from the initial window.
+pub fn set_congestion_control(
+    pcbs: &mut ContextBlocks,
+    id: usize,
+    algorithm: CongestionAlgorithm,
+) -> anyhow::Result<()> {
+    let pcb = pcbs.tcp_pcb.get_mut(id)?;
+    pcb.congestion = algorithm.build(pcb.mss);
+    debug!(
+        "tcp congestion control selected, id: {}, algorithm: {:?}",
+        id, algorithm
+    );
+    Ok(())
+}
+
+/// Waits for an established connection on a listening socket.
+pub fn accept(pcbs: &Mutex<ContextBlocks>, id: usize) -> anyhow::Result<usize> {
+    let mut guard = pcbs.lock().unwrap();
+    loop {
+        let listener = guard.tcp_pcb.get_mut(id)?;
+        anyhow::ensure!(
+            listener.state == TcpState::Listen,
+            "tcp socket not listening, id: {}",
+            id
+        );
+        if let Some(child) = listener.backlog.pop_front() {
+            guard.tcp_pcb.get_mut(child)?.parent = None;
+            debug!("tcp connection accepted, id: {}, child: {}", id, child);
+            return Ok(child);
+        }
+        let cond = listener.cond.clone();
+        guard = cond.wait(guard).unwrap();
+    }
+}
+
+/// Active OPEN: connects to `foreign` and waits until the connection is established.
+/// An unspecified local address or port is chosen by the stack.
+pub fn connect(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    local: Endpoint,
+    foreign: Endpoint,
+) -> anyhow::Result<usize> {
+    let mut guard = lock(context, pcbs);
+    let (context_guard, pcbs_guard) = &mut guard;
+    let mut local = local;
+    if local.address == Ipv4Address::ANY {
+        let Some(route) = context_guard.router.lookup(foreign.address) else {
+            anyhow::bail!("no route found, dst: {}", foreign.address);
+        };
+        local.address = route.interface.unicast;
+    }
+    let tcp = &mut pcbs_guard.tcp_pcb;
+    if local.port == 0 {
+        let Some(port) = tcp.ephemeral_port() else {
+            anyhow::bail!("no ephemeral port available");
+        };
+        local.port = port;
+    }
+    if tcp.select(&local, &foreign).is_some_and(|i| {
+        tcp.pcbs[i]
+            .as_ref()
+            .is_some_and(|pcb| pcb.foreign == foreign)
+    }) {
+        anyhow::bail!(
+            "tcp connection already exists, local: {}, foreign: {}",
+            local,
+            foreign
+        );
+    }
+
+    let mut pcb = TcpPcb::new(TcpState::SynSent, local, foreign);
+    pcb.iss = generate_isn(&local, &foreign);
+    pcb.snd.una = pcb.iss;
+    pcb.snd.nxt = pcb.iss.wrapping_add(1);
+    let Some(id) = tcp.alloc(pcb) else {
+        anyhow::bail!("no free tcp pcb, local: {}, foreign: {}", local, foreign);
+    };
+    let pcb = tcp.get_mut(id)?;
+    transmit(context_guard, pcb, pcb.iss, TCP_FLAG_SYN, &[])?;
+
+    loop {
+        let pcb = guard.1.tcp_pcb.get(id)?;
+        match pcb.state {
+            TcpState::SynSent | TcpState::SynReceived => guard = wait(context, pcbs, guard, id)?,
+            TcpState::Closed => {
+                let error = pcb.error.unwrap_or("connection closed");
+                guard.1.tcp_pcb.release(id);
+                anyhow::bail!("{}, foreign: {}", error, foreign);
+            }
+            _ => {
+                debug!(
+                    "tcp connection established, id: {}, local: {}, foreign: {}",
+                    id, local, foreign
+                );
+                return Ok(id);
+            }
+        }
+    }
+}
+
+/// Queues all of `data` for transmission, waiting for room in the send buffer if necessary.
+pub fn send(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+    data: &[u8],
+) -> anyhow::Result<usize> {
+    let mut guard = lock(context, pcbs);
+    let mut sent = 0;
+    while sent < data.len() {
+        let (context_guard, pcbs_guard) = &mut guard;
+        let pcb = pcbs_guard.tcp_pcb.get_mut(id)?;
+        if let Some(error) = pcb.error {
+            anyhow::bail!("{}, id: {}", error, id);
+        }
+        match pcb.state {
+            TcpState::Established | TcpState::CloseWait => {}
+            TcpState::SynSent | TcpState::SynReceived => {
+                guard = wait(context, pcbs, guard, id)?;
+                continue;
+            }
+            _ => anyhow::bail!("connection closing, id: {}", id),
+        }
+        let space = TCP_BUFFER_SIZE - pcb.send_buffer.len();
+        if space == 0 {
+            guard = wait(context, pcbs, guard, id)?;
+            continue;
+        }
+        let len = space.min(data.len() - sent);
+        pcb.send_buffer.extend(&data[sent..sent + len]);
+        sent += len;
+        output_data(context_guard, pcb)?;
+    }
+    Ok(sent)
+}
+
+/// Reads received data into `buf`, waiting for it to arrive. Returns 0 once the peer has closed.
+pub fn receive(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+    buf: &mut [u8],
+) -> anyhow::Result<usize> {
+    let mut guard = lock(context, pcbs);
+    loop {
+        let (context_guard, pcbs_guard) = &mut guard;
+        let pcb = pcbs_guard.tcp_pcb.get_mut(id)?;
+        if !pcb.recv_buffer.is_empty() {
+            let len = buf.len().min(pcb.recv_buffer.len());
+            for (dst, src) in buf.iter_mut().zip(pcb.recv_buffer.drain(..len)) {
+                *dst = src;
+            }
+            // Announce the reopened window, unless a segment is going to carry it anyway (RFC 9293 3.8.6.2.2)
+            let was_small = pcb.rcv.wnd < pcb.mss as u32;
+            pcb.rcv.wnd = (TCP_BUFFER_SIZE - pcb.recv_buffer.len()) as u32;
+            if was_small && pcb.rcv.wnd >= pcb.mss as u32 && pcb.state == TcpState::Established {
+                send_ack(context_guard, pcb)?;
+            }
+            return Ok(len);
+        }
+        if let Some(error) = pcb.error {
+            anyhow::bail!("{}, id: {}", error, id);
+        }
+        if pcb.fin_received {
+            return Ok(0);
+        }
+        match pcb.state {
+            TcpState::SynSent | TcpState::SynReceived | TcpState::Established => {
+                guard = wait(context, pcbs, guard, id)?;
+            }
+            _ => anyhow::bail!("connection closing, id: {}", id),
+        }
+    }
+}
+
+/// CLOSE: sends a FIN after the queued data. The socket must not be used afterwards.
+pub fn close(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+) -> anyhow::Result<()> {
+    let (mut context, mut pcbs) = lock(context, pcbs);
+    let tcp = &mut pcbs.tcp_pcb;
+    let pcb = tcp.get_mut(id)?;
+    debug!("tcp close, id: {}, state: {:?}", id, pcb.state);
+    match pcb.state {
+        TcpState::Closed | TcpState::SynSent => tcp.release(id),
+        TcpState::Listen => {
+            // Connections not accepted yet go away with the listener.
+            for child in 0..TCP_PCB_LENGTH {
+                if tcp.pcbs[child]
+                    .as_ref()
+                    .is_some_and(|pcb| pcb.parent == Some(id))
+                {
+                    reset(&mut context, tcp, child)?;
+                }
+            }
+            tcp.release(id);
+        }
+        TcpState::SynReceived => {
+            // Nothing can have been sent yet, so the FIN goes out right away.
+            transmit(
+                &mut context,
+                pcb,
+                pcb.snd.nxt,
+                TCP_FLAG_FIN | TCP_FLAG_ACK,
+                &[],
+            )?;
+            pcb.snd.nxt = pcb.snd.nxt.wrapping_add(1);
+            pcb.fin_sent = true;
+            pcb.set_state(TcpState::FinWait1);
+        }
+        TcpState::Established => {
+            pcb.set_state(TcpState::FinWait1);
+            output_data(&mut context, pcb)?;
+        }
+        TcpState::CloseWait => {
+            pcb.set_state(TcpState::LastAck);
+            output_data(&mut context, pcb)?;
+        }
+        _ => anyhow::bail!("connection closing, id: {}", id),
+    }
+    Ok(())
+}
+
+/// ABORT: resets the connection immediately, discarding any queued data.
+pub fn abort(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+) -> anyhow::Result<()> {
+    let (mut context, mut pcbs) = lock(context, pcbs);
+    reset(&mut context, &mut pcbs.tcp_pcb, id)
+}
+
+fn reset(
+    context: &mut ProtocolStackContext,
+    tcp: &mut TcpContext,
+    id: usize,
+) -> anyhow::Result<()> {
+    let pcb = tcp.get(id)?;
+    let result = if pcb.is_synchronized() && pcb.state != TcpState::TimeWait {
+        output(context, pcb, pcb.snd.nxt, TCP_FLAG_RST, &[])
+    } else {
+        Ok(())
+    };
+    tcp.release(id);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::devices::link::testing::pair;
+    use crate::devices::{run_net, NetDevice, NetDevices};
+    use crate::protocols::ipv4::Ipv4Interface;
+
+    use super::*;
+
+    struct Peer {
+        local: Endpoint,
+        foreign: Endpoint,
+    }
+
+    impl Peer {
+        // The foreign host the tests talk to, calling port 8000 of the null device.
+        fn new() -> Self {
+            Peer {
+                local: Endpoint::new(&[192, 0, 2, 2], 8000),
+                foreign: Endpoint::new(&[192, 0, 2, 1], 40000),
+            }
+        }
+
+        // Builds a segment as sent by the foreign host.
+        fn segment(&self, seq: u32, ack: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+            self.segment_with_options(seq, ack, flags, TcpOptions::default(), data)
+        }
+
+        fn segment_with_options(
+            &self,
+            seq: u32,
+            ack: u32,
+            flags: u8,
+            options: TcpOptions,
+            data: &[u8],
+        ) -> Vec<u8> {
+            let header = TcpHeader::new(self.foreign.port, self.local.port, seq, ack, flags, 8192)
+                .with_options(options);
+            build_segment(&self.foreign, &self.local, &header, data)
+        }
+
+        fn deliver(
+            &self,
+            context: &mut ProtocolStackContext,
+            pcbs: &mut ContextBlocks,
+            segment: &[u8],
+        ) {
+            recv(
+                context,
+                pcbs,
+                segment,
+                self.foreign.address,
+                self.local.address,
+            )
+            .unwrap();
+        }
+
+        // Listens on the local port and sends a SYN with sequence number 100, returning the half-open connection.
+        fn handshake(
+            &self,
+            context: &mut ProtocolStackContext,
+            pcbs: &mut ContextBlocks,
+            options: TcpOptions,
+        ) -> usize {
+            listen(pcbs, &Endpoint::new(&[0, 0, 0, 0], self.local.port)).unwrap();
+            self.deliver(
+                context,
+                pcbs,
+                &self.segment_with_options(100, 0, TCP_FLAG_SYN, options, &[]),
+            );
+            pcbs.tcp_pcb.select(&self.local, &self.foreign).unwrap()
+        }
+
+        // Completes the handshake, returning the connection and the number acknowledging its SYN.
+        fn established(
+            &self,
+            context: &mut ProtocolStackContext,
+            pcbs: &mut ContextBlocks,
+            options: TcpOptions,
+        ) -> (usize, u32) {
+            let child = self.handshake(context, pcbs, options);
+            let ack = pcbs.tcp_pcb.get(child).unwrap().iss.wrapping_add(1);
+            self.deliver(context, pcbs, &self.segment(101, ack, TCP_FLAG_ACK, &[]));
+            (child, ack)
+        }
+    }
+
+    fn setup() -> (NetDevices, ProtocolStackContext, ContextBlocks) {
+        let mut context = ProtocolStackContext::new();
+        let device = Arc::new(Mutex::new(NetDevice::null()));
+        let interface = Arc::new(Ipv4Interface::new(
+            Ipv4Address::from(&[192, 0, 2, 2]),
+            Ipv4Address::from(&[255, 255, 255, 0]),
+            device.clone(),
+        ));
+        device
+            .lock()
+            .unwrap()
+            .register_interface(&mut context, interface);
+        let mut devices = NetDevices::new();
+        devices.push_back(device);
+        run_net(&mut devices).unwrap();
+        (devices, context, ContextBlocks::new())
+    }
+
+    #[test]
+    fn test_tcp_header() {
+        let header = TcpHeader::new(8000, 40000, 1, 2, TCP_FLAG_SYN | TCP_FLAG_ACK, 1024);
+        let parsed = TcpHeader::try_from(header.to_bytes().as_ref()).unwrap();
+        assert_eq!(parsed.src_port, 8000);
+        assert_eq!(parsed.dst_port, 40000);
+        assert_eq!(parsed.seq, 1);
+        assert_eq!(parsed.ack, 2);
+        assert_eq!(parsed.header_length(), TCP_HEADER_MIN_LENGTH);
+        assert_eq!(flags_to_string(parsed.flags), "ACK|SYN");
+        assert_eq!(parsed.window, 1024);
+    }
+
+    #[test]
+    fn test_sequence_comparison_wraps() {
+        assert!(seq_lt(0xffff_fff0, 0x10));
+        assert!(seq_gt(0x10, 0xffff_fff0));
+        assert!(seq_le(5, 5));
+        assert!(!seq_lt(5, 5));
+    }
+
+    #[test]
+    fn test_passive_open_and_close() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let child = peer.handshake(&mut context, &mut pcbs, TcpOptions::default());
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        let listener = pcb.parent.unwrap();
+        assert_ne!(child, listener);
+        assert_eq!(pcb.state, TcpState::SynReceived);
+        assert_eq!(pcb.rcv.nxt, 101);
+        let iss = pcb.iss;
+
+        let ack = iss.wrapping_add(1);
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack, TCP_FLAG_ACK, &[]),
+        );
+        assert_eq!(
+            pcbs.tcp_pcb.get(child).unwrap().state,
+            TcpState::Established
+        );
+        assert_eq!(pcbs.tcp_pcb.get(listener).unwrap().backlog, [child]);
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack, TCP_FLAG_ACK | TCP_FLAG_PSH, b"hello"),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.recv_buffer, b"hello");
+        assert_eq!(pcb.rcv.nxt, 106);
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(106, ack, TCP_FLAG_ACK | TCP_FLAG_FIN, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.state, TcpState::CloseWait);
+        assert!(pcb.fin_received);
+    }
+
+    #[test]
+    fn test_out_of_window_segment_is_not_accepted() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let (child, _) = peer.established(&mut context, &mut pcbs, TcpOptions::default());
+
+        // A reset with an inexact sequence number only triggers a challenge ACK.
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(102, 0, TCP_FLAG_RST, &[]),
+        );
+        assert_eq!(
+            pcbs.tcp_pcb.get(child).unwrap().state,
+            TcpState::Established
+        );
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, 0, TCP_FLAG_RST, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.state, TcpState::Closed);
+        assert_eq!(pcb.error, Some("connection reset"));
+    }
+
+    #[test]
+    fn test_ack_stops_retransmission() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let child = peer.handshake(&mut context, &mut pcbs, TcpOptions::default());
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.retransmit_queue.len(), 1);
+        assert!(pcb.rto_deadline.is_some());
+
+        let ack = pcb.iss.wrapping_add(1);
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack, TCP_FLAG_ACK, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert!(pcb.retransmit_queue.is_empty());
+        assert!(pcb.rto_deadline.is_none());
+        assert!(pcb.rtt.srtt().is_some());
+    }
+
+    #[test]
+    fn test_retransmission_times_out() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let child = Peer::new().handshake(&mut context, &mut pcbs, TcpOptions::default());
+
+        let mut rto = pcbs.tcp_pcb.get(child).unwrap().rtt.rto();
+        for retries in 1..=TCP_RETRANSMIT_MAX {
+            let deadline = pcbs.tcp_pcb.get(child).unwrap().rto_deadline.unwrap();
+            timer_expired(&mut context, &mut pcbs.tcp_pcb, child, deadline).unwrap();
+            let pcb = pcbs.tcp_pcb.get(child).unwrap();
+            assert_eq!(pcb.retries, retries);
+            assert!(pcb.retransmit_queue[0].retransmitted);
+            assert!(pcb.rtt.rto() >= rto);
+            rto = pcb.rtt.rto();
+        }
+
+        // The half-open connection is dropped, the listener is left alone.
+        let deadline = pcbs.tcp_pcb.get(child).unwrap().rto_deadline.unwrap();
+        timer_expired(&mut context, &mut pcbs.tcp_pcb, child, deadline).unwrap();
+        assert!(pcbs.tcp_pcb.get(child).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_acks_trigger_fast_retransmit() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let (child, ack) = peer.established(&mut context, &mut pcbs, TcpOptions::default());
+
+        let pcb = pcbs.tcp_pcb.get_mut(child).unwrap();
+        pcb.send_buffer.extend([0; 4 * TCP_DEFAULT_MSS]);
+        output_data(&mut context, pcb).unwrap();
+        assert_eq!(pcb.retransmit_queue.len(), 4);
+
+        for _ in 0..3 {
+            peer.deliver(
+                &mut context,
+                &mut pcbs,
+                &peer.segment(101, ack, TCP_FLAG_ACK, &[]),
+            );
+        }
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert!(pcb.retransmit_queue[0].retransmitted);
+        assert!(!pcb.retransmit_queue[1].retransmitted);
+        assert_eq!(pcb.congestion.ssthresh(), 2 * TCP_DEFAULT_MSS);
+    }
+
+    #[test]
+    fn test_timeout_resends_every_lost_segment() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let (child, ack) = peer.established(&mut context, &mut pcbs, TcpOptions::default());
+        let pcb = pcbs.tcp_pcb.get_mut(child).unwrap();
+        pcb.send_buffer.extend([0; 4 * TCP_DEFAULT_MSS]);
+        output_data(&mut context, pcb).unwrap();
+
+        // The first two segments are lost, the peer queues the other two out of order.
+        let deadline = pcb.rto_deadline.unwrap();
+        timer_expired(&mut context, &mut pcbs.tcp_pcb, child, deadline).unwrap();
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.congestion.cwnd(), TCP_DEFAULT_MSS);
+        assert!(pcb.retransmit_queue[0].retransmitted);
+        assert!(pcb.retransmit_queue.iter().skip(1).all(|entry| entry.lost));
+
+        // The ACK of the first one clocks out the second without waiting for another timeout.
+        let mss = TCP_DEFAULT_MSS as u32;
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack.wrapping_add(mss), TCP_FLAG_ACK, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.retries, 0);
+        assert_eq!(pcb.congestion.cwnd(), 2 * TCP_DEFAULT_MSS);
+        assert!(pcb.retransmit_queue[0].retransmitted);
+        assert!(pcb.retransmit_queue[1].retransmitted);
+        assert!(pcb.retransmit_queue[2].lost);
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack.wrapping_add(4 * mss), TCP_FLAG_ACK, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert!(pcb.retransmit_queue.is_empty());
+        assert!(pcb.rto_deadline.is_none());
+    }
+
+    #[test]
+    fn test_options_are_negotiated() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let options = TcpOptions {
+            mss: Some(8960),
+            window_scale: Some(7),
+            sack_permitted: true,
+            timestamps: Some(Timestamps { value: 1, echo: 0 }),
+            ..Default::default()
+        };
+        let child = peer.handshake(&mut context, &mut pcbs, options);
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        // The null device has an MTU of 1500.
+        assert_eq!(pcb.mss, 1460 - TCP_TIMESTAMPS_LENGTH);
+        assert_eq!(pcb.snd_wscale, 7);
+        assert_eq!(pcb.rcv_wscale, TCP_WINDOW_SHIFT);
+        assert!(pcb.sack_permitted);
+        assert_eq!(pcb.ts_recent, 1);
+        // The window of a SYN is not scaled.
+        assert_eq!(pcb.snd.wnd, 8192);
+
+        let ack = pcb.iss.wrapping_add(1);
+        let options = TcpOptions {
+            timestamps: Some(Timestamps {
+                value: 2,
+                echo: timestamp_now(),
+            }),
+            ..Default::default()
+        };
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment_with_options(101, ack, TCP_FLAG_ACK, options, &[]),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.state, TcpState::Established);
+        assert_eq!(pcb.snd.wnd, 8192 << 7);
+        assert_eq!(pcb.ts_recent, 2);
+        assert!(pcb.rtt.srtt().is_some());
+    }
+
+    #[test]
+    fn test_tiny_mss_is_raised() {
+        for mss in [0, 8] {
+            let (_devices, mut context, mut pcbs) = setup();
+            let options = TcpOptions {
+                mss: Some(mss),
+                timestamps: Some(Timestamps { value: 1, echo: 0 }),
+                ..Default::default()
+            };
+            let child = Peer::new().handshake(&mut context, &mut pcbs, options);
+            let pcb = pcbs.tcp_pcb.get(child).unwrap();
+            assert_eq!(pcb.mss, TCP_MIN_MSS - TCP_TIMESTAMPS_LENGTH);
+            assert!(pcb.congestion.cwnd() > 0);
+        }
+    }
+
+    #[test]
+    fn test_options_are_not_offered_back() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let child = Peer::new().handshake(&mut context, &mut pcbs, TcpOptions::default());
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.mss, TCP_DEFAULT_MSS);
+        assert_eq!((pcb.snd_wscale, pcb.rcv_wscale), (0, 0));
+        let options = segment_options(&context, pcb, TCP_FLAG_SYN | TCP_FLAG_ACK, &[]);
+        assert_eq!(
+            options,
+            TcpOptions {
+                mss: Some(1460),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_text_is_reassembled() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let options = TcpOptions {
+            sack_permitted: true,
+            ..Default::default()
+        };
+        let (child, ack) = peer.established(&mut context, &mut pcbs, options);
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(111, ack, TCP_FLAG_ACK, b"!"),
+        );
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(106, ack, TCP_FLAG_ACK, b"world"),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert!(pcb.recv_buffer.is_empty());
+        assert_eq!(pcb.rcv.nxt, 101);
+        assert_eq!(
+            pcb.sack_blocks(),
+            [SackBlock {
+                left: 106,
+                right: 112
+            }]
+        );
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(101, ack, TCP_FLAG_ACK, b"hello"),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert_eq!(pcb.recv_buffer, b"helloworld!");
+        assert_eq!(pcb.rcv.nxt, 112);
+        assert!(pcb.sack_blocks().is_empty());
+    }
+
+    #[test]
+    fn test_old_timestamp_is_rejected() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        let timestamps = |value| TcpOptions {
+            timestamps: Some(Timestamps { value, echo: 0 }),
+            ..Default::default()
+        };
+        let child = peer.handshake(&mut context, &mut pcbs, timestamps(1000));
+        let ack = pcbs.tcp_pcb.get(child).unwrap().iss.wrapping_add(1);
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment_with_options(101, ack, TCP_FLAG_ACK, timestamps(1001), &[]),
+        );
+
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment_with_options(101, ack, TCP_FLAG_ACK, timestamps(999), b"stale"),
+        );
+        let pcb = pcbs.tcp_pcb.get(child).unwrap();
+        assert!(pcb.recv_buffer.is_empty());
+        assert_eq!(pcb.ts_recent, 1001);
+    }
+
+    #[test]
+    fn test_segment_without_connection_is_reset() {
+        let (_devices, mut context, mut pcbs) = setup();
+        let peer = Peer::new();
+        peer.deliver(
+            &mut context,
+            &mut pcbs,
+            &peer.segment(100, 0, TCP_FLAG_SYN, &[]),
+        );
+        assert!(pcbs.tcp_pcb.select(&peer.local, &peer.foreign).is_none());
+    }
+
+    #[test]
+    fn test_connection_across_link() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let listener = listen(
+            &mut host_b.pcbs.lock().unwrap(),
+            &Endpoint::new(&[0, 0, 0, 0], 8000),
+        )
+        .unwrap();
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            // Stands in for the IRQ handlers of both hosts.
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    host_a.poll();
+                    host_b.poll();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let server = s.spawn(|| {
+                let id = accept(&host_b.pcbs, listener).unwrap();
+                let mut buf = [0; 64];
+                let n = receive(&host_b.context, &host_b.pcbs, id, &mut buf).unwrap();
+                send(&host_b.context, &host_b.pcbs, id, &buf[..n]).unwrap();
+                close(&host_b.context, &host_b.pcbs, id).unwrap();
+            });
+
+            let id = connect(
+                &host_a.context,
+                &host_a.pcbs,
+                Endpoint::new(&[0, 0, 0, 0], 0),
+                Endpoint::new(&b, 8000),
+            )
+            .unwrap();
+            send(&host_a.context, &host_a.pcbs, id, b"hello").unwrap();
+            let mut buf = [0; 64];
+            let n = receive(&host_a.context, &host_a.pcbs, id, &mut buf).unwrap();
+            assert_eq!(&buf[..n], b"hello");
+            // The server closes after echoing.
+            assert_eq!(
+                receive(&host_a.context, &host_a.pcbs, id, &mut buf).unwrap(),
+                0
+            );
+            close(&host_a.context, &host_a.pcbs, id).unwrap();
+            server.join().unwrap();
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+}
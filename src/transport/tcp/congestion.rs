@@ -0,0 +1,458 @@
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use super::seq_le;
+
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+// CUBIC constants (RFC 9438 4.6 and 5.1)
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    Reno,
+    #[default]
+    NewReno,
+    Cubic,
+}
+
+impl CongestionAlgorithm {
+    pub fn build(self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Reno => Box::new(Reno::new(mss)),
+            CongestionAlgorithm::NewReno => Box::new(NewReno::new(mss)),
+            CongestionAlgorithm::Cubic => Box::new(Cubic::new(mss)),
+        }
+    }
+}
+
+impl FromStr for CongestionAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reno" => Ok(CongestionAlgorithm::Reno),
+            "newreno" => Ok(CongestionAlgorithm::NewReno),
+            "cubic" => Ok(CongestionAlgorithm::Cubic),
+            _ => Err(anyhow::anyhow!("unknown congestion control: {}", s)),
+        }
+    }
+}
+
+/// Sender side congestion control of a connection.
+/// Sizes are in bytes and `flight_size` is the amount of data sent but not yet acknowledged.
+pub trait CongestionControl: Debug + Send {
+    fn algorithm(&self) -> CongestionAlgorithm;
+
+    fn cwnd(&self) -> usize;
+
+    fn ssthresh(&self) -> usize;
+
+    /// An ACK advanced SND.UNA to `ack` by `acked` bytes, `srtt` being the smoothed RTT once measured.
+    /// Returns true when the first unacknowledged segment has to be retransmitted.
+    fn on_ack(&mut self, ack: u32, acked: usize, srtt: Option<Duration>, now: Instant) -> bool;
+
+    /// A duplicate ACK arrived.
+    /// Returns true when the first unacknowledged segment has to be retransmitted (fast retransmit).
+    fn on_duplicate_ack(&mut self, flight_size: usize, snd_nxt: u32) -> bool;
+
+    /// The retransmission timer expired.
+    fn on_timeout(&mut self, flight_size: usize);
+}
+
+/// State shared by the algorithms, following RFC 5681.
+#[derive(Debug, Clone)]
+struct Window {
+    cwnd: usize,
+    ssthresh: usize,
+    mss: usize,
+    dup_acks: u32,
+    // SND.NXT when fast recovery was entered (RFC 6582)
+    recover: Option<u32>,
+}
+
+impl Window {
+    fn new(mss: usize) -> Self {
+        Window {
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            mss,
+            dup_acks: 0,
+            recover: None,
+        }
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    fn slow_start(&mut self, acked: usize) {
+        self.cwnd += acked.min(self.mss);
+    }
+
+    // RFC 5681 (3)
+    fn congestion_avoidance(&mut self) {
+        self.cwnd += (self.mss * self.mss / self.cwnd).max(1);
+    }
+
+    fn duplicate_ack(&mut self) -> bool {
+        if self.recover.is_some() {
+            // Each duplicate ACK means a segment has left the network.
+            self.cwnd += self.mss;
+            return false;
+        }
+        self.dup_acks += 1;
+        self.dup_acks == DUPLICATE_ACK_THRESHOLD
+    }
+
+    fn enter_recovery(&mut self, ssthresh: usize, snd_nxt: u32) {
+        self.ssthresh = ssthresh.max(2 * self.mss);
+        self.cwnd = self.ssthresh + DUPLICATE_ACK_THRESHOLD as usize * self.mss;
+        self.recover = Some(snd_nxt);
+    }
+
+    // RFC 6582 3.2 (5): partial ACKs keep the connection in fast recovery.
+    fn recovery_ack(&mut self, ack: u32, acked: usize) -> bool {
+        let Some(recover) = self.recover else {
+            return false;
+        };
+        if seq_le(recover, ack) {
+            self.cwnd = self.ssthresh;
+            self.recover = None;
+            return false;
+        }
+        self.cwnd = self.cwnd.saturating_sub(acked).max(self.mss);
+        if acked >= self.mss {
+            self.cwnd += self.mss;
+        }
+        true
+    }
+
+    fn timeout(&mut self, ssthresh: usize) {
+        self.ssthresh = ssthresh.max(2 * self.mss);
+        self.cwnd = self.mss;
+        self.dup_acks = 0;
+        self.recover = None;
+    }
+}
+
+// RFC 5681 3.1
+fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
+
+/// RFC 5681
+#[derive(Debug, Clone)]
+pub struct Reno {
+    window: Window,
+}
+
+impl Reno {
+    pub fn new(mss: usize) -> Self {
+        Reno {
+            window: Window::new(mss),
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn algorithm(&self) -> CongestionAlgorithm {
+        CongestionAlgorithm::Reno
+    }
+
+    fn cwnd(&self) -> usize {
+        self.window.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.window.ssthresh
+    }
+
+    fn on_ack(&mut self, _ack: u32, acked: usize, _srtt: Option<Duration>, _now: Instant) -> bool {
+        let window = &mut self.window;
+        window.dup_acks = 0;
+        if window.recover.take().is_some() {
+            // Any new ACK deflates the window and ends fast recovery.
+            window.cwnd = window.ssthresh;
+        } else if window.in_slow_start() {
+            window.slow_start(acked);
+        } else {
+            window.congestion_avoidance();
+        }
+        false
+    }
+
+    fn on_duplicate_ack(&mut self, flight_size: usize, snd_nxt: u32) -> bool {
+        if !self.window.duplicate_ack() {
+            return false;
+        }
+        self.window.enter_recovery(flight_size / 2, snd_nxt);
+        true
+    }
+
+    fn on_timeout(&mut self, flight_size: usize) {
+        self.window.timeout(flight_size / 2);
+    }
+}
+
+/// RFC 6582
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    window: Window,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> Self {
+        NewReno {
+            window: Window::new(mss),
+        }
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn algorithm(&self) -> CongestionAlgorithm {
+        CongestionAlgorithm::NewReno
+    }
+
+    fn cwnd(&self) -> usize {
+        self.window.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.window.ssthresh
+    }
+
+    fn on_ack(&mut self, ack: u32, acked: usize, _srtt: Option<Duration>, _now: Instant) -> bool {
+        let window = &mut self.window;
+        window.dup_acks = 0;
+        if window.recover.is_some() {
+            window.recovery_ack(ack, acked)
+        } else {
+            if window.in_slow_start() {
+                window.slow_start(acked);
+            } else {
+                window.congestion_avoidance();
+            }
+            false
+        }
+    }
+
+    fn on_duplicate_ack(&mut self, flight_size: usize, snd_nxt: u32) -> bool {
+        if !self.window.duplicate_ack() {
+            return false;
+        }
+        self.window.enter_recovery(flight_size / 2, snd_nxt);
+        true
+    }
+
+    fn on_timeout(&mut self, flight_size: usize) {
+        self.window.timeout(flight_size / 2);
+    }
+}
+
+/// RFC 9438, with NewReno style fast recovery.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    window: Window,
+    // Window size just before the last reduction, in segments
+    w_max: f64,
+    // Reno-friendly estimate of the window, in segments
+    w_est: f64,
+    // Time to reach `w_max` from the start of the epoch, in seconds
+    k: f64,
+    epoch_start: Option<Instant>,
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Self {
+        Cubic {
+            window: Window::new(mss),
+            w_max: 0.0,
+            w_est: 0.0,
+            k: 0.0,
+            epoch_start: None,
+        }
+    }
+
+    fn segments(&self) -> f64 {
+        self.window.cwnd as f64 / self.window.mss as f64
+    }
+
+    // Records the window at a congestion event and returns the reduced ssthresh.
+    fn reduce(&mut self) -> usize {
+        let cwnd = self.segments();
+        // Fast convergence (RFC 9438 4.7)
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.epoch_start = None;
+        (self.window.cwnd as f64 * CUBIC_BETA) as usize
+    }
+
+    fn congestion_avoidance(&mut self, acked: usize, rtt: Duration, now: Instant) {
+        let mss = self.window.mss as f64;
+        let cwnd = self.segments();
+        let epoch_start = *self.epoch_start.get_or_insert_with(|| {
+            self.w_max = self.w_max.max(cwnd);
+            self.k = ((self.w_max - cwnd) / CUBIC_C).cbrt();
+            self.w_est = cwnd;
+            now
+        });
+        let t = (now.duration_since(epoch_start) + rtt).as_secs_f64();
+        // The window aims at W_cubic(t + RTT) at most 1.5 times the current one (RFC 9438 4.2)
+        let target = (CUBIC_C * (t - self.k).powi(3) + self.w_max).clamp(cwnd, 1.5 * cwnd);
+        let alpha = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+        self.w_est += alpha * (acked as f64 / mss) / cwnd;
+
+        let next = if target < self.w_est {
+            self.w_est
+        } else {
+            cwnd + (target - cwnd) / cwnd * (acked as f64 / mss)
+        };
+        self.window.cwnd = ((next * mss) as usize).max(self.window.cwnd);
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn algorithm(&self) -> CongestionAlgorithm {
+        CongestionAlgorithm::Cubic
+    }
+
+    fn cwnd(&self) -> usize {
+        self.window.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.window.ssthresh
+    }
+
+    fn on_ack(&mut self, ack: u32, acked: usize, srtt: Option<Duration>, now: Instant) -> bool {
+        self.window.dup_acks = 0;
+        if self.window.recover.is_some() {
+            self.window.recovery_ack(ack, acked)
+        } else {
+            if self.window.in_slow_start() {
+                self.window.slow_start(acked);
+            } else {
+                self.congestion_avoidance(acked, srtt.unwrap_or_default(), now);
+            }
+            false
+        }
+    }
+
+    fn on_duplicate_ack(&mut self, _flight_size: usize, snd_nxt: u32) -> bool {
+        if !self.window.duplicate_ack() {
+            return false;
+        }
+        let ssthresh = self.reduce();
+        self.window.enter_recovery(ssthresh, snd_nxt);
+        true
+    }
+
+    fn on_timeout(&mut self, _flight_size: usize) {
+        let ssthresh = self.reduce();
+        self.window.timeout(ssthresh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn test_slow_start_and_congestion_avoidance() {
+        let mut reno = Reno::new(MSS);
+        let now = Instant::now();
+        assert_eq!(reno.cwnd(), 4 * MSS);
+        reno.on_ack(0, MSS, None, now);
+        assert_eq!(reno.cwnd(), 5 * MSS);
+
+        reno.on_timeout(8 * MSS);
+        assert_eq!(reno.cwnd(), MSS);
+        assert_eq!(reno.ssthresh(), 4 * MSS);
+        for _ in 0..3 {
+            reno.on_ack(0, MSS, None, now);
+        }
+        assert_eq!(reno.cwnd(), 4 * MSS);
+        // One MSS per window of data in congestion avoidance
+        reno.on_ack(0, MSS, None, now);
+        assert_eq!(reno.cwnd(), 4 * MSS + MSS / 4);
+    }
+
+    #[test]
+    fn test_fast_retransmit() {
+        let mut reno = Reno::new(MSS);
+        assert!(!reno.on_duplicate_ack(10 * MSS, 10000));
+        assert!(!reno.on_duplicate_ack(10 * MSS, 10000));
+        assert!(reno.on_duplicate_ack(10 * MSS, 10000));
+        assert_eq!(reno.ssthresh(), 5 * MSS);
+        assert_eq!(reno.cwnd(), 8 * MSS);
+        assert!(!reno.on_duplicate_ack(10 * MSS, 10000));
+        assert_eq!(reno.cwnd(), 9 * MSS);
+
+        // A partial ACK ends Reno's recovery.
+        assert!(!reno.on_ack(5000, MSS, None, Instant::now()));
+        assert_eq!(reno.cwnd(), 5 * MSS);
+    }
+
+    #[test]
+    fn test_newreno_partial_ack() {
+        let mut newreno = NewReno::new(MSS);
+        for _ in 0..3 {
+            newreno.on_duplicate_ack(10 * MSS, 10000);
+        }
+        assert_eq!(newreno.cwnd(), 8 * MSS);
+        // The partial ACK retransmits the next hole and stays in recovery.
+        assert!(newreno.on_ack(2000, 2 * MSS, None, Instant::now()));
+        assert_eq!(newreno.cwnd(), 7 * MSS);
+        assert!(!newreno.on_ack(10000, 8 * MSS, None, Instant::now()));
+        assert_eq!(newreno.cwnd(), 5 * MSS);
+    }
+
+    #[test]
+    fn test_cubic_recovers_to_w_max() {
+        let rtt = Duration::from_millis(100);
+        let srtt = Some(rtt);
+        let mut cubic = Cubic::new(MSS);
+        let start = Instant::now();
+        for _ in 0..16 {
+            cubic.on_ack(0, MSS, srtt, start);
+        }
+        assert_eq!(cubic.cwnd(), 20 * MSS);
+        for _ in 0..3 {
+            cubic.on_duplicate_ack(20 * MSS, 0);
+        }
+        assert_eq!(cubic.ssthresh(), 14 * MSS);
+        cubic.on_ack(1, MSS, srtt, start);
+        assert_eq!(cubic.cwnd(), 14 * MSS);
+
+        // The target is W_cubic one RTT ahead: without an RTT the window starts out slower.
+        let mut unmeasured = cubic.clone();
+        cubic.on_ack(1, MSS, srtt, start);
+        unmeasured.on_ack(1, MSS, None, start);
+        assert!(cubic.cwnd() > unmeasured.cwnd());
+
+        // The window is back at W_max one RTT before K seconds.
+        let mut now = start;
+        let k = Duration::from_secs_f64((6.0 / CUBIC_C).cbrt());
+        while cubic.cwnd() < 20 * MSS {
+            assert!(now < start + k - rtt);
+            now += Duration::from_millis(10);
+            cubic.on_ack(1, MSS, srtt, now);
+        }
+    }
+}
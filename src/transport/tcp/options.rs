@@ -0,0 +1,199 @@
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+const TCP_OPTION_SACK: u8 = 5;
+const TCP_OPTION_TIMESTAMPS: u8 = 8;
+
+pub const TCP_OPTIONS_MAX_LENGTH: usize = 40;
+// RFC 7323 2.3
+pub const TCP_WINDOW_SCALE_MAX: u8 = 14;
+// Four blocks fill the option space, only three fit alongside timestamps (RFC 2018 3).
+pub const TCP_SACK_BLOCKS_MAX: usize = 4;
+pub const TCP_SACK_BLOCKS_MAX_WITH_TIMESTAMPS: usize = 3;
+
+/// A block of data received out of order, `left` inclusive and `right` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SackBlock {
+    pub left: u32,
+    pub right: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamps {
+    pub value: u32,
+    pub echo: u32,
+}
+
+/// Options carried by a TCP segment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub sack: Vec<SackBlock>,
+    pub timestamps: Option<Timestamps>,
+}
+
+impl TcpOptions {
+    /// Length of the encoded options, which is always a multiple of 4.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        if self.mss.is_some() {
+            len += 4;
+        }
+        if self.window_scale.is_some() {
+            len += 4;
+        }
+        if self.sack_permitted {
+            len += 4;
+        }
+        if self.timestamps.is_some() {
+            len += 12;
+        }
+        if !self.sack.is_empty() {
+            len += 4 + 8 * self.sack.len();
+        }
+        len
+    }
+
+    /// Encodes the options, padding them with NOPs so that each one is 4 byte aligned.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        if let Some(mss) = self.mss {
+            bytes.extend_from_slice(&[TCP_OPTION_MSS, 4]);
+            bytes.extend_from_slice(&mss.to_be_bytes());
+        }
+        if let Some(shift) = self.window_scale {
+            bytes.extend_from_slice(&[TCP_OPTION_NOP, TCP_OPTION_WINDOW_SCALE, 3, shift]);
+        }
+        if self.sack_permitted {
+            bytes.extend_from_slice(&[
+                TCP_OPTION_NOP,
+                TCP_OPTION_NOP,
+                TCP_OPTION_SACK_PERMITTED,
+                2,
+            ]);
+        }
+        if let Some(timestamps) = self.timestamps {
+            bytes.extend_from_slice(&[TCP_OPTION_NOP, TCP_OPTION_NOP, TCP_OPTION_TIMESTAMPS, 10]);
+            bytes.extend_from_slice(&timestamps.value.to_be_bytes());
+            bytes.extend_from_slice(&timestamps.echo.to_be_bytes());
+        }
+        if !self.sack.is_empty() {
+            let len = 2 + 8 * self.sack.len() as u8;
+            bytes.extend_from_slice(&[TCP_OPTION_NOP, TCP_OPTION_NOP, TCP_OPTION_SACK, len]);
+            for block in &self.sack {
+                bytes.extend_from_slice(&block.left.to_be_bytes());
+                bytes.extend_from_slice(&block.right.to_be_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for TcpOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let mut options = TcpOptions::default();
+        let mut i = 0;
+        while i < data.len() {
+            match data[i] {
+                TCP_OPTION_END => break,
+                TCP_OPTION_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            anyhow::ensure!(
+                i + 1 < data.len(),
+                "truncated tcp option, kind: {}",
+                data[i]
+            );
+            let (kind, len) = (data[i], data[i + 1] as usize);
+            anyhow::ensure!(
+                len >= 2 && i + len <= data.len(),
+                "invalid tcp option length, kind: {}, len: {}",
+                kind,
+                len
+            );
+            let value = &data[i + 2..i + len];
+            match (kind, value.len()) {
+                (TCP_OPTION_MSS, 2) => options.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (TCP_OPTION_WINDOW_SCALE, 1) => {
+                    options.window_scale = Some(value[0].min(TCP_WINDOW_SCALE_MAX))
+                }
+                (TCP_OPTION_SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (TCP_OPTION_SACK, len) if len % 8 == 0 => {
+                    options.sack = value
+                        .chunks(8)
+                        .map(|block| SackBlock {
+                            left: u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            right: u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                        })
+                        .collect();
+                }
+                (TCP_OPTION_TIMESTAMPS, 8) => {
+                    options.timestamps = Some(Timestamps {
+                        value: u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        echo: u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+                    })
+                }
+                // Unknown options are skipped.
+                _ => {}
+            }
+            i += len;
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_round_trip() {
+        let options = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            sack: vec![SackBlock {
+                left: 1000,
+                right: 2000,
+            }],
+            timestamps: Some(Timestamps {
+                value: 12345,
+                echo: 678,
+            }),
+        };
+        let bytes = options.to_bytes();
+        assert_eq!(bytes.len(), options.len());
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(TcpOptions::try_from(bytes.as_ref()).unwrap(), options);
+    }
+
+    #[test]
+    fn test_parse_linux_syn_options() {
+        // MSS 1460, SACK permitted, timestamps, NOP, window scale 7
+        let bytes = [
+            2, 4, 5, 180, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+        ];
+        let options = TcpOptions::try_from(bytes.as_ref()).unwrap();
+        assert_eq!(options.mss, Some(1460));
+        assert!(options.sack_permitted);
+        assert_eq!(options.timestamps, Some(Timestamps { value: 1, echo: 0 }));
+        assert_eq!(options.window_scale, Some(7));
+    }
+
+    #[test]
+    fn test_parse_invalid_length() {
+        assert!(TcpOptions::try_from([2, 4, 5].as_ref()).is_err());
+        assert!(TcpOptions::try_from([2, 0].as_ref()).is_err());
+        // Padding after the end of the option list is ignored.
+        assert!(TcpOptions::try_from([0, 42, 42].as_ref()).is_ok());
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+const TCP_RTO_INITIAL: Duration = Duration::from_secs(1);
+const TCP_RTO_AFTER_SYN_LOSS: Duration = Duration::from_secs(3);
+const TCP_RTO_MIN: Duration = Duration::from_secs(1);
+const TCP_RTO_MAX: Duration = Duration::from_secs(60);
+
+/// Retransmission timeout computation described in RFC 6298.
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    // Clock granularity, i.e. the interval the retransmission timer is checked at.
+    granularity: Duration,
+}
+
+impl RttEstimator {
+    pub fn new(granularity: Duration) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: TCP_RTO_INITIAL,
+            granularity,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Takes a new RTT measurement. Retransmitted segments must not be sampled (Karn's algorithm).
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or_default();
+        self.rto = (srtt + self.granularity.max(self.rttvar * 4)).clamp(TCP_RTO_MIN, TCP_RTO_MAX);
+    }
+
+    /// Doubles the timeout after the retransmission timer expired (RFC 6298 5.5).
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(TCP_RTO_MAX);
+    }
+
+    /// The timeout is re-initialized to 3 seconds when the SYN had to be retransmitted (RFC 6298 5.7).
+    pub fn syn_lost(&mut self) {
+        if self.srtt.is_none() {
+            self.rto = self.rto.max(TCP_RTO_AFTER_SYN_LOSS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_sample() {
+        let mut rtt = RttEstimator::new(Duration::from_millis(100));
+        assert_eq!(rtt.rto(), TCP_RTO_INITIAL);
+        rtt.sample(Duration::from_millis(400));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(400)));
+        // SRTT + 4 * RTTVAR = 400ms + 4 * 200ms
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn test_subsequent_sample() {
+        let mut rtt = RttEstimator::new(Duration::from_millis(100));
+        rtt.sample(Duration::from_millis(800));
+        rtt.sample(Duration::from_millis(400));
+        // RTTVAR = 3/4 * 400ms + 1/4 * 400ms, SRTT = 7/8 * 800ms + 1/8 * 400ms
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(750)));
+        assert_eq!(rtt.rto(), Duration::from_millis(2350));
+    }
+
+    #[test]
+    fn test_rto_is_bounded() {
+        let mut rtt = RttEstimator::new(Duration::from_millis(100));
+        rtt.sample(Duration::from_millis(1));
+        assert_eq!(rtt.rto(), TCP_RTO_MIN);
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), TCP_RTO_MAX);
+    }
+}
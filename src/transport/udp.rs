@@ -1,6 +1,11 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
-use log::{debug, error};
+use log::debug;
 
 use crate::{
     protocols::{
@@ -10,30 +15,16 @@ use crate::{
     utils::calculate_checksum,
 };
 
-use super::{ContextBlocks, Endpoint, TransportProtocolNumber};
-
-const UDP_PCB_LENGTH: usize = 16;
-
-#[derive(Debug, Clone)]
-struct PseudoHeader {
-    src: Ipv4Address,
-    dst: Ipv4Address,
-    zero: u8,
-    protocol: TransportProtocolNumber,
-    length: u16,
-}
+use super::{
+    icmp::{self, UnreachableCode},
+    ContextBlocks, Endpoint, PseudoHeader, TransportProtocolNumber,
+};
 
-impl PseudoHeader {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.extend_from_slice(&self.src.to_bytes());
-        bytes.extend_from_slice(&self.dst.to_bytes());
-        bytes.push(self.zero);
-        bytes.push(self.protocol as u8);
-        bytes.extend_from_slice(&self.length.to_be_bytes());
-        bytes
-    }
-}
+const UDP_MAX_SOCKETS: usize = 1024;
+const UDP_QUEUE_LENGTH: usize = 64;
+// Dynamic ports (RFC 6335 6)
+const UDP_EPHEMERAL_PORT_MIN: u16 = 49152;
+const UDP_EPHEMERAL_PORT_MAX: u16 = 65535;
 
 #[derive(Debug, Clone)]
 struct UdpHeader {
@@ -68,25 +59,35 @@ impl From<&[u8]> for UdpHeader {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PcbState {
     Open = 0,
+    // Closed while readers were blocked, the last one to wake up releases the pcb.
     Closing = 1,
 }
 
 #[derive(Debug, Clone)]
-struct UdpPcb {
+pub struct UdpPcb {
     state: PcbState,
     local: Endpoint,
+    // Only datagrams from this endpoint are received once connected
+    foreign: Option<Endpoint>,
     queue: VecDeque<UdpPcbQueueEntry>,
+    // Datagrams discarded because the queue was full
+    dropped: usize,
+    // Readers blocked in `receive_from`
+    waiters: usize,
+    cond: Arc<Condvar>,
 }
 
 impl UdpPcb {
-    const DEFAULT: Option<Self> = None;
-
-    fn can_be_bound(&self, address: Ipv4Address, port: u16) -> bool {
-        self.state == PcbState::Open
-            && (self.local.address == Ipv4Address::ANY
-                || address == Ipv4Address::ANY
-                || self.local.address == address)
-            && self.local.port == port
+    fn new() -> Self {
+        UdpPcb {
+            state: PcbState::Open,
+            local: Endpoint::new(&[0, 0, 0, 0], 0),
+            foreign: None,
+            queue: VecDeque::new(),
+            dropped: 0,
+            waiters: 0,
+            cond: Arc::new(Condvar::new()),
+        }
     }
 }
 
@@ -96,76 +97,342 @@ struct UdpPcbQueueEntry {
     data: Vec<u8>,
 }
 
+/// Number of pcbs bound to a port, whatever their address.
+#[derive(Debug, Default)]
+struct PortUsers {
+    unconnected: usize,
+    connected: usize,
+}
+
 pub struct UdpContext {
-    pcbs: [Option<UdpPcb>; UDP_PCB_LENGTH],
+    pcbs: HashMap<usize, UdpPcb>,
+    // Unconnected pcb bound to each local endpoint, the address being ANY for a wildcard bind
+    bound: HashMap<Endpoint, usize>,
+    // Connected pcb of each pair of local and foreign endpoints
+    connected: HashMap<(Endpoint, Endpoint), usize>,
+    ports: HashMap<u16, PortUsers>,
+    next_id: usize,
+    next_ephemeral_port: u16,
+    max_sockets: usize,
+    queue_length: usize,
 }
 
 impl UdpContext {
     pub fn new() -> Self {
         Self {
-            pcbs: [UdpPcb::DEFAULT; UDP_PCB_LENGTH],
+            pcbs: HashMap::new(),
+            bound: HashMap::new(),
+            connected: HashMap::new(),
+            ports: HashMap::new(),
+            next_id: 0,
+            next_ephemeral_port: UDP_EPHEMERAL_PORT_MIN,
+            max_sockets: UDP_MAX_SOCKETS,
+            queue_length: UDP_QUEUE_LENGTH,
         }
     }
 
-    pub fn select_pcb(&self, address: Ipv4Address, port: u16) -> Option<&UdpPcb> {
-        for pcb in self
+    /// Limits the number of pcbs open at once.
+    pub fn set_max_sockets(&mut self, max_sockets: usize) {
+        self.max_sockets = max_sockets;
+    }
+
+    /// Limits the datagrams each pcb queues, the ones arriving beyond are dropped and counted.
+    pub fn set_queue_length(&mut self, queue_length: usize) {
+        self.queue_length = queue_length;
+    }
+
+    /// Id, local and foreign endpoints, number of queued and of dropped datagrams of every pcb in use, by id.
+    pub fn list(&self) -> Vec<(usize, Endpoint, Option<Endpoint>, usize, usize)> {
+        let mut pcbs = self
             .pcbs
             .iter()
-            .filter(|pcb| pcb.is_some())
-            .map(|pcb| pcb.as_ref().unwrap())
-        {
-            if pcb.can_be_bound(address, port) {
-                return Some(pcb);
+            .filter(|(_, pcb)| pcb.state == PcbState::Open)
+            .map(|(&id, pcb)| (id, pcb.local, pcb.foreign, pcb.queue.len(), pcb.dropped))
+            .collect::<Vec<_>>();
+        pcbs.sort_by_key(|(id, ..)| *id);
+        pcbs
+    }
+
+    /// The pcb receiving datagrams sent from `src` to `address:port`, the most specific first:
+    /// connected to `src` before unconnected, bound to that very address before the wildcard.
+    fn select(&self, address: Ipv4Address, port: u16, src: Endpoint) -> Option<usize> {
+        let local = Endpoint { address, port };
+        let wildcard = Endpoint::new(&[0, 0, 0, 0], port);
+        self.connected
+            .get(&(local, src))
+            .or_else(|| self.connected.get(&(wildcard, src)))
+            .or_else(|| self.bound.get(&local))
+            .or_else(|| self.bound.get(&wildcard))
+            .copied()
+    }
+
+    /// Whether an unconnected pcb is bound to an endpoint overlapping `local`.
+    fn is_bound(&self, local: &Endpoint) -> bool {
+        if local.address == Ipv4Address::ANY {
+            self.ports
+                .get(&local.port)
+                .is_some_and(|users| users.unconnected > 0)
+        } else {
+            self.bound.contains_key(local)
+                || self
+                    .bound
+                    .contains_key(&Endpoint::new(&[0, 0, 0, 0], local.port))
+        }
+    }
+
+    fn ephemeral_port(&mut self) -> Option<u16> {
+        let range = (UDP_EPHEMERAL_PORT_MAX - UDP_EPHEMERAL_PORT_MIN) as usize + 1;
+        let start = (self.next_ephemeral_port - UDP_EPHEMERAL_PORT_MIN) as usize;
+        // Goes on from the last port allocated, so that a port just released is not reused at once.
+        let port = (0..range)
+            .map(|i| UDP_EPHEMERAL_PORT_MIN + ((start + i) % range) as u16)
+            .find(|port| !self.ports.contains_key(port))?;
+        self.next_ephemeral_port = port.checked_add(1).unwrap_or(UDP_EPHEMERAL_PORT_MIN);
+        Some(port)
+    }
+
+    fn get_mut(&mut self, id: usize) -> anyhow::Result<&mut UdpPcb> {
+        match self.pcbs.get_mut(&id) {
+            Some(pcb) if pcb.state == PcbState::Open => Ok(pcb),
+            _ => anyhow::bail!("udp socket not found, id: {}", id),
+        }
+    }
+
+    /// Binds the pcb to `local`, on an ephemeral port when its port is zero.
+    fn bind(&mut self, id: usize, mut local: Endpoint) -> anyhow::Result<()> {
+        if local.port == 0 {
+            let Some(port) = self.ephemeral_port() else {
+                anyhow::bail!("no ephemeral port available");
+            };
+            local.port = port;
+        }
+        anyhow::ensure!(
+            !self.is_bound(&local),
+            "udp socket already bound, endpoint: {}",
+            local
+        );
+        let pcb = self.get_mut(id)?;
+        anyhow::ensure!(
+            pcb.local.port == 0,
+            "udp socket already bound, id: {}, local: {}",
+            id,
+            pcb.local
+        );
+        pcb.local = local;
+        self.index(id, local, None);
+        debug!("bound udp socket, i: {}, pcb: {}", id, local);
+        Ok(())
+    }
+
+    /// Receives only from `foreign`, binding the pcb to an ephemeral port first if it is not bound.
+    /// A connected pcb leaves its local endpoint free for an unconnected one to bind.
+    fn connect(&mut self, id: usize, foreign: Endpoint) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            foreign.address != Ipv4Address::ANY && foreign.port != 0,
+            "invalid foreign endpoint: {}",
+            foreign
+        );
+        if self.get_mut(id)?.local.port == 0 {
+            self.bind(id, Endpoint::new(&[0, 0, 0, 0], 0))?;
+        }
+        let pcb = self.get_mut(id)?;
+        let (local, previous) = (pcb.local, pcb.foreign);
+        if previous == Some(foreign) {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            !self.connected.contains_key(&(local, foreign)),
+            "udp socket already connected, local: {}, foreign: {}",
+            local,
+            foreign
+        );
+        self.unindex(local, previous);
+        self.index(id, local, Some(foreign));
+        self.get_mut(id)?.foreign = Some(foreign);
+        debug!(
+            "connected udp socket, i: {}, local: {}, foreign: {}",
+            id, local, foreign
+        );
+        Ok(())
+    }
+
+    fn index(&mut self, id: usize, local: Endpoint, foreign: Option<Endpoint>) {
+        let users = self.ports.entry(local.port).or_default();
+        match foreign {
+            Some(foreign) => {
+                users.connected += 1;
+                self.connected.insert((local, foreign), id);
+            }
+            None => {
+                users.unconnected += 1;
+                self.bound.insert(local, id);
+            }
+        }
+    }
+
+    /// Stops delivering datagrams to the pcb and frees its local endpoint.
+    fn unindex(&mut self, local: Endpoint, foreign: Option<Endpoint>) {
+        let Some(users) = self.ports.get_mut(&local.port) else {
+            return;
+        };
+        match foreign {
+            Some(foreign) => {
+                self.connected.remove(&(local, foreign));
+                users.connected -= 1;
+            }
+            None => {
+                self.bound.remove(&local);
+                users.unconnected -= 1;
             }
         }
-        None
+        if users.connected == 0 && users.unconnected == 0 {
+            self.ports.remove(&local.port);
+        }
+    }
+}
+
+/// Allocates a pcb, which receives nothing until it is bound.
+pub fn open(pcbs: &mut ContextBlocks) -> anyhow::Result<usize> {
+    let udp = &mut pcbs.udp_pcb;
+    anyhow::ensure!(
+        udp.pcbs.len() < udp.max_sockets,
+        "no udp pcb available, max: {}",
+        udp.max_sockets
+    );
+    let id = udp.next_id;
+    udp.next_id += 1;
+    udp.pcbs.insert(id, UdpPcb::new());
+    debug!("udp pcb allocated, i: {}", id);
+    Ok(id)
+}
+
+pub fn bind(pcbs: &mut ContextBlocks, id: usize, local: &Endpoint) -> anyhow::Result<()> {
+    pcbs.udp_pcb.bind(id, *local)
+}
+
+/// Sets the destination of `send`, from which only datagrams are received afterwards.
+pub fn connect(pcbs: &mut ContextBlocks, id: usize, foreign: &Endpoint) -> anyhow::Result<()> {
+    pcbs.udp_pcb.connect(id, *foreign)
+}
+
+/// Sends `data` to the endpoint the pcb is connected to.
+pub fn send(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+    data: &[u8],
+) -> anyhow::Result<usize> {
+    let Some(foreign) = pcbs.lock().unwrap().udp_pcb.get_mut(id)?.foreign else {
+        anyhow::bail!("udp socket not connected, id: {}", id);
+    };
+    send_to(context, pcbs, id, data, foreign)
+}
+
+/// Sends `data` from the local endpoint of the pcb, returns the length sent.
+/// An unbound pcb is bound to an ephemeral port first, which it keeps for later datagrams.
+/// A connected pcb sends to the endpoint it is connected to only, as with EISCONN.
+pub fn send_to(
+    context: &Mutex<ProtocolStackContext>,
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+    data: &[u8],
+    foreign: Endpoint,
+) -> anyhow::Result<usize> {
+    let mut context = context.lock().unwrap();
+    let mut pcbs = pcbs.lock().unwrap();
+    let udp = &mut pcbs.udp_pcb;
+    if let Some(connected) = udp.get_mut(id)?.foreign {
+        anyhow::ensure!(
+            foreign == connected,
+            "udp socket is connected, id: {}, foreign: {}, to: {}",
+            id,
+            connected,
+            foreign
+        );
     }
+    if udp.get_mut(id)?.local.port == 0 {
+        udp.bind(id, Endpoint::new(&[0, 0, 0, 0], 0))?;
+    }
+    let local = udp.get_mut(id)?.local;
+    drop(pcbs);
+    output(&mut context, data, local, foreign)?;
+    Ok(data.len())
+}
+
+/// Blocks until a datagram arrives, copies as much of it as fits in `buf` and returns that length
+/// along with the sender. The rest of the datagram is discarded.
+/// Fails with `ErrorKind::TimedOut` once `timeout` elapses, or when the pcb is closed meanwhile.
+pub fn receive_from(
+    pcbs: &Mutex<ContextBlocks>,
+    id: usize,
+    buf: &mut [u8],
+    timeout: Option<Duration>,
+) -> anyhow::Result<(usize, Endpoint)> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut guard = pcbs.lock().unwrap();
+    loop {
+        let pcb = guard.udp_pcb.get_mut(id)?;
+        if let Some(entry) = pcb.queue.pop_front() {
+            let len = entry.data.len().min(buf.len());
+            buf[..len].copy_from_slice(&entry.data[..len]);
+            return Ok((len, entry.foreign));
+        }
 
-    pub fn select_pcb_mut(&mut self, address: Ipv4Address, port: u16) -> Option<&mut UdpPcb> {
-        for pcb in self
+        let cond = pcb.cond.clone();
+        pcb.waiters += 1;
+        let timed_out = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let (g, result) = cond.wait_timeout(guard, timeout).unwrap();
+                guard = g;
+                result.timed_out()
+            }
+            None => {
+                guard = cond.wait(guard).unwrap();
+                false
+            }
+        };
+
+        let udp = &mut guard.udp_pcb;
+        let pcb = udp
             .pcbs
-            .iter_mut()
-            .filter(|pcb| pcb.is_some())
-            .map(|pcb| pcb.as_mut().unwrap())
-        {
-            if pcb.can_be_bound(address, port) {
-                return Some(pcb);
+            .get_mut(&id)
+            .expect("udp pcb released with waiters");
+        pcb.waiters -= 1;
+        if pcb.state == PcbState::Closing {
+            if pcb.waiters == 0 {
+                udp.pcbs.remove(&id);
+                debug!("udp pcb released, i: {}", id);
             }
+            anyhow::bail!("udp socket closed, id: {}", id);
+        }
+        if timed_out && pcb.queue.is_empty() {
+            return Err(std::io::Error::from(ErrorKind::TimedOut).into());
         }
-        None
     }
 }
 
-pub fn bind(pcbs: &mut ContextBlocks, endpoint: &Endpoint) -> Option<usize> {
-    if pcbs
-        .udp_pcb
-        .select_pcb(endpoint.address, endpoint.port)
-        .is_some()
-    {
-        error!("udp socket already bound, endpoint: {}", endpoint);
-        return None;
-    }
-
-    for (i, pcb) in pcbs
-        .udp_pcb
-        .pcbs
-        .iter_mut()
-        .enumerate()
-        .filter(|(_, pcb)| pcb.is_none())
-    {
-        *pcb = Some(UdpPcb {
-            state: PcbState::Open,
-            local: endpoint.clone(),
-            queue: VecDeque::new(),
-        });
-        debug!("bound udp socket, i: {}, pcb: {}", i, endpoint);
-        return Some(i);
+/// Releases the pcb, once the readers blocked on it are woken up if there are any.
+pub fn close(pcbs: &Mutex<ContextBlocks>, id: usize) -> anyhow::Result<()> {
+    let udp = &mut pcbs.lock().unwrap().udp_pcb;
+    let pcb = udp.get_mut(id)?;
+    let (local, foreign) = (pcb.local, pcb.foreign);
+    udp.unindex(local, foreign);
+    let pcb = udp.get_mut(id)?;
+    if pcb.waiters > 0 {
+        pcb.state = PcbState::Closing;
+        pcb.queue.clear();
+        pcb.cond.notify_all();
+        debug!("udp pcb closing, i: {}, waiters: {}", id, pcb.waiters);
+    } else {
+        udp.pcbs.remove(&id);
+        debug!("udp pcb released, i: {}", id);
     }
-    None
+    Ok(())
 }
 
 #[tracing::instrument(skip_all)]
-pub fn send(
+pub fn output(
     context: &mut ProtocolStackContext,
     data: &[u8],
     src: Endpoint,
@@ -178,6 +445,17 @@ pub fn send(
             IPV4_PAYLOAD_MAX_LENGTH - size_of::<UdpHeader>()
         );
     }
+    let mut src = src;
+    // The pseudo header needs the address the packet is actually sent from.
+    if src.address == Ipv4Address::ANY {
+        let Some(route) = context.router.lookup(dst.address) else {
+            return Err(icmp::unreachable(
+                UnreachableCode::Net,
+                format!("no route found, dst: {}", dst.address),
+            ));
+        };
+        src.address = route.interface.unicast;
+    }
     let length = (size_of::<UdpHeader>() + data.len()) as u16;
     debug!(
         "send udp packet, src: {:?}, dst: {:?}, length: {}",
@@ -193,7 +471,7 @@ pub fn send(
     let header = UdpHeader {
         src_port: src.port,
         dst_port: dst.port,
-        length: length,
+        length,
         checksum: 0,
     };
     let header_bytes = header.to_bytes();
@@ -212,6 +490,7 @@ pub fn send(
         &data,
         src.address,
         dst.address,
+        false,
     )
 }
 
@@ -260,20 +539,299 @@ pub fn recv(
         payload.len()
     );
 
-    let Some(pcb) = pcbs.udp_pcb.select_pcb_mut(dst, header.dst_port) else {
-        anyhow::bail!(
-            "udp socket not found, dst: {}, port: {}",
-            dst,
-            header.dst_port
-        );
+    let foreign = Endpoint {
+        address: src,
+        port: header.src_port,
+    };
+    let udp = &mut pcbs.udp_pcb;
+    let Some(id) = udp.select(dst, header.dst_port, foreign) else {
+        return Err(icmp::unreachable(
+            UnreachableCode::Port,
+            format!(
+                "udp socket not found, dst: {}, port: {}",
+                dst, header.dst_port
+            ),
+        ));
     };
+    let queue_length = udp.queue_length;
+    let pcb = udp.get_mut(id)?;
+    if pcb.queue.len() >= queue_length {
+        pcb.dropped += 1;
+        debug!(
+            "udp queue full, datagram dropped, i: {}, dropped: {}",
+            id, pcb.dropped
+        );
+        return Ok(());
+    }
     pcb.queue.push_back(UdpPcbQueueEntry {
-        foreign: Endpoint {
-            address: dst,
-            port: header.src_port,
-        },
+        foreign,
         data: payload.to_vec(),
     });
+    pcb.cond.notify_all();
     debug!("udp queue pushed, len: {}", pcb.queue.len());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{
+        ethernet::ETHERNET_HEADER_SIZE,
+        link::testing::{connect, echo, pair, pending, settle, Host},
+    };
+
+    #[test]
+    fn test_datagram_across_link() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+
+        let id = open(&mut host_b.pcbs.lock().unwrap()).unwrap();
+        bind(
+            &mut host_b.pcbs.lock().unwrap(),
+            id,
+            &Endpoint::new(&[0, 0, 0, 0], 7),
+        )
+        .unwrap();
+        output(
+            &mut host_a.context.lock().unwrap(),
+            b"hello",
+            Endpoint::new(&a, 40000),
+            Endpoint::new(&b, 7),
+        )
+        .unwrap();
+        settle(&[&host_a, &host_b]);
+
+        // The datagram is longer than the buffer, so it is truncated.
+        let mut buf = [0; 4];
+        let (len, foreign) = receive_from(&host_b.pcbs, id, &mut buf, None).unwrap();
+        assert_eq!(&buf[..len], b"hell");
+        assert_eq!(foreign, Endpoint::new(&a, 40000));
+    }
+
+    #[test]
+    fn test_unbound_socket_sends_from_ephemeral_port() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let id_b = open(&mut host_b.pcbs.lock().unwrap()).unwrap();
+        bind(
+            &mut host_b.pcbs.lock().unwrap(),
+            id_b,
+            &Endpoint::new(&[0, 0, 0, 0], 7),
+        )
+        .unwrap();
+
+        let id_a = open(&mut host_a.pcbs.lock().unwrap()).unwrap();
+        for _ in 0..2 {
+            send_to(
+                &host_a.context,
+                &host_a.pcbs,
+                id_a,
+                b"ping",
+                Endpoint::new(&b, 7),
+            )
+            .unwrap();
+        }
+        settle(&[&host_a, &host_b]);
+
+        // Both datagrams passed the checksum, from the interface address and the same port.
+        let mut buf = [0; 4];
+        for _ in 0..2 {
+            let (_, foreign) = receive_from(&host_b.pcbs, id_b, &mut buf, None).unwrap();
+            assert_eq!(foreign, Endpoint::new(&a, UDP_EPHEMERAL_PORT_MIN));
+        }
+        send_to(
+            &host_b.context,
+            &host_b.pcbs,
+            id_b,
+            b"pong",
+            Endpoint::new(&a, UDP_EPHEMERAL_PORT_MIN),
+        )
+        .unwrap();
+        settle(&[&host_a, &host_b]);
+        let (len, foreign) = receive_from(&host_a.pcbs, id_a, &mut buf, None).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(foreign, Endpoint::new(&b, 7));
+    }
+
+    #[test]
+    fn test_receive_times_out() {
+        let pcbs = Mutex::new(ContextBlocks::new());
+        let id = open(&mut pcbs.lock().unwrap()).unwrap();
+        let err =
+            receive_from(&pcbs, id, &mut [0; 4], Some(Duration::from_millis(10))).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|err| err.kind()),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_close_wakes_blocked_readers() {
+        let pcbs = Mutex::new(ContextBlocks::new());
+        let id = open(&mut pcbs.lock().unwrap()).unwrap();
+        std::thread::scope(|s| {
+            let readers = (0..2)
+                .map(|_| s.spawn(|| receive_from(&pcbs, id, &mut [0; 4], None)))
+                .collect::<Vec<_>>();
+            while pcbs.lock().unwrap().udp_pcb.pcbs[&id].waiters < 2 {
+                std::thread::yield_now();
+            }
+            close(&pcbs, id).unwrap();
+            for reader in readers {
+                assert!(reader.join().unwrap().is_err());
+            }
+        });
+        // The last reader to wake up released the pcb.
+        assert!(pcbs.lock().unwrap().udp_pcb.pcbs.is_empty());
+        assert!(close(&pcbs, id).is_err());
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_counts() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let mut pcbs = host_b.pcbs.lock().unwrap();
+        pcbs.udp_pcb.set_queue_length(2);
+        let id = open(&mut pcbs).unwrap();
+        bind(&mut pcbs, id, &Endpoint::new(&b, 7)).unwrap();
+        drop(pcbs);
+
+        for data in [b"one", b"two", b"six"] {
+            output(
+                &mut host_a.context.lock().unwrap(),
+                data,
+                Endpoint::new(&a, 40000),
+                Endpoint::new(&b, 7),
+            )
+            .unwrap();
+        }
+        settle(&[&host_a, &host_b]);
+
+        assert_eq!(
+            host_b.pcbs.lock().unwrap().udp_pcb.list(),
+            [(id, Endpoint::new(&b, 7), None, 2, 1)]
+        );
+        let mut buf = [0; 8];
+        let (len, _) = receive_from(&host_b.pcbs, id, &mut buf, None).unwrap();
+        assert_eq!(&buf[..len], b"one");
+    }
+
+    #[test]
+    fn test_hundreds_of_sockets() {
+        let mut pcbs = ContextBlocks::new();
+        pcbs.udp_pcb.set_max_sockets(500);
+        let ids = (0..500)
+            .map(|i| {
+                let id = open(&mut pcbs).unwrap();
+                bind(&mut pcbs, id, &Endpoint::new(&[0, 0, 0, 0], 1000 + i)).unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        assert!(open(&mut pcbs).is_err());
+
+        let (address, src) = (
+            Ipv4Address::new(&[192, 0, 2, 2]),
+            Endpoint::new(&[192, 0, 2, 1], 40000),
+        );
+        assert_eq!(pcbs.udp_pcb.select(address, 1499, src), Some(ids[499]));
+        assert_eq!(pcbs.udp_pcb.select(address, 1500, src), None);
+
+        // Closing frees both a slot and the port, which a specific address can then be bound on.
+        let pcbs = Mutex::new(pcbs);
+        close(&pcbs, ids[0]).unwrap();
+        let mut pcbs = pcbs.into_inner().unwrap();
+        let id = open(&mut pcbs).unwrap();
+        assert!(bind(&mut pcbs, id, &Endpoint::new(&[192, 0, 2, 2], 1001)).is_err());
+        bind(&mut pcbs, id, &Endpoint::new(&[192, 0, 2, 2], 1000)).unwrap();
+        assert_eq!(pcbs.udp_pcb.select(address, 1000, src), Some(id));
+        assert_eq!(
+            pcbs.udp_pcb
+                .select(Ipv4Address::new(&[127, 0, 0, 1]), 1000, src),
+            None
+        );
+    }
+
+    #[test]
+    fn test_connected_socket_takes_its_peer() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (c, b_c) = ([198, 51, 100, 1], [198, 51, 100, 2]);
+        let (mut host_a, mut host_b, mut host_c) = (Host::new(), Host::new(), Host::new());
+        connect(&mut host_a, a, &mut host_b, b, 24);
+        connect(&mut host_c, c, &mut host_b, b_c, 24);
+        echo(&host_a, a, b);
+        echo(&host_c, c, b_c);
+        settle(&[&host_a, &host_b, &host_c]);
+
+        // A client connected from port 123, then a server bound to the wildcard on the same port.
+        let mut pcbs = host_b.pcbs.lock().unwrap();
+        let client = open(&mut pcbs).unwrap();
+        bind(&mut pcbs, client, &Endpoint::new(&[0, 0, 0, 0], 123)).unwrap();
+        super::connect(&mut pcbs, client, &Endpoint::new(&a, 123)).unwrap();
+        let server = open(&mut pcbs).unwrap();
+        bind(&mut pcbs, server, &Endpoint::new(&[0, 0, 0, 0], 123)).unwrap();
+        drop(pcbs);
+
+        for (host, src, dst, data) in [(&host_a, a, b, b"peer"), (&host_c, c, b_c, b"else")] {
+            output(
+                &mut host.context.lock().unwrap(),
+                data,
+                Endpoint::new(&src, 123),
+                Endpoint::new(&dst, 123),
+            )
+            .unwrap();
+        }
+        settle(&[&host_a, &host_b, &host_c]);
+
+        let mut buf = [0; 4];
+        let timeout = Some(Duration::from_millis(10));
+        let (_, foreign) = receive_from(&host_b.pcbs, client, &mut buf, None).unwrap();
+        assert_eq!((&buf, foreign), (b"peer", Endpoint::new(&a, 123)));
+        assert!(receive_from(&host_b.pcbs, client, &mut buf, timeout).is_err());
+        let (_, foreign) = receive_from(&host_b.pcbs, server, &mut buf, None).unwrap();
+        assert_eq!((&buf, foreign), (b"else", Endpoint::new(&c, 123)));
+        assert!(receive_from(&host_b.pcbs, server, &mut buf, timeout).is_err());
+
+        // Sending needs no destination once connected.
+        let id = open(&mut host_a.pcbs.lock().unwrap()).unwrap();
+        assert!(send(&host_a.context, &host_a.pcbs, id, b"ping").is_err());
+        super::connect(
+            &mut host_a.pcbs.lock().unwrap(),
+            id,
+            &Endpoint::new(&b, 123),
+        )
+        .unwrap();
+        send(&host_a.context, &host_a.pcbs, id, b"ping").unwrap();
+        // Nor does it go anywhere else.
+        let elsewhere = Endpoint::new(&b, 124);
+        assert!(send_to(&host_a.context, &host_a.pcbs, id, b"pong", elsewhere).is_err());
+        settle(&[&host_a, &host_b, &host_c]);
+        let (_, foreign) = receive_from(&host_b.pcbs, server, &mut buf, None).unwrap();
+        assert_eq!((&buf, foreign.address), (b"ping", Ipv4Address::new(&a)));
+    }
+
+    #[test]
+    fn test_closed_port_is_unreachable() {
+        let (a, b) = ([192, 0, 2, 1], [192, 0, 2, 2]);
+        let (host_a, host_b) = pair(a, b);
+        let device_a = host_a.device();
+
+        output(
+            &mut host_a.context.lock().unwrap(),
+            b"hello",
+            Endpoint::new(&a, 40000),
+            Endpoint::new(&b, 9),
+        )
+        .unwrap();
+        while host_b.poll() {}
+
+        let frames = pending(&device_a);
+        assert_eq!(frames.len(), 1);
+        let icmp = &frames[0][ETHERNET_HEADER_SIZE + 20..];
+        assert_eq!(icmp[0], icmp::IcmpType::DestinationUnreachable as u8);
+        assert_eq!(icmp[1], UnreachableCode::Port as u8);
+        // The IP header and the UDP header of the datagram, without its payload.
+        assert_eq!(icmp.len(), 8 + 20 + 8);
+        assert_eq!(&icmp[8 + 12..8 + 16], &a);
+        assert_eq!(&icmp[8 + 20..8 + 24], &[0x9c, 0x40, 0x00, 0x09]);
+    }
+}
@@ -16,25 +16,6 @@ pub fn calculate_checksum(data: &[u8], sum: u16) -> u16 {
     !sum as u16
 }
 
-// Safety: Returning type T should be defined as #[repr(packed)].
-pub unsafe fn to_struct<T: Sized>(data: &[u8]) -> &T {
-    let (head, t, _) = data[..size_of::<T>()].align_to::<T>();
-    assert!(head.is_empty());
-    &t[0]
-}
-
-// Safety: Converted type T should be defined as #[repr(packed)].
-pub unsafe fn to_bytes<T: Sized>(t: &T) -> &[u8] {
-    let ptr = t as *const T as *const u8;
-    std::slice::from_raw_parts(ptr, size_of::<T>())
-}
-
-// Safety: Converted type T should be defined as #[repr(packed)].
-pub unsafe fn to_bytes_mut<T: Sized>(t: &mut T) -> &mut [u8] {
-    let ptr = t as *mut T as *mut u8;
-    std::slice::from_raw_parts_mut(ptr, size_of::<T>())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;